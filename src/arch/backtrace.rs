@@ -0,0 +1,93 @@
+use core::arch::asm;
+
+use x86_64::VirtAddr;
+
+use crate::mem::memory::{physical_memory_offset, translate_addr};
+use crate::serial_println;
+
+const MAX_FRAMES: usize = 32;
+// A caller's frame is never this far above its callee's, so a bigger step means a bad chain
+const MAX_FRAME_STEP: u64 = 1024 * 1024;
+
+/// Instruction, stack and frame pointer at the moment `Registers::capture` was called.
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let (rip, rsp, rbp): (u64, u64, u64);
+        unsafe {
+            asm!("lea {}, [rip]", out(reg) rip, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+        Registers { rip, rsp, rbp }
+    }
+}
+
+/// Return addresses found by following the saved frame pointers up from `rbp`, innermost
+/// first. Needs frame pointers, which the target spec forces on.
+///
+/// Every frame is checked against the page tables before it is read, so a corrupt chain ends
+/// the walk instead of faulting. Without the physical memory offset nothing can be checked and
+/// the walk yields nothing.
+pub struct StackFrames {
+    frame: u64,
+    remaining: usize,
+}
+
+impl StackFrames {
+    pub fn new(rbp: u64) -> Self {
+        StackFrames { frame: rbp, remaining: MAX_FRAMES }
+    }
+}
+
+impl Iterator for StackFrames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.remaining == 0 || self.frame == 0 || self.frame % 8 != 0 {
+            return None;
+        }
+        // The saved frame pointer and the return address above it
+        if !is_mapped(self.frame) || !is_mapped(self.frame + 8) {
+            return None;
+        }
+
+        let saved = self.frame as *const u64;
+        let (next_frame, return_address) = unsafe { (saved.read(), saved.add(1).read()) };
+        if return_address == 0 {
+            return None;
+        }
+
+        // The stack grows down, so callers always sit higher up
+        self.frame = if next_frame > self.frame && next_frame - self.frame < MAX_FRAME_STEP {
+            next_frame
+        } else {
+            0
+        };
+        self.remaining -= 1;
+        Some(return_address)
+    }
+}
+
+fn is_mapped(address: u64) -> bool {
+    match (physical_memory_offset(), VirtAddr::try_new(address)) {
+        (Some(offset), Ok(address)) => unsafe { translate_addr(address, offset) }.is_some(),
+        _ => false,
+    }
+}
+
+/// Prints `registers` and the return addresses on the stack they point at to serial.
+pub fn print_backtrace(registers: Registers) {
+    serial_println!("rip {:#018x}  rsp {:#018x}  rbp {:#018x}", registers.rip, registers.rsp, registers.rbp);
+    serial_println!("Backtrace:");
+    for (depth, address) in StackFrames::new(registers.rbp).enumerate() {
+        serial_println!("  #{:<2} {:#018x}", depth, address);
+    }
+}
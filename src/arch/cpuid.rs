@@ -0,0 +1,69 @@
+use core::arch::x86_64::__cpuid;
+
+const CPUID_VENDOR: u32 = 0x0;
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_BRAND_FIRST: u32 = 0x8000_0002;
+const CPUID_BRAND_LAST: u32 = 0x8000_0004;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+const FEATURE_EDX_APIC: u32 = 1 << 9;
+const FEATURE_ECX_X2APIC: u32 = 1 << 21;
+const POWER_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+/// The 12-byte vendor string from leaf 0, such as `GenuineIntel` or `AuthenticAMD`.
+pub fn vendor() -> [u8; 12] {
+    let leaf = __cpuid(CPUID_VENDOR);
+    let mut vendor = [0u8; 12];
+    // The vendor string is spread over EBX, EDX, ECX, in that order
+    vendor[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf.edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&leaf.ecx.to_le_bytes());
+    vendor
+}
+
+/// The 48-byte processor brand string from leaves 0x80000002-0x80000004, NUL padded, or `None`
+/// if the CPU does not have those leaves.
+pub fn brand() -> Option<[u8; 48]> {
+    if max_extended_leaf() < CPUID_BRAND_LAST {
+        return None;
+    }
+
+    let mut brand = [0u8; 48];
+    for (i, leaf) in (CPUID_BRAND_FIRST..=CPUID_BRAND_LAST).enumerate() {
+        let result = __cpuid(leaf);
+        for (j, register) in [result.eax, result.ebx, result.ecx, result.edx].iter().enumerate() {
+            let offset = i * 16 + j * 4;
+            brand[offset..offset + 4].copy_from_slice(&register.to_le_bytes());
+        }
+    }
+    Some(brand)
+}
+
+/// Whether the CPU has a local APIC.
+pub fn has_apic() -> bool {
+    __cpuid(CPUID_FEATURES).edx & FEATURE_EDX_APIC != 0
+}
+
+/// Whether the local APIC can run in x2APIC mode, with MSR instead of MMIO registers.
+pub fn has_x2apic() -> bool {
+    __cpuid(CPUID_FEATURES).ecx & FEATURE_ECX_X2APIC != 0
+}
+
+/// Whether the TSC runs at a constant rate in every power state, which makes it usable as a
+/// clock source next to the HPET.
+pub fn has_hpet_invariant_tsc() -> bool {
+    max_extended_leaf() >= CPUID_POWER_MANAGEMENT
+        && __cpuid(CPUID_POWER_MANAGEMENT).edx & POWER_EDX_INVARIANT_TSC != 0
+}
+
+fn max_extended_leaf() -> u32 {
+    __cpuid(CPUID_EXTENDED_MAX).eax
+}
+
+#[test_case]
+fn test_cpuid_vendor_and_apic() {
+    assert!(vendor().iter().all(|byte| byte.is_ascii_graphic()));
+    // Every machine this kernel boots on, QEMU included, has a local APIC
+    assert!(has_apic());
+}
@@ -0,0 +1,3 @@
+pub mod backtrace;
+pub mod cpuid;
+pub mod reset;
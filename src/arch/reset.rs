@@ -0,0 +1,68 @@
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::serial_println;
+
+const KBC_STATUS_PORT: u16 = 0x64;
+const KBC_COMMAND_PORT: u16 = 0x64;
+const KBC_DATA_PORT: u16 = 0x60;
+const KBC_STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const KBC_STATUS_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+// Each status read takes about a microsecond on the ISA bus, so this is roughly 100 ms
+const KBC_POLL_LIMIT: usize = 100_000;
+
+/// Restarts the machine by pulsing the CPU reset line through the 8042 keyboard controller.
+///
+/// If the controller does not reset the CPU, an empty IDT turns the next exception into a
+/// triple fault, which resets it as well.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    // Serial output is unbuffered, so this is the last thing to go out before the reset
+    serial_println!("Rebooting");
+
+    let mut status = Port::<u8>::new(KBC_STATUS_PORT);
+    let mut command = Port::<u8>::new(KBC_COMMAND_PORT);
+    let mut data = Port::<u8>::new(KBC_DATA_PORT);
+
+    unsafe {
+        // The controller only takes a command once its input buffer is empty; pending
+        // keyboard bytes are drained so they cannot keep it busy
+        for _ in 0..KBC_POLL_LIMIT {
+            let state = status.read();
+            if state & KBC_STATUS_OUTPUT_FULL != 0 {
+                data.read();
+            }
+            if state & KBC_STATUS_INPUT_FULL == 0 {
+                break;
+            }
+        }
+
+        command.write(KBC_PULSE_RESET);
+
+        for _ in 0..KBC_POLL_LIMIT {
+            status.read();
+        }
+    }
+
+    serial_println!("Keyboard controller reset failed; forcing a triple fault");
+    triple_fault();
+}
+
+fn triple_fault() -> ! {
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+
+    unsafe {
+        lidt(&empty);
+    }
+    x86_64::instructions::interrupts::int3();
+
+    // Not reached: the breakpoint cannot be delivered without an IDT
+    crate::halt();
+}
@@ -0,0 +1,427 @@
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use x86_64::VirtAddr;
+
+use crate::vga_buffer::Color;
+use crate::{arch, filesystem, hardware, logger, mem, platform, serial_println, task, QemuExitCode};
+
+/// Largest range `hexdump` prints in one go; more would scroll off the screen anyway.
+const HEXDUMP_MAX_LEN: u64 = 4096;
+
+/// Follow-up work a command leaves to the screen it was typed on, since `dispatch` only has a
+/// text sink to write to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandAction<'a> {
+    None,
+    ClearScreen,
+    SetColor(Color),
+    SetPrompt(&'a str),
+    SetPromptColor(Color),
+    /// Has to run once the caller no longer holds its screen lock.
+    RunUnlocked(UnlockedCommand<'a>),
+    /// `bind` with its arguments; key bindings are state of the shell, not of `dispatch`.
+    Bind(&'a str),
+}
+
+/// Commands that cannot run while the screen is locked: the disk commands poll the timer, which
+/// does not tick with interrupts off, and the breakpoint handler prints to the screen itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnlockedCommand<'a> {
+    Breakpoint,
+    ReadBlock(u64),
+    WriteBlock(u64),
+    ListFiles,
+    CatFile(&'a str),
+}
+
+/// Runs `command`, writing its output to `out`, and returns what the caller's screen should do.
+///
+/// Every console calls this, so a command added here is available on all of them.
+pub fn dispatch<'a>(command: &'a str, out: &mut dyn Write) -> CommandAction<'a> {
+    let command = command.trim();
+
+    let mut parts = command.split_whitespace();
+    let command_name = parts.next().unwrap_or("");
+    let arguments: Vec<&str> = parts.collect();
+
+    match command_name {
+        "help" => {
+            out.write_str("\n").unwrap();
+            out.write_str("\nAvailable commands:\n").unwrap();
+            out.write_str("help  - Show this help message\n").unwrap();
+            out.write_str("clear - Clear the screen\n").unwrap();
+            out.write_str("echo [-n] - Echo the input text (-n: no trailing newline)\n").unwrap();
+            out.write_str("ticks - Show the raw timer tick counter\n").unwrap();
+            out.write_str("uptime - Show the time since boot\n").unwrap();
+            out.write_str("mem   - Show heap usage\n").unwrap();
+            out.write_str("halt  - Stop the system\n").unwrap();
+            out.write_str("shutdown - Power off through ACPI\n").unwrap();
+            out.write_str("reboot - Restart the machine\n").unwrap();
+            out.write_str("pci   - List PCI devices with vendor and class names (-v for subsystem IDs)\n").unwrap();
+            out.write_str("lspci - List PCI devices found by walking the bridges\n").unwrap();
+            out.write_str("sysinfo - Show platform information\n").unwrap();
+            out.write_str("cpu   - Show the CPU vendor, brand and APIC/TSC features\n").unwrap();
+            out.write_str("lsacpi - List the ACPI tables found at boot\n").unwrap();
+            out.write_str("breakpoint - Trigger int3 and return from the handler\n").unwrap();
+            out.write_str("color <name> - Set the text color (e.g. color lightgreen)\n").unwrap();
+            out.write_str("prompt <text> | -c <color> - Change the prompt or its color\n").unwrap();
+            out.write_str("exit [code] - End a headless QEMU session (0 = success, anything else = failure)\n").unwrap();
+            out.write_str("hexdump <hexaddr> [len] - Dump physical memory as hex and ASCII\n").unwrap();
+            out.write_str("translate <hexaddr> - Show the physical address a virtual address maps to\n").unwrap();
+            out.write_str("nvme  - Show NVMe controller version and default namespace\n").unwrap();
+            out.write_str("disk  - Show NVMe namespace count, capacity and block size\n").unwrap();
+            out.write_str("read [lba] - Dump one block of the default NVMe namespace\n").unwrap();
+            out.write_str("write <lba> - Write a test pattern to an NVMe block and read it back\n").unwrap();
+            out.write_str("ls    - List the root directory of the FAT volume on the NVMe disk\n").unwrap();
+            out.write_str("cat <file> - Print a file from that root directory\n").unwrap();
+            out.write_str("heartbeat <seconds|off> - Periodic alive line on serial\n").unwrap();
+            out.write_str("loglevel [error|warn|info|debug] - Show or set the serial log level\n").unwrap();
+            out.write_str("keymap [us|de|uk] - Show or switch the keyboard layout\n").unwrap();
+            out.write_str("bind [key] [command] - Bind F1-F12 or Ctrl+<letter> to a command\n").unwrap();
+        }
+        "clear" => {
+            return CommandAction::ClearScreen;
+        }
+        "echo" => {
+            // The first line break only leaves the input line, like every command's output
+            out.write_str("\n").unwrap();
+            let (newline, words) = match arguments.split_first() {
+                Some((&"-n", rest)) => (false, rest),
+                _ => (true, arguments.as_slice()),
+            };
+            for (i, word) in words.iter().enumerate() {
+                if i > 0 {
+                    out.write_str(" ").unwrap();
+                }
+                out.write_str(word).unwrap();
+            }
+            if newline {
+                out.write_str("\n").unwrap();
+            }
+        }
+        "ticks" => {
+            let ticks = hardware::pit::timer_ticks();
+            write!(out, "\nPIT ticks: {}\n", ticks).unwrap();
+        }
+        "uptime" => {
+            let ms = hardware::pit::uptime_ms();
+            write!(out, "\nUp {}.{:02}s\n", ms / 1000, ms % 1000 / 10).unwrap();
+        }
+        "mem" => {
+            let stats = mem::allocator::heap_stats();
+            write!(out, "\nHeap: {}KB total, {}KB used, {}KB free\n",
+                   stats.size / 1024, stats.used / 1024, stats.free / 1024).unwrap();
+            write!(out, "Heap range: {:#x}-{:#x}\n", stats.start, stats.end).unwrap();
+        }
+        "halt" => {
+            // Output to both the screen and serial is unbuffered, so nothing is left to flush
+            out.write_str("\nSystem halted.\n").unwrap();
+            serial_println!("System halted.");
+            crate::halt();
+        }
+        "shutdown" => {
+            out.write_str("\nPowering off...\n").unwrap();
+            hardware::power::shutdown();
+        }
+        "reboot" => {
+            out.write_str("\nRebooting...\n").unwrap();
+            arch::reset::reboot();
+        }
+        "pci" => {
+            let verbose = arguments.contains(&"-v");
+            hardware::pci::display_devices(out, verbose);
+        }
+        "lspci" => {
+            hardware::pci::lspci(out);
+        }
+        "lsacpi" => {
+            hardware::acpi::list_tables(out);
+        }
+        "sysinfo" => {
+            write!(out, "\nPlatform: {}\n", platform::name()).unwrap();
+            if let Some(vendor) = platform::hypervisor_vendor() {
+                let vendor = core::str::from_utf8(&vendor).unwrap_or("?").trim_end_matches('\0');
+                write!(out, "Hypervisor vendor: {}\n", vendor).unwrap();
+            }
+            write!(out, "Uptime ticks: {}\n", hardware::pit::timer_ticks()).unwrap();
+        }
+        "breakpoint" => {
+            return CommandAction::RunUnlocked(UnlockedCommand::Breakpoint);
+        }
+        "read" => {
+            let lba = match arguments.as_slice() {
+                [] => Some(0),
+                [lba] => lba.parse::<u64>().ok(),
+                _ => None,
+            };
+            match lba {
+                Some(lba) => return CommandAction::RunUnlocked(UnlockedCommand::ReadBlock(lba)),
+                None => out.write_str("\nUsage: read [lba]\n").unwrap(),
+            }
+        }
+        "write" => {
+            // No default LBA: this overwrites whatever is stored there
+            let lba = match arguments.as_slice() {
+                [lba] => lba.parse::<u64>().ok(),
+                _ => None,
+            };
+            match lba {
+                Some(lba) => return CommandAction::RunUnlocked(UnlockedCommand::WriteBlock(lba)),
+                None => out.write_str("\nUsage: write <lba>\n").unwrap(),
+            }
+        }
+        "ls" => {
+            return CommandAction::RunUnlocked(UnlockedCommand::ListFiles);
+        }
+        "cat" => {
+            match command[command_name.len()..].trim() {
+                "" => out.write_str("\nUsage: cat <file>\n").unwrap(),
+                name => return CommandAction::RunUnlocked(UnlockedCommand::CatFile(name)),
+            }
+        }
+        "bind" => {
+            return CommandAction::Bind(command[command_name.len()..].trim());
+        }
+        "hexdump" => {
+            hexdump(&arguments, out);
+        }
+        "translate" => {
+            translate(&arguments, out);
+        }
+        "cpu" => {
+            let vendor = arch::cpuid::vendor();
+            write!(out, "\nVendor: {}\n", core::str::from_utf8(&vendor).unwrap_or("?")).unwrap();
+            if let Some(brand) = arch::cpuid::brand() {
+                let brand = core::str::from_utf8(&brand).unwrap_or("?").trim_end_matches('\0').trim();
+                write!(out, "Brand: {}\n", brand).unwrap();
+            }
+            let yes_no = |present: bool| if present { "yes" } else { "no" };
+            write!(out, "APIC: {}, x2APIC: {}, invariant TSC: {}\n",
+                   yes_no(arch::cpuid::has_apic()),
+                   yes_no(arch::cpuid::has_x2apic()),
+                   yes_no(arch::cpuid::has_hpet_invariant_tsc())).unwrap();
+        }
+        "nvme" => {
+            match filesystem::nvme::version() {
+                Some(version) => {
+                    write!(out, "\nNVMe version: {}\n", version).unwrap();
+                    match filesystem::nvme::default_namespace() {
+                        Some(nsid) => write!(out, "Default namespace: {}\n", nsid).unwrap(),
+                        None => out.write_str("No active namespaces\n").unwrap(),
+                    }
+                }
+                None => out.write_str("\nNo NVMe controller initialized\n").unwrap(),
+            }
+        }
+        "disk" => {
+            match filesystem::nvme::namespace_count() {
+                Some(count) => {
+                    write!(out, "\nNVMe namespaces: {}\n", count).unwrap();
+                    match filesystem::nvme::namespace_info() {
+                        Some(info) => write!(
+                            out,
+                            "Namespace {}: {} MB, {}-byte blocks ({} of {} blocks allocatable)\n",
+                            info.nsid,
+                            info.capacity_bytes() / (1024 * 1024),
+                            info.block_size,
+                            info.capacity_blocks,
+                            info.size_blocks
+                        ).unwrap(),
+                        None if count > 0 => out.write_str("Default namespace could not be identified\n").unwrap(),
+                        None => {}
+                    }
+                }
+                None => out.write_str("\nNo NVMe controller initialized\n").unwrap(),
+            }
+        }
+        "heartbeat" => {
+            match arguments.first().copied() {
+                Some("off") => {
+                    task::heartbeat::set_interval(None);
+                    out.write_str("\nHeartbeat off\n").unwrap();
+                }
+                Some(seconds) => match seconds.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => {
+                        task::heartbeat::set_interval(Some(seconds));
+                        write!(out, "\nHeartbeat every {} s on serial\n", seconds).unwrap();
+                    }
+                    _ => out.write_str("\nUsage: heartbeat <seconds|off>\n").unwrap(),
+                },
+                None => out.write_str("\nUsage: heartbeat <seconds|off>\n").unwrap(),
+            }
+        }
+        "loglevel" => {
+            match arguments.first().copied() {
+                None => write!(out, "\nLog level: {}\n", logger::max_level().name()).unwrap(),
+                Some(name) => match logger::Level::from_name(name) {
+                    Some(level) => {
+                        logger::set_max_level(level);
+                        write!(out, "\nLog level set to {}\n", level.name()).unwrap();
+                    }
+                    None => out.write_str("\nUsage: loglevel [error|warn|info|debug]\n").unwrap(),
+                },
+            }
+        }
+        "keymap" => {
+            match arguments.first().copied() {
+                None => write!(out, "\nKeyboard layout: {}\n", task::keyboard::layout().name()).unwrap(),
+                Some(name) => match task::keyboard::KeyLayout::from_name(name) {
+                    Some(layout) => {
+                        task::keyboard::set_layout(layout);
+                        write!(out, "\nKeyboard layout set to {}\n", layout.name()).unwrap();
+                    }
+                    None => out.write_str("\nUsage: keymap [us|de|uk]\n").unwrap(),
+                },
+            }
+        }
+        "color" => {
+            match arguments.first().and_then(|name| Color::from_name(name)) {
+                Some(color) => {
+                    out.write_str("\nText color changed\n").unwrap();
+                    return CommandAction::SetColor(color);
+                }
+                None => out.write_str("\nUsage: color <black|blue|green|cyan|red|magenta|brown|lightgray|darkgray|lightblue|lightgreen|lightcyan|lightred|pink|yellow|white>\n").unwrap(),
+            }
+        }
+        "prompt" => {
+            match arguments.as_slice() {
+                ["-c", name] => match Color::from_name(name) {
+                    Some(color) => {
+                        out.write_str("\nPrompt color changed\n").unwrap();
+                        return CommandAction::SetPromptColor(color);
+                    }
+                    None => out.write_str("\nUnknown color; see 'color' for the names\n").unwrap(),
+                },
+                [] | ["-c"] => out.write_str("\nUsage: prompt <text> | prompt -c <color>\n").unwrap(),
+                _ => {
+                    // Everything after the command name, with its inner spacing kept
+                    let prompt = command[command_name.len()..].trim();
+                    return CommandAction::SetPrompt(prompt);
+                }
+            }
+        }
+        "exit" => {
+            // Interactive `cargo run` sessions have the isa-debug-exit device too, so being under
+            // QEMU is not enough; only a headless session may be ended from the shell
+            if !platform::is_headless() || !platform::is_qemu() {
+                out.write_str("\nexit is not applicable outside a headless QEMU session\n").unwrap();
+                return CommandAction::None;
+            }
+
+            let exit_code = match arguments.first().map(|code| code.parse::<u32>()) {
+                None | Some(Ok(0)) => QemuExitCode::Success,
+                Some(Ok(_)) => QemuExitCode::Failed,
+                Some(Err(_)) => {
+                    out.write_str("\nUsage: exit [code]\n").unwrap();
+                    return CommandAction::None;
+                }
+            };
+
+            serial_println!("Exiting QEMU with {:?}", exit_code);
+            crate::exit_qemu(exit_code);
+
+            // Still running, so QEMU was started without the isa-debug-exit device
+            out.write_str("\nexit is not applicable: no isa-debug-exit device\n").unwrap();
+        }
+        "scan" => {
+            hardware::pci::display_disks(out);
+        }
+        _ => {
+            out.write_str("\nUnknown command: ").unwrap();
+            out.write_str(command).unwrap();
+            out.write_str("\nType 'help' to see available commands.\n").unwrap();
+        }
+    }
+
+    CommandAction::None
+}
+
+/// Handles `translate <hexaddr>` by walking the active page table.
+fn translate(arguments: &[&str], out: &mut dyn Write) {
+    let address = match arguments.first()
+        .and_then(|arg| u64::from_str_radix(arg.trim_start_matches("0x"), 16).ok())
+    {
+        Some(address) => address,
+        None => {
+            out.write_str("\nUsage: translate <hexaddr>\n").unwrap();
+            return;
+        }
+    };
+
+    let virt = match VirtAddr::try_new(address) {
+        Ok(virt) => virt,
+        Err(_) => {
+            write!(out, "\n{:#x} is not a canonical address\n", address).unwrap();
+            return;
+        }
+    };
+    let offset = match mem::memory::physical_memory_offset() {
+        Some(offset) => offset,
+        None => {
+            out.write_str("\nPhysical memory offset not set\n").unwrap();
+            return;
+        }
+    };
+
+    match unsafe { mem::memory::translate_addr(virt, offset) } {
+        Some(phys) => write!(out, "\n{:#x} -> {:#x}\n", address, phys.as_u64()).unwrap(),
+        None => write!(out, "\n{:#x} -> not mapped\n", address).unwrap(),
+    }
+}
+
+/// Handles `hexdump <hexaddr> [len]`, mapping the range first if nothing maps it yet.
+fn hexdump(arguments: &[&str], out: &mut dyn Write) {
+    let address = arguments.first()
+        .and_then(|arg| u64::from_str_radix(arg.trim_start_matches("0x"), 16).ok());
+    let len = match arguments.get(1) {
+        Some(arg) => match arg.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => arg.parse::<u64>().ok(),
+        },
+        None => Some(256),
+    };
+    let (address, len) = match (address, len) {
+        (Some(address), Some(len)) if len > 0 && len <= HEXDUMP_MAX_LEN => (address, len),
+        _ => {
+            write!(out, "\nUsage: hexdump <hexaddr> [len], len 1-{}\n", HEXDUMP_MAX_LEN).unwrap();
+            return;
+        }
+    };
+
+    let virt = match mem::memory::map_physical_range(address, len) {
+        Ok(virt) => virt,
+        Err(e) => {
+            write!(out, "\nCannot map {:#x}: {}\n", address, e).unwrap();
+            return;
+        }
+    };
+
+    out.write_str("\n").unwrap();
+    let base: *const u8 = virt.as_ptr();
+    let mut line = [0u8; 16];
+    for line_start in (0..len).step_by(16) {
+        let count = (len - line_start).min(16) as usize;
+        for (i, byte) in line[..count].iter_mut().enumerate() {
+            // Device registers can have side effects, so read each byte exactly once
+            *byte = unsafe { core::ptr::read_volatile(base.add(line_start as usize + i)) };
+        }
+
+        write!(out, "{:08x} ", address + line_start).unwrap();
+        for i in 0..16 {
+            if i == 8 {
+                out.write_str(" ").unwrap();
+            }
+            match line[..count].get(i) {
+                Some(byte) => write!(out, " {:02x}", byte).unwrap(),
+                None => out.write_str("   ").unwrap(),
+            }
+        }
+        out.write_str("  |").unwrap();
+        for &byte in &line[..count] {
+            let shown = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' };
+            out.write_char(shown).unwrap();
+        }
+        out.write_str("|\n").unwrap();
+    }
+}
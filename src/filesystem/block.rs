@@ -0,0 +1,43 @@
+use core::fmt;
+
+/// Why a block device request failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The LBA is at or past `num_blocks`.
+    OutOfRange,
+    /// The buffer is shorter than one block.
+    BufferTooSmall,
+    /// The device itself reported an error.
+    Device(&'static str),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "block out of range"),
+            BlockError::BufferTooSmall => write!(f, "buffer smaller than a block"),
+            BlockError::Device(message) => write!(f, "device error: {}", message),
+        }
+    }
+}
+
+/// Storage addressed in fixed-size logical blocks, so filesystems can be written once for
+/// NVMe, a RAM disk or whatever comes next.
+///
+/// Buffers must hold at least `block_size()` bytes; only the first block's worth is used.
+pub trait BlockDevice {
+    /// Size of one logical block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Number of logical blocks; valid LBAs are `0..num_blocks()`.
+    fn num_blocks(&self) -> u64;
+
+    fn read_block(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    fn write_block(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes earlier writes durable. Devices without a volatile cache have nothing to do.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::filesystem::block::{BlockDevice, BlockError};
+
+const SECTOR_SIGNATURE: u16 = 0xAA55;
+const MBR_PARTITION_TABLE: usize = 446;
+const MBR_PARTITION_ENTRY_SIZE: usize = 16;
+const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F; // Read-only, hidden, system and volume ID together
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+const ENTRY_KANJI_E5: u8 = 0x05; // Stands for a real 0xE5 as first name byte
+
+// Cluster count limits from the FAT specification; they alone decide the FAT type
+const FAT12_MAX_CLUSTERS: u32 = 4084;
+const FAT16_MAX_CLUSTERS: u32 = 65524;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    Block(BlockError),
+    /// Neither a FAT boot sector nor an MBR with a FAT partition.
+    NoFatVolume,
+    /// A FAT12 volume, or a sector size other than 512-4096.
+    Unsupported,
+    /// A cluster chain that leaves the volume, loops or ends early.
+    Corrupt,
+    NotFound,
+    IsDirectory,
+}
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FatError::Block(error) => write!(f, "{}", error),
+            FatError::NoFatVolume => write!(f, "no FAT volume found"),
+            FatError::Unsupported => write!(f, "unsupported FAT variant"),
+            FatError::Corrupt => write!(f, "corrupt cluster chain"),
+            FatError::NotFound => write!(f, "file not found"),
+            FatError::IsDirectory => write!(f, "is a directory"),
+        }
+    }
+}
+
+impl From<BlockError> for FatError {
+    fn from(error: BlockError) -> Self {
+        FatError::Block(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// Volume layout from the BIOS Parameter Block, in sectors relative to the volume start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FatGeometry {
+    pub fat_type: FatType,
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fat_count: u32,
+    pub fat_size: u32,          // Sectors per FAT
+    pub root_entry_count: u32,  // FAT16 only; FAT32 keeps the root in clusters
+    pub root_cluster: u32,      // FAT32 only
+    pub first_data_sector: u32,
+    pub cluster_count: u32,
+}
+
+impl FatGeometry {
+    /// Parses the boot sector of a FAT volume.
+    pub fn parse(sector: &[u8]) -> Result<FatGeometry, FatError> {
+        if sector.len() < 512 || read_u16(sector, 510) != SECTOR_SIGNATURE {
+            return Err(FatError::NoFatVolume);
+        }
+        // A boot sector starts with a jump over the BPB
+        if sector[0] != 0xEB && sector[0] != 0xE9 {
+            return Err(FatError::NoFatVolume);
+        }
+
+        let bytes_per_sector = read_u16(sector, 11) as u32;
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved_sectors = read_u16(sector, 14) as u32;
+        let fat_count = sector[16] as u32;
+        let root_entry_count = read_u16(sector, 17) as u32;
+        let total_sectors = match read_u16(sector, 19) {
+            0 => read_u32(sector, 32),
+            total => total as u32,
+        };
+        let fat_size = match read_u16(sector, 22) {
+            0 => read_u32(sector, 36),
+            size => size as u32,
+        };
+
+        // Anything but a sane BPB is not a volume, e.g. an MBR whose boot code starts with a jump
+        if !bytes_per_sector.is_power_of_two() || !(512..=4096).contains(&bytes_per_sector) {
+            return Err(FatError::NoFatVolume);
+        }
+        if !sectors_per_cluster.is_power_of_two() || reserved_sectors == 0 || fat_count == 0 || fat_size == 0 {
+            return Err(FatError::NoFatVolume);
+        }
+
+        let root_dir_sectors = (root_entry_count * DIR_ENTRY_SIZE as u32).div_ceil(bytes_per_sector);
+        let first_data_sector = reserved_sectors + fat_count * fat_size + root_dir_sectors;
+        let data_sectors = total_sectors.checked_sub(first_data_sector).ok_or(FatError::NoFatVolume)?;
+        let cluster_count = data_sectors / sectors_per_cluster;
+
+        let fat_type = match cluster_count {
+            0..=FAT12_MAX_CLUSTERS => return Err(FatError::Unsupported),
+            ..=FAT16_MAX_CLUSTERS => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+
+        Ok(FatGeometry {
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            fat_size,
+            root_entry_count,
+            root_cluster: if fat_type == FatType::Fat32 { read_u32(sector, 44) } else { 0 },
+            first_data_sector,
+            cluster_count,
+        })
+    }
+
+    fn cluster_size(&self) -> usize {
+        (self.bytes_per_sector * self.sectors_per_cluster) as usize
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.first_data_sector + (cluster - 2) * self.sectors_per_cluster
+    }
+}
+
+/// Start sector of the first FAT16 or FAT32 partition in an MBR, if there is one.
+pub fn find_fat_partition(mbr: &[u8]) -> Option<u32> {
+    if mbr.len() < 512 || read_u16(mbr, 510) != SECTOR_SIGNATURE {
+        return None;
+    }
+
+    (0..4)
+        .map(|i| &mbr[MBR_PARTITION_TABLE + i * MBR_PARTITION_ENTRY_SIZE..][..MBR_PARTITION_ENTRY_SIZE])
+        .find(|entry| matches!(entry[4], 0x04 | 0x06 | 0x0E | 0x0B | 0x0C))
+        .map(|entry| read_u32(entry, 8))
+}
+
+/// One entry of a directory listing. Names are the short 8.3 names; long names are skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u32,
+    first_cluster: u32,
+}
+
+/// A read-only FAT16/FAT32 volume on a block device.
+pub struct FatVolume<D: BlockDevice> {
+    device: D,
+    start: u64, // Byte offset of the volume on the device
+    geometry: FatGeometry,
+}
+
+impl<D: BlockDevice> FatVolume<D> {
+    /// Opens the FAT volume at the start of `device`, or in its first FAT partition.
+    pub fn open(mut device: D) -> Result<Self, FatError> {
+        let mut first = vec![0u8; device.block_size().max(512)];
+        read_bytes(&mut device, 0, &mut first)?;
+
+        let (start, geometry) = match FatGeometry::parse(&first) {
+            Ok(geometry) => (0, geometry),
+            Err(FatError::NoFatVolume) => {
+                // Partition offsets are in 512-byte sectors, whatever the device block size
+                let start = find_fat_partition(&first).ok_or(FatError::NoFatVolume)? as u64 * 512;
+                let mut boot = vec![0u8; 512];
+                read_bytes(&mut device, start, &mut boot)?;
+                (start, FatGeometry::parse(&boot)?)
+            }
+            Err(error) => return Err(error),
+        };
+
+        Ok(FatVolume { device, start, geometry })
+    }
+
+    pub fn geometry(&self) -> &FatGeometry {
+        &self.geometry
+    }
+
+    /// Lists the root directory.
+    pub fn root_dir(&mut self) -> Result<Vec<DirEntry>, FatError> {
+        let raw = match self.geometry.fat_type {
+            FatType::Fat16 => {
+                let mut raw = vec![0u8; self.geometry.root_entry_count as usize * DIR_ENTRY_SIZE];
+                let sector = self.geometry.reserved_sectors + self.geometry.fat_count * self.geometry.fat_size;
+                self.read_sectors(sector, &mut raw)?;
+                raw
+            }
+            FatType::Fat32 => self.read_chain(self.geometry.root_cluster, None)?,
+        };
+
+        Ok(parse_dir(&raw))
+    }
+
+    /// Reads the whole file `name` from the root directory. The name match ignores case.
+    pub fn read_file(&mut self, name: &str) -> Result<Vec<u8>, FatError> {
+        let entry = self.root_dir()?
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))
+            .ok_or(FatError::NotFound)?;
+        if entry.is_dir {
+            return Err(FatError::IsDirectory);
+        }
+        if entry.size == 0 {
+            return Ok(Vec::new());
+        }
+
+        self.read_chain(entry.first_cluster, Some(entry.size as usize))
+    }
+
+    /// Reads the cluster chain starting at `cluster`, stopping after `limit` bytes if given.
+    fn read_chain(&mut self, mut cluster: u32, limit: Option<usize>) -> Result<Vec<u8>, FatError> {
+        let cluster_size = self.geometry.cluster_size();
+        let mut data = Vec::new();
+
+        // Every cluster can be visited once, so a longer chain has to loop
+        for _ in 0..self.geometry.cluster_count {
+            if !self.is_data_cluster(cluster) {
+                return Err(FatError::Corrupt);
+            }
+
+            let offset = data.len();
+            data.resize(offset + cluster_size, 0);
+            self.read_sectors(self.geometry.cluster_sector(cluster), &mut data[offset..])?;
+
+            if let Some(limit) = limit.filter(|&limit| data.len() >= limit) {
+                data.truncate(limit);
+                return Ok(data);
+            }
+
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None if limit.is_none() => return Ok(data),
+                None => return Err(FatError::Corrupt), // Chain shorter than the file size
+            };
+        }
+
+        Err(FatError::Corrupt)
+    }
+
+    /// The cluster after `cluster` in its chain, or `None` at the end of the chain.
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, FatError> {
+        let (offset, end_marker) = match self.geometry.fat_type {
+            FatType::Fat16 => (cluster * 2, 0xFFF8),
+            FatType::Fat32 => (cluster * 4, 0x0FFF_FFF8),
+        };
+        let byte = self.start
+            + self.geometry.reserved_sectors as u64 * self.geometry.bytes_per_sector as u64
+            + offset as u64;
+
+        let mut raw = [0u8; 4];
+        let len = if self.geometry.fat_type == FatType::Fat16 { 2 } else { 4 };
+        read_bytes(&mut self.device, byte, &mut raw[..len])?;
+        // The top four bits of a FAT32 entry are reserved
+        let next = u32::from_le_bytes(raw) & 0x0FFF_FFFF;
+
+        Ok(if next >= end_marker { None } else { Some(next) })
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (2..self.geometry.cluster_count + 2).contains(&cluster)
+    }
+
+    fn read_sectors(&mut self, sector: u32, buf: &mut [u8]) -> Result<(), FatError> {
+        let byte = self.start + sector as u64 * self.geometry.bytes_per_sector as u64;
+        read_bytes(&mut self.device, byte, buf)
+    }
+}
+
+/// Fills `buf` from byte `offset` of `device`, which need not be block aligned.
+fn read_bytes<D: BlockDevice>(device: &mut D, offset: u64, buf: &mut [u8]) -> Result<(), FatError> {
+    let block_size = device.block_size();
+    let mut block = vec![0u8; block_size];
+    let mut done = 0;
+
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let lba = position / block_size as u64;
+        let within = (position % block_size as u64) as usize;
+        let count = (block_size - within).min(buf.len() - done);
+
+        device.read_block(lba, &mut block)?;
+        buf[done..done + count].copy_from_slice(&block[within..within + count]);
+        done += count;
+    }
+
+    Ok(())
+}
+
+fn parse_dir(raw: &[u8]) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+
+    for entry in raw.chunks_exact(DIR_ENTRY_SIZE) {
+        let attributes = entry[11];
+        match entry[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => continue,
+            _ if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME => continue,
+            _ if attributes & ATTR_VOLUME_ID != 0 => continue,
+            _ => {}
+        }
+
+        entries.push(DirEntry {
+            name: short_name(&entry[..11]),
+            is_dir: attributes & ATTR_DIRECTORY != 0,
+            size: read_u32(entry, 28),
+            first_cluster: (read_u16(entry, 20) as u32) << 16 | read_u16(entry, 26) as u32,
+        });
+    }
+
+    entries
+}
+
+/// Turns a space-padded 8.3 name such as `README  TXT` into `README.TXT`.
+fn short_name(raw: &[u8]) -> String {
+    let mut name = String::new();
+    for (i, &byte) in raw[..8].iter().enumerate() {
+        let byte = if i == 0 && byte == ENTRY_KANJI_E5 { ENTRY_DELETED } else { byte };
+        name.push(byte as char);
+    }
+    name.truncate(name.trim_end().len());
+
+    let extension = core::str::from_utf8(&raw[8..11]).unwrap_or("").trim_end();
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(extension);
+    }
+    name
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[test_case]
+fn test_parse_fat32_geometry() {
+    let mut sector = [0u8; 512];
+    sector[0] = 0xEB;
+    sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+    sector[13] = 8;
+    sector[14..16].copy_from_slice(&32u16.to_le_bytes());
+    sector[16] = 2;
+    sector[32..36].copy_from_slice(&2_097_152u32.to_le_bytes()); // 1 GiB
+    sector[36..40].copy_from_slice(&2048u32.to_le_bytes());
+    sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+    sector[510..512].copy_from_slice(&SECTOR_SIGNATURE.to_le_bytes());
+
+    let geometry = FatGeometry::parse(&sector).expect("boot sector did not parse");
+    assert_eq!(geometry.fat_type, FatType::Fat32);
+    assert_eq!(geometry.first_data_sector, 32 + 2 * 2048);
+    assert_eq!(geometry.cluster_count, (2_097_152 - 4128) / 8);
+    assert_eq!(geometry.root_cluster, 2);
+    assert_eq!(geometry.cluster_size(), 4096);
+
+    // Too few clusters for anything but FAT12
+    sector[32..36].copy_from_slice(&20_000u32.to_le_bytes());
+    assert_eq!(FatGeometry::parse(&sector), Err(FatError::Unsupported));
+
+    sector[11..13].copy_from_slice(&0u16.to_le_bytes());
+    assert_eq!(FatGeometry::parse(&sector), Err(FatError::NoFatVolume));
+
+    sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+    sector[0] = 0;
+    assert_eq!(FatGeometry::parse(&sector), Err(FatError::NoFatVolume));
+}
+
+#[test_case]
+fn test_find_fat_partition() {
+    let mut mbr = [0u8; 512];
+    mbr[510..512].copy_from_slice(&SECTOR_SIGNATURE.to_le_bytes());
+    assert_eq!(find_fat_partition(&mbr), None);
+
+    // Entry 0 is a Linux partition, entry 1 is FAT32 with LBA addressing
+    mbr[MBR_PARTITION_TABLE + 4] = 0x83;
+    mbr[MBR_PARTITION_TABLE + 16 + 4] = 0x0C;
+    mbr[MBR_PARTITION_TABLE + 16 + 8..MBR_PARTITION_TABLE + 16 + 12].copy_from_slice(&2048u32.to_le_bytes());
+    assert_eq!(find_fat_partition(&mbr), Some(2048));
+}
@@ -1,2 +1,4 @@
-pub mod fat32;
-pub mod nvme;
\ No newline at end of file
+pub mod block;
+pub mod fat;
+pub mod nvme;
+pub mod ramdisk;
@@ -1,27 +1,287 @@
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB};
-use x86_64::{VirtAddr};
-
-use crate::{serial_println};
-use crate::hardware::pci::{read_pci_bar, get_pci_device};
-use crate::hardware::pit::{timer_wait_ms, timer_wait_sec};
-use crate::mem::memory::map_nvme_base;
-
-const NVME_RESET_TIMEOUT: u8 = 100;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::{debug, error, info, platform, warn};
+use crate::filesystem::block::{BlockDevice, BlockError};
+use crate::hardware::pci::{bar_size, enable_bus_mastering, enable_memory_space, find_capability, for_each_device, read_pci_bar, PciDevice, PCI_CAP_ID_MSIX};
+use crate::hardware::mmio::{wait_for_bit, RegisterBlock, Timeout};
+use crate::hardware::pit::{ms_to_ticks, timer_ticks};
+use crate::mem::memory::{map_nvme_base, unmap_page, ContiguousFrames, MMIO_VIRT_BASE};
+use crate::sync::with_locked_irqsafe;
+
+// Floor for the CAP.TO based ready timeout. The short one was tuned against QEMU
+const NVME_RESET_TIMEOUT_QEMU_MS: u64 = 100;
+const NVME_RESET_TIMEOUT_HARDWARE_MS: u64 = 2000;
+const NVME_IDENTIFY_CNS_NAMESPACE: u8 = 0;
 const NVME_IDENTIFY_CNS: u32 = 1;
-const QUEUE_SIZE: u32 = 256; // Maximum queue size
-const ASQ_SIZE: usize = 64 * 64; // Admin Submission Queue size
-const ACQ_SIZE: usize = 64 * 16;  // Admin Completion Queue size
-
+const NVME_IDENTIFY_CNS_ACTIVE_NAMESPACES: u8 = 2;
+const ACTIVE_NAMESPACE_LIST_ENTRIES: usize = 4096 / 4;
+const ADMIN_QUEUE_DEPTH: u32 = 32; // Admin commands are only issued during bring-up
+const IO_QUEUE_DEPTH: u32 = 64;    // Depth requested when creating I/O queues
+const SUBMISSION_ENTRY_SIZE: u32 = 64;
+const FRAME_QUEUE_ENTRIES: u32 = 4096 / SUBMISSION_ENTRY_SIZE; // Entries that fit in one queue frame
+
+const COMPLETION_ENTRY_SIZE: u64 = 16;
+const IO_QUEUE_ID: u16 = 1;
+const DEFAULT_BLOCK_SIZE: usize = 512; // For namespaces whose LBA format is not known
+const IDENTIFY_NAMESPACE_LBAF: usize = 128; // Offset of the LBA format table
+const IDENTIFY_NAMESPACE_MIN_LEN: usize = IDENTIFY_NAMESPACE_LBAF + 64 * 4;
+const DMA_BUFFER_FRAMES: usize = 16;   // Physically contiguous bounce buffer, described by a PRP list
+const DMA_BUFFER_SIZE: usize = DMA_BUFFER_FRAMES * 4096;
+const PRP_LIST_ENTRIES: usize = 4096 / 8; // A single list page; we never chain lists
+
+const NVME_ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const NVME_ADMIN_CREATE_IO_CQ: u8 = 0x05;
 const NVME_ADMIN_IDENTIFY: u8 = 0x06;
 const NVME_ADMIN_SET_FEATURES: u8 = 0x09;
 const NVME_FEAT_SOFTWARE_PROGRESS_MARKER: u8 = 0x80;
 
+const NVME_CMD_FLUSH: u8 = 0x00;
+const NVME_CMD_WRITE: u8 = 0x01;
+const NVME_CMD_READ: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeError {
+    NotInitialized,
+    NoNamespace,
+    BufferTooSmall,
+    OutOfRange,
+    TransferTooLarge,
+    Timeout,
+    CommandFailed(NvmeStatus),
+}
+
+impl fmt::Display for NvmeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NvmeError::NotInitialized => write!(f, "NVMe controller not initialized"),
+            NvmeError::NoNamespace => write!(f, "no active NVMe namespace"),
+            NvmeError::BufferTooSmall => write!(f, "buffer too small for the transfer"),
+            NvmeError::OutOfRange => write!(f, "blocks past the end of the namespace"),
+            NvmeError::TransferTooLarge => write!(f, "transfer too large for one PRP list"),
+            NvmeError::Timeout => write!(f, "completion timeout"),
+            NvmeError::CommandFailed(status) => write!(f, "NVMe error: {}", status),
+        }
+    }
+}
+
+/// Decoded status field of a completion entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeStatus {
+    Success,
+    // Generic Command Status (SCT 0)
+    InvalidOpcode,
+    InvalidField,
+    CommandIdConflict,
+    DataTransferError,
+    AbortedPowerLoss,
+    InternalError,
+    AbortRequested,
+    AbortedQueueDeleted,
+    InvalidNamespace,
+    LbaOutOfRange,
+    CapacityExceeded,
+    NamespaceNotReady,
+    /// Command Specific Status (SCT 1), e.g. an invalid queue identifier on queue creation.
+    CommandSpecific(u8),
+    /// Media and Data Integrity Errors (SCT 2).
+    MediaError(u8),
+    Other { sct: u8, sc: u8 },
+}
+
+impl NvmeStatus {
+    /// Decodes the 16-bit status field: phase tag in bit 0, SC in bits 1:8, SCT in bits 9:11.
+    pub fn from_status(status: u16) -> NvmeStatus {
+        let sc = ((status >> 1) & 0xFF) as u8;
+        let sct = ((status >> 9) & 0x7) as u8;
+
+        match (sct, sc) {
+            (0, 0x00) => NvmeStatus::Success,
+            (0, 0x01) => NvmeStatus::InvalidOpcode,
+            (0, 0x02) => NvmeStatus::InvalidField,
+            (0, 0x03) => NvmeStatus::CommandIdConflict,
+            (0, 0x04) => NvmeStatus::DataTransferError,
+            (0, 0x05) => NvmeStatus::AbortedPowerLoss,
+            (0, 0x06) => NvmeStatus::InternalError,
+            (0, 0x07) => NvmeStatus::AbortRequested,
+            (0, 0x08) => NvmeStatus::AbortedQueueDeleted,
+            (0, 0x0B) => NvmeStatus::InvalidNamespace,
+            (0, 0x80) => NvmeStatus::LbaOutOfRange,
+            (0, 0x81) => NvmeStatus::CapacityExceeded,
+            (0, 0x82) => NvmeStatus::NamespaceNotReady,
+            (1, sc) => NvmeStatus::CommandSpecific(sc),
+            (2, sc) => NvmeStatus::MediaError(sc),
+            (sct, sc) => NvmeStatus::Other { sct, sc },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NvmeStatus::Success => "Successful Completion",
+            NvmeStatus::InvalidOpcode => "Invalid Command Opcode",
+            NvmeStatus::InvalidField => "Invalid Field in Command",
+            NvmeStatus::CommandIdConflict => "Command ID Conflict",
+            NvmeStatus::DataTransferError => "Data Transfer Error",
+            NvmeStatus::AbortedPowerLoss => "Commands Aborted due to Power Loss Notification",
+            NvmeStatus::InternalError => "Internal Error",
+            NvmeStatus::AbortRequested => "Command Abort Requested",
+            NvmeStatus::AbortedQueueDeleted => "Command Aborted due to SQ Deletion",
+            NvmeStatus::InvalidNamespace => "Invalid Namespace or Format",
+            NvmeStatus::LbaOutOfRange => "LBA Out of Range",
+            NvmeStatus::CapacityExceeded => "Capacity Exceeded",
+            NvmeStatus::NamespaceNotReady => "Namespace Not Ready",
+            NvmeStatus::CommandSpecific(_) => "Command Specific Error",
+            NvmeStatus::MediaError(_) => "Media or Data Integrity Error",
+            NvmeStatus::Other { .. } => "Unknown Status",
+        }
+    }
+}
+
+impl fmt::Display for NvmeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NvmeStatus::CommandSpecific(sc) | NvmeStatus::MediaError(sc) => write!(f, "{} ({:#x})", self.name(), sc),
+            NvmeStatus::Other { sct, sc } => write!(f, "{} (SCT {}, SC {:#x})", self.name(), sct, sc),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+// Lets init code that reports `&'static str` errors use `?` on admin commands
+impl From<NvmeError> for &'static str {
+    fn from(error: NvmeError) -> Self {
+        match error {
+            NvmeError::NotInitialized => "NVMe controller not initialized",
+            NvmeError::NoNamespace => "no active NVMe namespace",
+            NvmeError::BufferTooSmall => "buffer too small for the transfer",
+            NvmeError::OutOfRange => "blocks past the end of the namespace",
+            NvmeError::TransferTooLarge => "transfer too large for one PRP list",
+            NvmeError::Timeout => "completion timeout",
+            NvmeError::CommandFailed(status) => status.name(),
+        }
+    }
+}
+
+/// Where completion entries are read from, so tests can stand in for a controller.
+trait CompletionSource {
+    fn entry(&self, index: u32) -> NvmeCompletion;
+}
+
+/// A completion queue in memory, at its `MMIO_VIRT_BASE` mapping.
+struct MappedCompletionQueue(u64);
+
+impl CompletionSource for MappedCompletionQueue {
+    fn entry(&self, index: u32) -> NvmeCompletion {
+        let entry = (self.0 + index as u64 * COMPLETION_ENTRY_SIZE) as *const NvmeCompletion;
+        unsafe { core::ptr::read_volatile(entry) }
+    }
+}
+
+/// Waits for the entry at `head` to carry the expected phase tag, for at most `timeout_ms`.
+///
+/// The deadline is measured in timer ticks, so interrupts must be enabled while this runs.
+fn poll_completion(queue: &impl CompletionSource, head: u32, phase: bool, timeout_ms: u64) -> Result<NvmeCompletion, Timeout> {
+    let deadline = timer_ticks() + ms_to_ticks(timeout_ms);
+
+    loop {
+        let completion = queue.entry(head);
+        if (completion.status & 1 != 0) == phase {
+            return Ok(completion);
+        }
+        if timer_ticks() >= deadline {
+            return Err(Timeout);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// A submission queue and the completion queue it posts to, each backed by one frame.
+///
+/// The controller reads and writes the queues through their physical addresses; we go through
+/// the mapping at `MMIO_VIRT_BASE + phys`.
+struct QueuePair {
+    id: u16,
+    depth: u32,
+    sq_phys: u64,
+    cq_phys: u64,
+    sq_tail: u32,
+    cq_head: u32,
+    phase: bool, // Phase tag that marks a completion entry as new; flips on every wrap
+    next_command_id: u16,
+}
+
 struct NvmeRegisters {
     nvme_base_addr: u64,
     nvme_virt_addr: VirtAddr,
+    register_size: u64, // Size of BAR0, covering the registers and every doorbell
+    admin_sq_virt: u64, // CPU view of the admin queues; ASQ/ACQ hold the physical addresses
+    admin_cq_virt: u64,
     submission_queue_tail: u64,
     completion_queue_head: u64,
-    doorbell_stride: u32,
+    completion_phase: bool, // Expected phase tag of the next admin completion
+    capabilities: NvmeCapabilities,
+    version: NvmeVersion,
+    default_namespace: Option<u32>, // First active nsid, used when a command does not name one
+    namespace_count: u32,
+    namespace: Option<NamespaceInfo>, // Identify Namespace data of the default namespace
+    io_queue: Option<QueuePair>,
+    dma_buffer: u64, // Physical start of the contiguous bounce buffer
+    prp_list: Option<PhysFrame<Size4KiB>>, // Reused for every command; only one is in flight
+}
+
+/// Decoded Controller Capabilities (CAP) register at offset 0x00.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NvmeCapabilities {
+    max_queue_entries: u16,           // MQES, zero-based
+    contiguous_queues_required: bool, // CQR
+    arbitration_mechanisms: u8,       // AMS
+    timeout: u8,                      // TO, in 500 ms units
+    doorbell_stride: u8,              // DSTRD, stride is 4 << DSTRD bytes
+    subsystem_reset: bool,            // NSSRS
+    command_sets: u8,                 // CSS
+    memory_page_size_min: u8,         // MPSMIN, page size is 4096 << MPSMIN
+    memory_page_size_max: u8,         // MPSMAX
+}
+
+fn decode_cap(cap: u64) -> NvmeCapabilities {
+    NvmeCapabilities {
+        max_queue_entries: (cap & 0xFFFF) as u16,
+        contiguous_queues_required: (cap >> 16) & 0x1 != 0,
+        arbitration_mechanisms: ((cap >> 17) & 0x3) as u8,
+        timeout: ((cap >> 24) & 0xFF) as u8,
+        doorbell_stride: ((cap >> 32) & 0xF) as u8,
+        subsystem_reset: (cap >> 36) & 0x1 != 0,
+        command_sets: ((cap >> 37) & 0xFF) as u8,
+        memory_page_size_min: ((cap >> 48) & 0xF) as u8,
+        memory_page_size_max: ((cap >> 52) & 0xF) as u8,
+    }
+}
+
+/// Spec version the controller implements, from the VS register at offset 0x08.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NvmeVersion {
+    pub major: u16,
+    pub minor: u8,
+    pub tertiary: u8,
+}
+
+impl NvmeVersion {
+    fn from_register(vs: u32) -> Self {
+        NvmeVersion {
+            major: (vs >> 16) as u16,
+            minor: ((vs >> 8) & 0xFF) as u8,
+            tertiary: (vs & 0xFF) as u8,
+        }
+    }
+}
+
+impl fmt::Display for NvmeVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.tertiary)
+    }
 }
 
 #[repr(C)]
@@ -40,15 +300,61 @@ struct NvmeCommand {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Default)]
 struct NvmeCompletion {
     command_specific: u32,
     reserved: u32,
     submission_queue_head: u16,
     submission_queue_id: u16,
     command_id: u16,
-    phase_tag: u16,
-    status: u16,
+    status: u16, // Bit 0 is the phase tag
+}
+
+/// Size and LBA format of a namespace, from its Identify Namespace data (CNS 0x00).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamespaceInfo {
+    pub nsid: u32,
+    pub size_blocks: u64,     // NSZE
+    pub capacity_blocks: u64, // NCAP, how much of NSZE can actually be allocated
+    pub block_size: usize,
+}
+
+impl NamespaceInfo {
+    /// Parses Identify Namespace data. The block size comes from the LBA format that FLBAS
+    /// selects; a format the controller leaves out or that is below 512 bytes falls back to
+    /// `DEFAULT_BLOCK_SIZE`.
+    fn parse(nsid: u32, data: &[u8]) -> Option<NamespaceInfo> {
+        if data.len() < IDENTIFY_NAMESPACE_MIN_LEN {
+            return None;
+        }
+
+        let read_u64 = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let format_count = data[25] as usize + 1; // NLBAF is zero-based
+        let flbas = data[26];
+        // Bits 3:0 select the format, bits 6:5 extend the index past 16 formats
+        let format = (flbas & 0x0F) as usize | (((flbas >> 5) & 0x3) as usize) << 4;
+
+        let block_size = if format < format_count {
+            let lbads = data[IDENTIFY_NAMESPACE_LBAF + format * 4 + 2];
+            match lbads {
+                9..=16 => 1usize << lbads,
+                _ => DEFAULT_BLOCK_SIZE,
+            }
+        } else {
+            DEFAULT_BLOCK_SIZE
+        };
+
+        Some(NamespaceInfo {
+            nsid,
+            size_blocks: read_u64(0),
+            capacity_blocks: read_u64(8),
+            block_size,
+        })
+    }
+
+    pub fn capacity_bytes(&self) -> u64 {
+        self.capacity_blocks * self.block_size as u64
+    }
 }
 
 #[repr(C, packed)]
@@ -67,90 +373,82 @@ struct NvmeIdentifyController {
 
 impl NvmeRegisters {
     fn new(addr: u64) -> Self {
-        let nvme_virt_addr = VirtAddr::new(0xffff_8000_0000_0000 + addr);
+        let nvme_virt_addr = VirtAddr::new(MMIO_VIRT_BASE + addr);
 
         NvmeRegisters {
             nvme_base_addr: addr,
             nvme_virt_addr,
+            register_size: 0x1000,
+            admin_sq_virt: 0,
+            admin_cq_virt: 0,
             submission_queue_tail: 0,
             completion_queue_head: 0,
-            doorbell_stride: 0,
+            completion_phase: true,
+            capabilities: decode_cap(0),
+            version: NvmeVersion::from_register(0),
+            default_namespace: None,
+            namespace_count: 0,
+            namespace: None,
+            io_queue: None,
+            dma_buffer: 0,
+            prp_list: None,
         }
     }
 
     fn reset(&mut self) {
         // Read the CAP register
-        let cap = self.nvme_read_reg64(0x00);
-
-        // Extract fields from the CAP register
-        let cqr = (cap & (1 << 0)) != 0;
-        let mqes = (cap >> 1) & 0x7FFF; // Maximum Queue Entry Size
-        let cms = (cap & (1 << 16)) != 0;
-        let sqs = (cap >> 20) & 0x0F; // Submission Queue Size
-        let aqs = (cap >> 24) & 0x0F; // Admin Queue Size
-        let to = (cap >> 32) & 0xFF; // Timeout
-        let dstrd = (cap >> 40) & 0x0F; // Doorbell stride
-
-        // Print the fields
-        serial_println!("NVMe Controller CAP Register Details:");
-        serial_println!("CQR: {}", cqr);
-        serial_println!("MQES: {}", mqes);
-        serial_println!("CMS: {}", cms);
-        serial_println!("SQS: {}", sqs);
-        serial_println!("AQS: {}", aqs);
-        serial_println!("TO: {}", to);
-        serial_println!("DSTRD: {}", dstrd);
-
-        self.doorbell_stride = dstrd as u32;
+        self.capabilities = decode_cap(self.nvme_read_reg64(0x00));
+        debug!("NVMe Controller CAP Register Details: {:?}", self.capabilities);
+        self.version = NvmeVersion::from_register(self.nvme_read_reg32(0x08));
+        info!("NVMe Controller Version: {}", self.version);
 
         // Reset the NVMe controller
         self.nvme_write_reg32(0x14, 0); // Reset command
-        serial_println!("Sent reset command to NVMe");
+        debug!("Sent reset command to NVMe");
 
         // Wait until the reset is complete
         self.wait_nvme_reset();
     }
 
-    fn wait_nvme_reset(&self) {
-        let mut timeout = NVME_RESET_TIMEOUT;
+    /// Clamps a requested queue depth to CAP.MQES and to the single frame backing each queue.
+    fn clamp_queue_depth(&self, depth: u32) -> u32 {
+        let controller_max = self.capabilities.max_queue_entries as u32 + 1;
+        depth.min(controller_max).min(FRAME_QUEUE_ENTRIES)
+    }
 
-        while timeout > 0 {
-            timer_wait_ms(1);  // Wait 1 ms
-            let status = self.nvme_read_reg32(0x1C);
+    fn admin_queue_depth(&self) -> u32 {
+        self.clamp_queue_depth(ADMIN_QUEUE_DEPTH)
+    }
 
-            if status == 0 {
-                self.nvme_write_reg32(0x14, 1);  // Power on the controller
-                return;
-            }
+    /// How long CSTS.RDY may take to change, from CAP.TO (500 ms units).
+    fn ready_timeout_ms(&self) -> u64 {
+        let floor = if platform::is_qemu() {
+            NVME_RESET_TIMEOUT_QEMU_MS
+        } else {
+            NVME_RESET_TIMEOUT_HARDWARE_MS
+        };
+        (self.capabilities.timeout as u64 * 500).max(floor)
+    }
 
-            timeout -= 1;
+    fn wait_nvme_reset(&self) {
+        // CSTS.RDY has to drop before the controller may be enabled again
+        if wait_for_bit(self, 0x1C, 0, false, self.ready_timeout_ms()).is_err() {
+            error!("NVMe reset timed out");
+            return;
         }
 
-        serial_println!("NVMe reset timed out");
+        self.nvme_write_reg32(0x14, 1);  // Power on the controller
     }
 
     fn enable(&self) {
-        timer_wait_ms(1);  // Wait for a moment
-
+        let ready = wait_for_bit(self, 0x1C, 0, true, self.ready_timeout_ms());
         let status = self.nvme_read_reg32(0x1C);
-        serial_println!("STATUS: {:?}", status);
+        debug!("STATUS: {:?}", status);
 
-        if status == 1 {
-            serial_println!("Successfully Reset NVMe");
+        if ready.is_ok() {
+            info!("Successfully Reset NVMe");
         } else {
-            serial_println!("NVMe enable failed with status: {:?}", status);
-        }
-    }
-
-    fn send_init_command(&self) {
-        let addr = self.nvme_virt_addr.as_u64();
-        let current_value = unsafe { core::ptr::read_volatile(addr as *const u16) };
-
-        // BIT 1, 2 and 10 should be enabled
-        let values = current_value | (1 << 1) | (1 << 2) | (1 << 10);
-
-        unsafe {
-            core::ptr::write_volatile(addr as *mut u16, values);
+            error!("NVMe enable failed with status: {:?}", status);
         }
     }
 
@@ -162,28 +460,40 @@ impl NvmeRegisters {
         self.configure_queues(asq_frame, acq_frame, mapper, frame_allocator);
 
         // Set queue sizes in the AQA register
-        let queue_size = (QUEUE_SIZE - 1) | ((QUEUE_SIZE - 1) << 16);
+        let entries = self.admin_queue_depth();
+        let queue_size = (entries - 1) | ((entries - 1) << 16);
         self.nvme_write_reg32(0x24, queue_size); // AQA register
+        debug!("NVMe admin queue depth: {} entries", entries);
 
         self.submission_queue_tail = 0;
         self.completion_queue_head = 0;
+        self.completion_phase = true; // The queue starts zeroed, so the first pass posts 1s
 
-        serial_println!("NVMe Admin Queue initialized");
+        info!("NVMe Admin Queue initialized");
     }
 
     fn configure_queues(&mut self, asq_frame: PhysFrame<Size4KiB>, acq_frame: PhysFrame<Size4KiB>, mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
-        let asq_virt_addr = 0xffff800000000000 + asq_frame.start_address().as_u64();
-        let acq_virt_addr = 0xffff800000000000 + acq_frame.start_address().as_u64();
+        let asq_virt_addr = MMIO_VIRT_BASE + asq_frame.start_address().as_u64();
+        let acq_virt_addr = MMIO_VIRT_BASE + acq_frame.start_address().as_u64();
 
-        serial_println!("ASQ ADDRESS: {:X}", asq_virt_addr);
-        serial_println!("ACQ ADDRESS: {:X}", acq_virt_addr);
+        // Sharing a frame would let completions overwrite submitted commands
+        assert_ne!(asq_frame, acq_frame, "ASQ and ACQ must occupy distinct frames");
+        debug!("ASQ: phys {:X}, virt {:X}", asq_frame.start_address().as_u64(), asq_virt_addr);
+        debug!("ACQ: phys {:X}, virt {:X}", acq_frame.start_address().as_u64(), acq_virt_addr);
 
         // ASQ en ACQ
         self.map_queue(mapper, asq_frame, asq_virt_addr, "ASQ", frame_allocator);
-        self.map_queue(mapper, asq_frame, acq_virt_addr, "ACQ", frame_allocator);
+        self.map_queue(mapper, acq_frame, acq_virt_addr, "ACQ", frame_allocator);
+        unsafe {
+            core::ptr::write_bytes(asq_virt_addr as *mut u8, 0, 4096);
+            core::ptr::write_bytes(acq_virt_addr as *mut u8, 0, 4096);
+        }
+        self.admin_sq_virt = asq_virt_addr;
+        self.admin_cq_virt = acq_virt_addr;
 
-        self.nvme_write_reg64(0x28, asq_virt_addr);  // ASQ
-        self.nvme_write_reg64(0x30, acq_virt_addr);  // ACQ
+        // The controller DMAs to these, so it needs the physical addresses
+        self.nvme_write_reg64(0x28, asq_frame.start_address().as_u64());  // ASQ
+        self.nvme_write_reg64(0x30, acq_frame.start_address().as_u64());  // ACQ
     }
 
     fn map_queue(&self, mapper: &mut OffsetPageTable, frame: PhysFrame<Size4KiB>, addr: u64, name: &str, frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
@@ -191,7 +501,7 @@ impl NvmeRegisters {
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
         // Debug output
-        serial_println!(
+        debug!(
             "Mapping {}: Virt Addr: {:X} -> Phys Addr: {:X}",
             name,
             addr,
@@ -200,7 +510,7 @@ impl NvmeRegisters {
 
         unsafe {
             if let Err(e) = mapper.map_to(page, frame, flags, frame_allocator) {
-                serial_println!("Failed to map {}: {:?}", name, e);
+                error!("Failed to map {}: {:?}", name, e);
                 panic!("Mapping error");
             }
         }
@@ -208,16 +518,26 @@ impl NvmeRegisters {
 
     fn allocate_frame(&self, frame_allocator: &mut impl FrameAllocator<Size4KiB>, name: &str) -> Result<PhysFrame<Size4KiB>, &'static str> {
         frame_allocator.allocate_frame().ok_or_else(|| {
-            serial_println!("Failed to allocate frame for {}", name);
+            error!("Failed to allocate frame for {}", name);
             "Allocation Error"
         })
     }
 
-    fn send_identify_command(&mut self, cns: u8, nsid: u32, mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(), &'static str> {
-        let identify_data = self.allocate_frame(frame_allocator, "Identify Data")
-            .expect("Failed to allocate Identify Data frame");
-
-        serial_println!("Identify Data Frame Start Address: {:X}", identify_data.start_address().as_u64());
+    /// Issues an Identify command and hands the 4 KiB result to `parse`, by virtual address.
+    ///
+    /// The data frame is only needed until it is parsed, so it is unmapped and freed again
+    /// afterwards, also when the command fails.
+    fn identify<T>(
+        &mut self,
+        cns: u8,
+        nsid: u32,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+        parse: impl FnOnce(u64) -> T,
+    ) -> Result<T, &'static str> {
+        let identify_data = self.allocate_frame(frame_allocator, "Identify Data")?;
+
+        debug!("Identify Data Frame Start Address: {:X}", identify_data.start_address().as_u64());
 
         let mut cmd = NvmeCommand {
             opcode: NVME_ADMIN_IDENTIFY,
@@ -234,37 +554,105 @@ impl NvmeRegisters {
 
         cmd.command_specific[0] = (cns as u32) & 0xFF;
 
-        if cns == 0 {
-            cmd.namespace_id = nsid; // Set the namespace ID
+        debug!("Submitting Identify command with CNS: {}", cns);
+
+        if let Err(e) = self.submit_admin_command(cmd) {
+            unsafe { frame_allocator.deallocate_frame(identify_data) };
+            return Err(e.into());
         }
 
-        serial_println!("Submitting Identify command with CNS: {}", cns);
+        let identify_virt_addr = self.map_identify_data(identify_data, mapper, frame_allocator);
+        let parsed = parse(identify_virt_addr);
 
-        self.submit_admin_command(cmd)?;
+        let page = Page::containing_address(VirtAddr::new(identify_virt_addr));
+        match unmap_page(page, mapper) {
+            Ok(frame) => unsafe { frame_allocator.deallocate_frame(frame) },
+            // Still mapped, so the frame cannot be handed out again
+            Err(e) => warn!("Failed to unmap Identify data: {:?}", e),
+        }
+
+        Ok(parsed)
+    }
 
+    fn send_identify_command(&mut self, cns: u8, nsid: u32, mapper: &mut OffsetPageTable, frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>)) -> Result<(), &'static str> {
         // Read the Identify Data structure
-        let identify_data_virt_addr = self.map_identify_data(identify_data, mapper, frame_allocator);
-        let identify_data = unsafe { core::ptr::read_volatile(identify_data_virt_addr as *const NvmeIdentifyController) };
+        let (identify_data_virt_addr, identify_data) = self.identify(cns, nsid, mapper, frame_allocator, |virt_addr| {
+            (virt_addr, unsafe { core::ptr::read_volatile(virt_addr as *const NvmeIdentifyController) })
+        })?;
 
         // Check for IO capabilities
         if identify_data.controller_multi_path_io_and_namespace_sharing_capabilities != 0 {
-            serial_println!("NVMe controller is an IO controller");
+            debug!("NVMe controller is an IO controller");
         } else {
-            serial_println!("NVMe controller with address {:X?} is not an IO controller: {:?}", identify_data_virt_addr, identify_data);
+            warn!("NVMe controller with address {:X?} is not an IO controller: {:?}", identify_data_virt_addr, identify_data);
         }
 
         Ok(())
     }
 
+    /// Fetches the active namespace list (CNS 2) and returns its first, lowest, nsid together
+    /// with the number of active namespaces. The list is walked in place, since only the first
+    /// entry is kept.
+    fn first_active_namespace(&mut self, mapper: &mut OffsetPageTable, frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>)) -> Result<(Option<u32>, u32), &'static str> {
+        // nsid 0 asks for every active namespace; the list is zero-terminated
+        let (first, count) = self.identify(NVME_IDENTIFY_CNS_ACTIVE_NAMESPACES, 0, mapper, frame_allocator, |list_virt_addr| {
+            let list = unsafe { core::slice::from_raw_parts(list_virt_addr as *const u32, ACTIVE_NAMESPACE_LIST_ENTRIES) };
+
+            let mut active = list.iter()
+                .map(|nsid| unsafe { core::ptr::read_volatile(nsid) })
+                .take_while(|&nsid| nsid != 0);
+            let first = active.next();
+            (first, first.map_or(0, |_| 1 + active.count() as u32))
+        })?;
+        info!("NVMe active namespaces: {}", count);
+
+        Ok((first, count))
+    }
+
+    fn select_default_namespace(&mut self, mapper: &mut OffsetPageTable, frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>)) {
+        (self.default_namespace, self.namespace_count) = self.first_active_namespace(mapper, frame_allocator)
+            .unwrap_or_else(|e| {
+                error!("Failed to read NVMe active namespace list: {}", e);
+                (None, 0)
+            });
+
+        match self.default_namespace {
+            Some(nsid) => {
+                info!("NVMe default namespace: {}", nsid);
+                self.namespace = self.identify_namespace(nsid, mapper, frame_allocator)
+                    .unwrap_or_else(|e| {
+                        error!("Failed to identify NVMe namespace {}: {}", nsid, e);
+                        None
+                    });
+            }
+            None => {
+                warn!("NVMe controller has no active namespaces");
+            }
+        }
+    }
+
+    /// Issues Identify Namespace (CNS 0) for `nsid` and parses its size and block size.
+    fn identify_namespace(&mut self, nsid: u32, mapper: &mut OffsetPageTable, frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>)) -> Result<Option<NamespaceInfo>, &'static str> {
+        let info = self.identify(NVME_IDENTIFY_CNS_NAMESPACE, nsid, mapper, frame_allocator, |data_virt_addr| {
+            let data = unsafe { core::slice::from_raw_parts(data_virt_addr as *const u8, 4096) };
+            NamespaceInfo::parse(nsid, data)
+        })?;
+        if let Some(info) = info {
+            info!("NVMe namespace {}: {} blocks of {} bytes, {} allocatable",
+                            nsid, info.size_blocks, info.block_size, info.capacity_blocks);
+        }
+        Ok(info)
+    }
+
     fn is_controller_ready(&self) -> bool {
         let csts = self.nvme_read_reg32(0x1c);  // CSTS register
         (csts & 0x1) == 1  // Check RDY bit
     }
 
     fn map_identify_data(&self, identify_frame: PhysFrame<Size4KiB>, mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> u64 {
-        let identify_virt_addr = 0xffff800000000000 + identify_frame.start_address().as_u64();
+        let identify_virt_addr = MMIO_VIRT_BASE + identify_frame.start_address().as_u64();
 
-        serial_println!(
+        debug!(
             "Mapped Identify Frame: Physical Address: {:X}, Virtual Address: {:X}",
             identify_frame.start_address().as_u64(),
             identify_virt_addr
@@ -273,79 +661,249 @@ impl NvmeRegisters {
         identify_virt_addr
     }
 
-    fn submit_admin_command(&mut self, cmd: NvmeCommand) -> Result<(), &'static str> {
+    fn submit_admin_command(&mut self, cmd: NvmeCommand) -> Result<(), NvmeError> {
         // Submit the command to the Admin Submission Queue
-        let asq_base_addr = self.nvme_read_reg64(0x28);
-        let asq_addr = (asq_base_addr + (self.submission_queue_tail * core::mem::size_of::<NvmeCommand>() as u64)) as *mut NvmeCommand;
+        let asq_addr = (self.admin_sq_virt + (self.submission_queue_tail * core::mem::size_of::<NvmeCommand>() as u64)) as *mut NvmeCommand;
 
-        serial_println!("Submission Queue Address: {:?}", asq_addr);
+        debug!("Submission Queue Address: {:?}", asq_addr);
 
         unsafe {
             // Write the command to the submission queue
             core::ptr::write_volatile(asq_addr, cmd);
         }
 
-        serial_println!("COMMAND: {:?}", cmd);
-        serial_println!("PRP1 Physical Address: 0x{:X}", cmd.prp1);
+        debug!("COMMAND: {:?}", cmd);
+        debug!("PRP1 Physical Address: 0x{:X}", cmd.prp1);
 
         // Increment the Submission Queue Tail
         let old_tail = self.submission_queue_tail;
-        self.submission_queue_tail = (self.submission_queue_tail + 1) % QUEUE_SIZE as u64;
-
-        // Calculate the offset for the Submission Queue Tail Doorbell
-        let sq_tail_doorbell_offset = 0x1000 + 2 * (4 << self.doorbell_stride);
+        self.submission_queue_tail = (self.submission_queue_tail + 1) % self.admin_queue_depth() as u64;
 
         // Debug: Print values before writing
-        serial_println!("Old Tail: {}, New Tail: {}", old_tail, self.submission_queue_tail);
+        debug!("Old Tail: {}, New Tail: {}", old_tail, self.submission_queue_tail);
 
         // Write to the Submission Queue Tail Doorbell Register
-        self.nvme_write_reg32_no_address(asq_base_addr, sq_tail_doorbell_offset as u32, self.submission_queue_tail as u32);
+        self.nvme_write_reg32(self.submission_doorbell(0), self.submission_queue_tail as u32);
 
-        serial_println!("Command submitted successfully");
+        debug!("Command submitted successfully");
 
         // Wait for completion
         self.wait_for_completion()
     }
 
-    fn wait_for_completion(&mut self) -> Result<(), &'static str> {
-        let acq_addr = self.nvme_read_reg64(0x30) as *const NvmeCompletion;
+    fn wait_for_completion(&mut self) -> Result<(), NvmeError> {
+        let completions = MappedCompletionQueue(self.admin_cq_virt);
+        let head = self.completion_queue_head as u32;
+        let completion = poll_completion(&completions, head, self.completion_phase, self.ready_timeout_ms())
+            .map_err(|_| {
+                error!("NVMe admin command timed out");
+                NvmeError::Timeout
+            })?;
+
+        debug!("Completion: {:?}", completion);
+
+        // Consume the entry; the expected phase flips every time the queue wraps
+        self.completion_queue_head += 1;
+        if self.completion_queue_head == self.admin_queue_depth() as u64 {
+            self.completion_queue_head = 0;
+            self.completion_phase = !self.completion_phase;
+        }
+        self.nvme_write_reg32(self.completion_doorbell(0), self.completion_queue_head as u32);
 
-        loop {
-            // Read the completion entry
-            let completion = unsafe { core::ptr::read_volatile(acq_addr.add(self.completion_queue_head as usize)) };
+        let status = NvmeStatus::from_status(completion.status);
+        if status != NvmeStatus::Success {
+            error!("NVMe error: {}", status);
+            return Err(NvmeError::CommandFailed(status));
+        }
 
-            serial_println!("Completion: {:?}", completion);
+        Ok(())
+    }
 
-            // Check if the completion is valid
-            if (completion.phase_tag & 1) == (self.completion_queue_head & 1) as u16 {
-                // Process the completion
-                self.completion_queue_head = (self.completion_queue_head + 1) % QUEUE_SIZE as u64;
-                self.nvme_write_reg32_no_address(self.nvme_read_reg64(0x28),0x1000 + 3 * (4 << self.doorbell_stride as u64), self.completion_queue_head as u32);
+    /// Offset of the submission queue tail doorbell of queue `queue_id`, from the register base.
+    fn submission_doorbell(&self, queue_id: u16) -> u32 {
+        0x1000 + (2 * queue_id as u32) * (4 << self.capabilities.doorbell_stride)
+    }
 
-                // Check status of the command
-                if self.completion_queue_head == QUEUE_SIZE as u64 {
-                    self.completion_queue_head = 0;
-                }
+    /// Offset of the completion queue head doorbell of queue `queue_id`, from the register base.
+    fn completion_doorbell(&self, queue_id: u16) -> u32 {
+        0x1000 + (2 * queue_id as u32 + 1) * (4 << self.capabilities.doorbell_stride)
+    }
 
-                let status = completion.status;
-                serial_println!("Completion Status: 0x{:X}", status);
-                if (status >> 1) != 0 {
-                    let status_code_type = (status >> 9) & 0x7;
-                    let status_code = (status >> 1) & 0xFF;
-                    serial_println!("Command failed. Status Code Type: {}, Status Code: {}", status_code_type, status_code);
-                    return Err("Command failed");
-                }
+    /// Creates the I/O queue pair and the DMA bounce buffer used by `read_blocks`.
+    fn init_io_queues(&mut self, mapper: &mut OffsetPageTable, frame_allocator: &mut (impl FrameAllocator<Size4KiB> + ContiguousFrames)) -> Result<(), &'static str> {
+        let depth = self.clamp_queue_depth(IO_QUEUE_DEPTH);
+        let sq_frame = self.allocate_frame(frame_allocator, "I/O SQ")?;
+        let cq_frame = self.allocate_frame(frame_allocator, "I/O CQ")?;
+        self.map_queue(mapper, sq_frame, MMIO_VIRT_BASE + sq_frame.start_address().as_u64(), "I/O SQ", frame_allocator);
+        self.map_queue(mapper, cq_frame, MMIO_VIRT_BASE + cq_frame.start_address().as_u64(), "I/O CQ", frame_allocator);
 
-                // Process the Identify data here (if applicable)
+        // The controller only ever sees fresh, zeroed queues
+        unsafe {
+            core::ptr::write_bytes((MMIO_VIRT_BASE + sq_frame.start_address().as_u64()) as *mut u8, 0, 4096);
+            core::ptr::write_bytes((MMIO_VIRT_BASE + cq_frame.start_address().as_u64()) as *mut u8, 0, 4096);
+        }
 
-                return Ok(());
+        self.dma_buffer = frame_allocator.allocate_contiguous(DMA_BUFFER_FRAMES)
+            .ok_or_else(|| {
+                error!("Failed to allocate {} contiguous frames for the DMA buffer", DMA_BUFFER_FRAMES);
+                "Allocation Error"
+            })?
+            .start_address().as_u64();
+        for i in 0..DMA_BUFFER_FRAMES as u64 {
+            let frame = PhysFrame::containing_address(PhysAddr::new(self.dma_buffer + i * 4096));
+            self.map_queue(mapper, frame, MMIO_VIRT_BASE + frame.start_address().as_u64(), "DMA buffer", frame_allocator);
+        }
+        let prp_list = self.allocate_frame(frame_allocator, "PRP list")?;
+        self.map_queue(mapper, prp_list, MMIO_VIRT_BASE + prp_list.start_address().as_u64(), "PRP list", frame_allocator);
+        self.prp_list = Some(prp_list);
+
+        // The completion queue has to exist before a submission queue can post to it
+        let queue_size = (depth - 1) << 16 | IO_QUEUE_ID as u32;
+        let mut create_cq = new_command(NVME_ADMIN_CREATE_IO_CQ, cq_frame.start_address().as_u64());
+        create_cq.command_specific[0] = queue_size;
+        create_cq.command_specific[1] = 1; // Physically contiguous, interrupts off
+        self.submit_admin_command(create_cq)?;
+
+        let mut create_sq = new_command(NVME_ADMIN_CREATE_IO_SQ, sq_frame.start_address().as_u64());
+        create_sq.command_specific[0] = queue_size;
+        create_sq.command_specific[1] = (IO_QUEUE_ID as u32) << 16 | 1; // Completion queue, contiguous
+        self.submit_admin_command(create_sq)?;
+
+        self.io_queue = Some(QueuePair {
+            id: IO_QUEUE_ID,
+            depth,
+            sq_phys: sq_frame.start_address().as_u64(),
+            cq_phys: cq_frame.start_address().as_u64(),
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            next_command_id: 0,
+        });
+        info!("NVMe I/O queue {} created with {} entries", IO_QUEUE_ID, depth);
+
+        Ok(())
+    }
+
+    /// Submits `cmd` on the I/O queue and polls for its completion.
+    ///
+    /// Completions are detected through the timer tick counter, so interrupts must be enabled.
+    fn submit_io_command(&mut self, mut cmd: NvmeCommand) -> Result<(), NvmeError> {
+        let timeout_ms = self.ready_timeout_ms();
+        let mut queue = self.io_queue.take().ok_or(NvmeError::NotInitialized)?;
+        let result = self.submit_on(&mut queue, &mut cmd, timeout_ms);
+        self.io_queue = Some(queue);
+        result
+    }
+
+    fn submit_on(&self, queue: &mut QueuePair, cmd: &mut NvmeCommand, timeout_ms: u64) -> Result<(), NvmeError> {
+        cmd.command_id = queue.next_command_id;
+        queue.next_command_id = queue.next_command_id.wrapping_add(1);
+
+        let slot = MMIO_VIRT_BASE + queue.sq_phys + queue.sq_tail as u64 * SUBMISSION_ENTRY_SIZE as u64;
+        unsafe { core::ptr::write_volatile(slot as *mut NvmeCommand, *cmd) };
+        queue.sq_tail = (queue.sq_tail + 1) % queue.depth;
+        self.nvme_write_reg32(self.submission_doorbell(queue.id), queue.sq_tail);
+
+        let completions = MappedCompletionQueue(MMIO_VIRT_BASE + queue.cq_phys);
+        let completion = poll_completion(&completions, queue.cq_head, queue.phase, timeout_ms)
+            .map_err(|_| NvmeError::Timeout)?;
+
+        queue.cq_head += 1;
+        if queue.cq_head == queue.depth {
+            queue.cq_head = 0;
+            queue.phase = !queue.phase;
+        }
+        self.nvme_write_reg32(self.completion_doorbell(queue.id), queue.cq_head);
+
+        match NvmeStatus::from_status(completion.status) {
+            NvmeStatus::Success => Ok(()),
+            status => {
+                error!("NVMe error: {}", status);
+                Err(NvmeError::CommandFailed(status))
             }
+        }
+    }
 
-            // Add a small delay or yield here to prevent tight looping
-            timer_wait_ms(50);
+    /// Logical block size of `nsid` in bytes. Only the default namespace is identified.
+    fn block_size(&self, nsid: u32) -> usize {
+        match self.namespace {
+            Some(info) if info.nsid == nsid => info.block_size,
+            _ => DEFAULT_BLOCK_SIZE,
         }
     }
 
+    fn read_blocks(&mut self, nsid: u32, lba: u64, count: u16, buffer: &mut [u8]) -> Result<(), NvmeError> {
+        self.transfer(nsid, lba, count, Transfer::Read(buffer))
+    }
+
+    fn write_blocks(&mut self, nsid: u32, lba: u64, count: u16, buffer: &[u8]) -> Result<(), NvmeError> {
+        self.transfer(nsid, lba, count, Transfer::Write(buffer))
+    }
+
+    /// Commits everything written to `nsid` so far to non-volatile media.
+    fn flush(&mut self, nsid: u32) -> Result<(), NvmeError> {
+        if nsid == 0 {
+            return Err(NvmeError::NoNamespace);
+        }
+        let mut cmd = new_command(NVME_CMD_FLUSH, 0);
+        cmd.namespace_id = nsid;
+        self.submit_io_command(cmd)
+    }
+
+    /// Moves `count` blocks starting at `lba` between `data` and namespace `nsid`.
+    fn transfer(&mut self, nsid: u32, lba: u64, count: u16, mut data: Transfer) -> Result<(), NvmeError> {
+        if nsid == 0 {
+            return Err(NvmeError::NoNamespace);
+        }
+        let block_size = self.block_size(nsid);
+        let length = count as usize * block_size;
+        if data.len() < length {
+            return Err(NvmeError::BufferTooSmall);
+        }
+        // Only the default namespace has a known size; the controller checks the others
+        if let Some(info) = self.namespace.filter(|info| info.nsid == nsid) {
+            if lba.checked_add(count as u64).map_or(true, |end| end > info.size_blocks) {
+                return Err(NvmeError::OutOfRange);
+            }
+        }
+
+        // Transfer through the bounce buffer, as many whole blocks at a time as fit in it
+        let blocks_per_chunk = (DMA_BUFFER_SIZE / block_size) as u64;
+        let mut done = 0u64;
+        while done < count as u64 {
+            let blocks = blocks_per_chunk.min(count as u64 - done);
+            let bytes = blocks as usize * block_size;
+
+            // Set up together with the bounce buffer, so this also says the buffer exists
+            let prp_list = self.prp_list.ok_or(NvmeError::NotInitialized)?;
+
+            let offset = done as usize * block_size;
+            let bounce = (MMIO_VIRT_BASE + self.dma_buffer) as *mut u8;
+            if let Transfer::Write(source) = &data {
+                unsafe { core::ptr::copy_nonoverlapping(source[offset..].as_ptr(), bounce, bytes) };
+            }
+
+            // The reserved frame is always there, so the only way this fails is a chunk too big
+            let (prp1, prp2) = build_prp_list(self.dma_buffer, bytes, &mut ReservedFrame(prp_list))
+                .map_err(|_| NvmeError::TransferTooLarge)?;
+            let mut cmd = new_command(data.opcode(), prp1);
+            cmd.namespace_id = nsid;
+            cmd.prp2 = prp2;
+            cmd.command_specific[0] = (lba + done) as u32;
+            cmd.command_specific[1] = ((lba + done) >> 32) as u32;
+            cmd.command_specific[2] = (blocks - 1) as u32; // Zero-based block count
+            self.submit_io_command(cmd)?;
+
+            if let Transfer::Read(destination) = &mut data {
+                unsafe { core::ptr::copy_nonoverlapping(bounce, destination[offset..].as_mut_ptr(), bytes) };
+            }
+
+            done += blocks;
+        }
+
+        Ok(())
+    }
+
     // Read & Write NVMe registers
     fn nvme_read_reg32(&self, offset: u32) -> u32 {
         unsafe {
@@ -368,72 +926,407 @@ impl NvmeRegisters {
         }
     }
 
-    fn nvme_write_reg32_no_address(&self, addr: u64, offset: u32, value: u32) {
+    fn nvme_write_reg64(&self, offset: u32, value: u64) {
         unsafe {
-            let reg_addr = (addr + offset as u64) as *mut u32;
+            let reg_addr = (self.nvme_virt_addr.as_u64() + offset as u64) as *mut u64;
             core::ptr::write_volatile(reg_addr, value);
         }
     }
+}
 
-    fn nvme_write_reg64(&self, offset: u32, value: u64) {
-        unsafe {
-            let reg_addr = (self.nvme_virt_addr.as_u64() + offset as u64) as *mut u64;
-            core::ptr::write_volatile(reg_addr, value);
+/// Caller buffer of a block transfer; the direction picks the opcode and which way the bounce
+/// buffer is copied.
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl Transfer<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Transfer::Read(buffer) => buffer.len(),
+            Transfer::Write(buffer) => buffer.len(),
+        }
+    }
+
+    fn opcode(&self) -> u8 {
+        match self {
+            Transfer::Read(_) => NVME_CMD_READ,
+            Transfer::Write(_) => NVME_CMD_WRITE,
+        }
+    }
+}
+
+/// Physical addresses of every page after the first that `len` bytes at `buffer_phys` touch.
+fn prp_pages(buffer_phys: u64, len: usize) -> core::iter::StepBy<core::ops::Range<u64>> {
+    let second_page = (buffer_phys & !0xFFF) + 4096;
+    (second_page..buffer_phys + len as u64).step_by(4096)
+}
+
+/// Describes `len` bytes of physically contiguous memory at `buffer_phys` as PRP1 and PRP2.
+///
+/// PRP1 always points at the data. A transfer that fits in one page needs nothing else, one
+/// that touches exactly two pages puts the second page in PRP2, and anything longer gets a PRP
+/// list: a page from `frame_allocator`, filled with the remaining page addresses, that PRP2
+/// points at. The list page must be mapped at `MMIO_VIRT_BASE + phys`, and since lists are not
+/// chained, a transfer may touch at most `PRP_LIST_ENTRIES + 1` pages.
+fn build_prp_list(buffer_phys: u64, len: usize, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(u64, u64), &'static str> {
+    let pages = prp_pages(buffer_phys, len);
+    let prp2 = match pages.clone().count() {
+        0 => 0,
+        1 => pages.clone().next().unwrap_or(0),
+        count if count > PRP_LIST_ENTRIES => return Err("transfer too large for one PRP list"),
+        _ => {
+            let list = frame_allocator.allocate_frame().ok_or("no frame for the PRP list")?.start_address().as_u64();
+            let entries = (MMIO_VIRT_BASE + list) as *mut u64;
+            for (i, page) in pages.enumerate() {
+                unsafe { core::ptr::write_volatile(entries.add(i), page) };
+            }
+            list
         }
+    };
+
+    Ok((buffer_phys, prp2))
+}
+
+/// Hands out one frame that was reserved and mapped up front, for code that runs after the
+/// frame allocator has been given back to `kernel_main`.
+struct ReservedFrame(PhysFrame<Size4KiB>);
+
+unsafe impl FrameAllocator<Size4KiB> for ReservedFrame {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        Some(self.0)
     }
 }
 
-static mut CONTROLLER: Option<NvmeRegisters> = None;
+/// A command with every field zeroed except the opcode and PRP1.
+fn new_command(opcode: u8, prp1: u64) -> NvmeCommand {
+    NvmeCommand {
+        opcode,
+        flags: 0,
+        command_id: 0,
+        namespace_id: 0,
+        reserved1: 0,
+        reserved2: 0,
+        metadata_ptr: 0,
+        prp1,
+        prp2: 0,
+        command_specific: [0; 6],
+    }
+}
 
-pub fn init_controller(mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
+impl RegisterBlock for NvmeRegisters {
+    fn read_reg32(&self, offset: u32) -> u32 {
+        self.nvme_read_reg32(offset)
+    }
+}
+
+static CONTROLLER: Mutex<Option<NvmeRegisters>> = Mutex::new(None);
+
+pub fn init_controller(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ContiguousFrames),
+) {
     let nvme_base_addr = find_first_nvme();
+    let mut controller = NvmeRegisters::new(nvme_base_addr);
+
+    // The registers only decode with memory space enabled, and queues need DMA
+    if let Some(pci_device) = find_nvme_device() {
+        let (bus, device, function) = (pci_device.bus, pci_device.device, pci_device.function);
+        // Sized before decoding is enabled; bar_size briefly rewrites BAR0
+        controller.register_size = bar_size(bus, device, function, 0);
+        debug!("NVMe register window: {:#x} bytes", controller.register_size);
+        if !enable_memory_space(bus, device, function) {
+            warn!("NVMe: memory space enable did not stick");
+        }
+        if !enable_bus_mastering(bus, device, function) {
+            warn!("NVMe: bus mastering enable did not stick");
+        }
+        match find_capability(bus, device, function, PCI_CAP_ID_MSIX) {
+            Some(offset) => { debug!("NVMe: MSI-X capability at {:#x}", offset); }
+            None => { debug!("NVMe: no MSI-X capability"); }
+        }
+    }
 
-    unsafe {
-        CONTROLLER = Some(NvmeRegisters::new(nvme_base_addr));
+    map_nvme_base(controller.nvme_base_addr, controller.nvme_virt_addr, controller.register_size, mapper, frame_allocator);
 
-        if let Some(controller) = CONTROLLER.as_mut() {
-            map_nvme_base(controller.nvme_base_addr, controller.nvme_virt_addr, mapper, frame_allocator);
+    // Initialize NVMe controller. This waits on timer ticks, so it must run with interrupts
+    // enabled and outside the CONTROLLER lock.
+    controller.reset();
+    controller.init_admin_queues(mapper, frame_allocator);
+    controller.enable();
 
-            // Enable Interrupts, bus-mastering and memory space access
-            controller.send_init_command();
+    if controller.is_controller_ready() {
+        controller.send_identify_command(NVME_IDENTIFY_CNS as u8, 0, mapper, frame_allocator)
+            .expect("Failed to send Identify Controller command");
+        controller.select_default_namespace(mapper, frame_allocator);
+        if let Err(e) = controller.init_io_queues(mapper, frame_allocator) {
+            error!("Failed to create NVMe I/O queues: {}", e);
+        }
+    }
 
-            // Initialize NVMe controller
-            controller.reset();
-            controller.init_admin_queues(mapper, frame_allocator);
-            controller.enable();
+    with_locked_irqsafe(&CONTROLLER, |slot| *slot = Some(controller));
+}
 
-            if controller.is_controller_ready() {
-                controller.send_identify_command(NVME_IDENTIFY_CNS as u8, 0, mapper, frame_allocator)
-                    .expect("Failed to send Identify Controller command");
-            }
+/// Spec version of the initialized controller, if any.
+pub fn version() -> Option<NvmeVersion> {
+    with_locked_irqsafe(&CONTROLLER, |slot| slot.as_ref().map(|controller| controller.version))
+}
 
+/// The namespace disk commands target when no nsid is given.
+pub fn default_namespace() -> Option<u32> {
+    with_locked_irqsafe(&CONTROLLER, |slot| slot.as_ref().and_then(|controller| controller.default_namespace))
+}
 
-        } else {
-            panic!("Failed to initialize NVMe controller");
+/// Number of active namespaces on the controller, if one was initialized.
+pub fn namespace_count() -> Option<u32> {
+    with_locked_irqsafe(&CONTROLLER, |slot| slot.as_ref().map(|controller| controller.namespace_count))
+}
+
+/// Size and block size of the default namespace, if it could be identified.
+pub fn namespace_info() -> Option<NamespaceInfo> {
+    with_locked_irqsafe(&CONTROLLER, |slot| slot.as_ref().and_then(|controller| controller.namespace))
+}
+
+/// Resolves the nsid for a disk command: an explicit `nsid` wins, otherwise the default is used.
+pub fn resolve_namespace(nsid: Option<u32>) -> Result<u32, &'static str> {
+    match nsid {
+        Some(0) => Err("Namespace ID 0 is not a valid namespace"),
+        Some(nsid) => Ok(nsid),
+        None => default_namespace().ok_or("No active NVMe namespace"),
+    }
+}
+
+/// Reads `count` logical blocks starting at `lba` from namespace `nsid` into `buffer`.
+///
+/// This polls for completion on the timer, so it must not be called with interrupts disabled
+/// (for example from inside the shell's writer lock). No interrupt handler takes the
+/// controller lock, so holding it with interrupts enabled is safe.
+pub fn read_blocks(nsid: u32, lba: u64, count: u16, buffer: &mut [u8]) -> Result<(), NvmeError> {
+    match CONTROLLER.lock().as_mut() {
+        Some(controller) => controller.read_blocks(nsid, lba, count, buffer),
+        None => Err(NvmeError::NotInitialized),
+    }
+}
+
+/// Writes `count` logical blocks from `buffer` to namespace `nsid`, starting at `lba`.
+///
+/// Same locking rules as `read_blocks`. The data may sit in the controller's volatile cache
+/// until `flush` is called.
+pub fn write_blocks(nsid: u32, lba: u64, count: u16, buffer: &[u8]) -> Result<(), NvmeError> {
+    match CONTROLLER.lock().as_mut() {
+        Some(controller) => controller.write_blocks(nsid, lba, count, buffer),
+        None => Err(NvmeError::NotInitialized),
+    }
+}
+
+/// Makes earlier writes to `nsid` durable. Same locking rules as `read_blocks`.
+pub fn flush(nsid: u32) -> Result<(), NvmeError> {
+    match CONTROLLER.lock().as_mut() {
+        Some(controller) => controller.flush(nsid),
+        None => Err(NvmeError::NotInitialized),
+    }
+}
+
+/// An identified NVMe namespace as a `BlockDevice`. Requests go through the global
+/// controller, so the locking rules of `read_blocks` apply to every method.
+pub struct NvmeNamespace {
+    info: NamespaceInfo,
+}
+
+impl NvmeNamespace {
+    /// The default namespace, if the controller came up and could identify it.
+    pub fn open_default() -> Option<NvmeNamespace> {
+        namespace_info().map(|info| NvmeNamespace { info })
+    }
+
+    pub fn nsid(&self) -> u32 {
+        self.info.nsid
+    }
+}
+
+impl From<NvmeError> for BlockError {
+    fn from(error: NvmeError) -> Self {
+        match error {
+            NvmeError::OutOfRange => BlockError::OutOfRange,
+            NvmeError::BufferTooSmall => BlockError::BufferTooSmall,
+            error => BlockError::Device(error.into()),
         }
     }
 }
 
+impl BlockDevice for NvmeNamespace {
+    fn block_size(&self) -> usize {
+        self.info.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.info.size_blocks
+    }
+
+    fn read_block(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        Ok(read_blocks(self.info.nsid, lba, 1, buf)?)
+    }
+
+    fn write_block(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        Ok(write_blocks(self.info.nsid, lba, 1, buf)?)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(flush(self.info.nsid)?)
+    }
+}
+
+/// Logical block size of `nsid`, for sizing `read_blocks` buffers.
+pub fn block_size(nsid: u32) -> Option<usize> {
+    CONTROLLER.lock().as_ref().map(|controller| controller.block_size(nsid))
+}
+
 pub fn find_first_nvme() -> u64 {
-    for bus in 0..=255 {
-        for device in 0..31 {
-            for function in 0..7 {
-                if let Some(pci_device) = get_pci_device(bus, device, function) {
-                    if pci_device.class_code == 0x01 && pci_device.subclass_code == 0x08 {
-                        return get_nvme_base_addr(bus, device, function);
-                    }
-                }
-            }
-        }
+    match find_nvme_device() {
+        Some(pci_device) => get_nvme_base_addr(pci_device.bus, pci_device.device, pci_device.function),
+        None => 0,
     }
+}
 
-    0
+// Walks the bus without allocating, since only the first match is needed
+fn find_nvme_device() -> Option<PciDevice> {
+    let mut found = None;
+    for_each_device(|pci_device| {
+        if found.is_none() && pci_device.class_code == 0x01 && pci_device.subclass_code == 0x08 {
+            found = Some(*pci_device);
+        }
+    });
+    found
 }
 
 fn get_nvme_base_addr(bus: u8, device: u8, function: u8) -> u64 {
     let bar0 = read_pci_bar(bus, device, function, 0); // BAR0
-    let bar1 = read_pci_bar(bus, device, function, 1); // BAR1
+    let base = bar0 as u64 & 0xFFFFFFF0;
+
+    // Bits 2:1 of a memory BAR encode its type; 0b10 means the upper half lives in BAR1
+    if (bar0 >> 1) & 0b11 == 0b10 {
+        let bar1 = read_pci_bar(bus, device, function, 1); // BAR1
+        ((bar1 as u64) << 32) | base
+    } else {
+        base
+    }
+}
+
+#[test_case]
+fn test_decode_cap() {
+    // MQES 0x7FF, CQR, TO 15, NVM command set, MPSMAX 4
+    let capabilities = decode_cap(0x0040_0020_0F01_07FF);
+
+    assert_eq!(capabilities.max_queue_entries, 0x7FF);
+    assert!(capabilities.contiguous_queues_required);
+    assert_eq!(capabilities.arbitration_mechanisms, 0);
+    assert_eq!(capabilities.timeout, 15);
+    assert_eq!(capabilities.doorbell_stride, 0);
+    assert!(!capabilities.subsystem_reset);
+    assert_eq!(capabilities.command_sets, 1);
+    assert_eq!(capabilities.memory_page_size_min, 0);
+    assert_eq!(capabilities.memory_page_size_max, 4);
+}
+
+#[test_case]
+fn test_decode_version() {
+    let version = NvmeVersion::from_register(0x0001_0400);
+    assert_eq!(version, NvmeVersion { major: 1, minor: 4, tertiary: 0 });
+
+    // Lib tests run without a heap, so format into a stack buffer
+    struct StackBuffer([u8; 16], usize);
+    impl fmt::Write for StackBuffer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.1 + s.len();
+            self.0.get_mut(self.1..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.1 = end;
+            Ok(())
+        }
+    }
+    let mut buffer = StackBuffer([0; 16], 0);
+    fmt::Write::write_fmt(&mut buffer, format_args!("{}", version)).unwrap();
+    assert_eq!(&buffer.0[..buffer.1], b"1.4.0");
+    assert!(NvmeVersion::from_register(0x0001_0100) >= NvmeVersion { major: 1, minor: 1, tertiary: 0 });
+}
 
-    // Combine BAR0 and BAR1 into a 64-bit MMIO address
-    ((bar1 as u64) << 32) | (bar0 as u64 & 0xFFFFFFF0)
-}
\ No newline at end of file
+#[test_case]
+fn test_queue_entry_sizes() {
+    assert_eq!(core::mem::size_of::<NvmeCommand>(), SUBMISSION_ENTRY_SIZE as usize);
+    assert_eq!(core::mem::size_of::<NvmeCompletion>(), COMPLETION_ENTRY_SIZE as usize);
+}
+
+#[test_case]
+fn test_decode_status() {
+    // Phase tag set, everything else clear
+    assert_eq!(NvmeStatus::from_status(0x0001), NvmeStatus::Success);
+    assert_eq!(NvmeStatus::from_status(0x02 << 1), NvmeStatus::InvalidField);
+    assert_eq!(NvmeStatus::from_status(0x80 << 1 | 1), NvmeStatus::LbaOutOfRange);
+    assert_eq!(NvmeStatus::from_status(1 << 9 | 0x01 << 1), NvmeStatus::CommandSpecific(0x01));
+    assert_eq!(NvmeStatus::from_status(7 << 9 | 0x05 << 1), NvmeStatus::Other { sct: 7, sc: 0x05 });
+    assert_eq!(NvmeStatus::InvalidField.name(), "Invalid Field in Command");
+}
+
+/// A completion queue whose controller never posts anything.
+#[cfg(test)]
+struct StuckCompletionQueue;
+
+#[cfg(test)]
+impl CompletionSource for StuckCompletionQueue {
+    fn entry(&self, _index: u32) -> NvmeCompletion {
+        NvmeCompletion::default()
+    }
+}
+
+#[test_case]
+fn test_never_completing_command_times_out() {
+    assert_eq!(poll_completion(&StuckCompletionQueue, 0, true, 0).err(), Some(Timeout));
+    assert_eq!(poll_completion(&StuckCompletionQueue, 0, true, 20).err(), Some(Timeout));
+}
+
+#[test_case]
+fn test_completion_phase_after_wrap() {
+    // After the first wrap the controller posts phase 0, which a zeroed entry also matches
+    assert!(poll_completion(&StuckCompletionQueue, 0, false, 0).is_ok());
+}
+
+#[test_case]
+fn test_prp_pages() {
+    // One page, exactly or unaligned but within it
+    assert_eq!(prp_pages(0x10000, 4096).count(), 0);
+    assert_eq!(prp_pages(0x10200, 512).count(), 0);
+    // Exactly two pages, and an unaligned transfer that spills into a second page
+    assert_eq!(prp_pages(0x10000, 8192).next(), Some(0x11000));
+    assert_eq!(prp_pages(0x10200, 4096).count(), 1);
+    // Three pages need a list
+    let mut pages = prp_pages(0x10000, 3 * 4096);
+    assert_eq!(pages.next(), Some(0x11000));
+    assert_eq!(pages.next(), Some(0x12000));
+    assert_eq!(pages.next(), None);
+}
+
+#[test_case]
+fn test_parse_identify_namespace() {
+    let mut data = [0u8; IDENTIFY_NAMESPACE_MIN_LEN];
+    data[0..8].copy_from_slice(&0x20_0000u64.to_le_bytes()); // NSZE
+    data[8..16].copy_from_slice(&0x1F_0000u64.to_le_bytes()); // NCAP
+    data[25] = 1; // Two LBA formats
+    data[IDENTIFY_NAMESPACE_LBAF + 2] = 9; // Format 0: 512 bytes
+    data[IDENTIFY_NAMESPACE_LBAF + 4 + 2] = 12; // Format 1: 4096 bytes
+
+    data[26] = 1;
+    let info = NamespaceInfo::parse(1, &data).expect("identify data did not parse");
+    assert_eq!(info.size_blocks, 0x20_0000);
+    assert_eq!(info.capacity_blocks, 0x1F_0000);
+    assert_eq!(info.block_size, 4096);
+    assert_eq!(info.capacity_bytes(), 0x1F_0000 * 4096);
+
+    data[26] = 0;
+    assert_eq!(NamespaceInfo::parse(1, &data).unwrap().block_size, 512);
+
+    // FLBAS pointing past NLBAF is ignored
+    data[26] = 5;
+    assert_eq!(NamespaceInfo::parse(1, &data).unwrap().block_size, DEFAULT_BLOCK_SIZE);
+
+    assert_eq!(NamespaceInfo::parse(1, &data[..64]), None);
+}
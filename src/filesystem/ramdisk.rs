@@ -0,0 +1,64 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::filesystem::block::{BlockDevice, BlockError};
+
+/// A `BlockDevice` kept in a heap buffer, for running block and filesystem code without disks.
+/// Starts out zeroed and loses its contents when dropped.
+pub struct RamDisk {
+    data: Vec<u8>,
+    block_size: usize,
+    num_blocks: u64,
+}
+
+impl RamDisk {
+    pub fn new(block_size: usize, num_blocks: u64) -> Self {
+        RamDisk {
+            data: vec![0; block_size * num_blocks as usize],
+            block_size,
+            num_blocks,
+        }
+    }
+
+    /// A disk holding `image`, rounded up to whole blocks with zeroes.
+    pub fn from_image(block_size: usize, image: &[u8]) -> Self {
+        let num_blocks = image.len().div_ceil(block_size) as u64;
+        let mut disk = RamDisk::new(block_size, num_blocks);
+        disk.data[..image.len()].copy_from_slice(image);
+        disk
+    }
+
+    fn block_range(&self, lba: u64, buf_len: usize) -> Result<core::ops::Range<usize>, BlockError> {
+        if lba >= self.num_blocks {
+            return Err(BlockError::OutOfRange);
+        }
+        if buf_len < self.block_size {
+            return Err(BlockError::BufferTooSmall);
+        }
+
+        let start = lba as usize * self.block_size;
+        Ok(start..start + self.block_size)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_block(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let range = self.block_range(lba, buf.len())?;
+        buf[..self.block_size].copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let range = self.block_range(lba, buf.len())?;
+        self.data[range].copy_from_slice(&buf[..self.block_size]);
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::mem::memory::{physical_memory_offset, MMIO_VIRT_BASE};
+use crate::serial_println;
+
+/// Header shared by every ACPI system description table (RSDT, XSDT, FADT, MADT, ...).
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<SdtHeader>();
+
+/// An ACPI table mapped into the MMIO window at `MMIO_VIRT_BASE + phys`.
+///
+/// Pages that were not mapped yet are mapped on creation and unmapped again when the table is
+/// dropped, so parsers never have to assume the firmware's memory is identity-mapped.
+pub struct Table<'a, M: Mapper<Size4KiB>> {
+    mapper: &'a mut M,
+    virt_addr: VirtAddr,
+    length: usize,
+    mapped_pages: Vec<Page<Size4KiB>>,
+}
+
+impl<'a, M: Mapper<Size4KiB>> Table<'a, M> {
+    /// Maps the table at `phys_addr`, then validates its length and checksum.
+    pub fn new(
+        phys_addr: u64,
+        mapper: &'a mut M,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<Self, &'static str> {
+        let mut table = Table {
+            mapper,
+            virt_addr: VirtAddr::new(MMIO_VIRT_BASE + phys_addr),
+            length: HEADER_SIZE,
+            mapped_pages: Vec::new(),
+        };
+
+        // Map the header first, its length tells us how much more to map
+        table.map_range(phys_addr, HEADER_SIZE, frame_allocator)?;
+        let length = table.header().length as usize;
+        if length < HEADER_SIZE {
+            return Err("ACPI table shorter than its header");
+        }
+
+        table.map_range(phys_addr, length, frame_allocator)?;
+        table.length = length;
+
+        let sum = table.bytes().iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if sum != 0 {
+            return Err("ACPI table checksum mismatch");
+        }
+
+        Ok(table)
+    }
+
+    pub fn header(&self) -> SdtHeader {
+        unsafe { core::ptr::read_unaligned(self.virt_addr.as_ptr::<SdtHeader>()) }
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        self.header().signature
+    }
+
+    /// The whole table, header included.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt_addr.as_ptr::<u8>(), self.length) }
+    }
+
+    /// Everything after the header, for table-specific parsers.
+    pub fn body(&self) -> &[u8] {
+        &self.bytes()[HEADER_SIZE..]
+    }
+
+    fn map_range(
+        &mut self,
+        phys_addr: u64,
+        length: usize,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), &'static str> {
+        let first_frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(PhysAddr::new(phys_addr));
+        let last_frame = PhysFrame::containing_address(PhysAddr::new(phys_addr + length as u64 - 1));
+
+        for frame in PhysFrame::range_inclusive(first_frame, last_frame) {
+            let page = Page::containing_address(VirtAddr::new(MMIO_VIRT_BASE + frame.start_address().as_u64()));
+            if self.mapper.translate_page(page).is_ok() {
+                continue;
+            }
+
+            unsafe {
+                self.mapper.map_to(page, frame, PageTableFlags::PRESENT, frame_allocator)
+                    .map_err(|_| "failed to map ACPI table")?
+                    .flush();
+            }
+            self.mapped_pages.push(page);
+        }
+
+        Ok(())
+    }
+}
+
+impl<M: Mapper<Size4KiB>> Drop for Table<'_, M> {
+    fn drop(&mut self) {
+        // The frames belong to the firmware, so only the mappings are released
+        for page in self.mapped_pages.drain(..) {
+            if let Ok((_, flush)) = self.mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+    }
+}
+
+/// Root System Description Pointer, found by scanning the BIOS area.
+#[repr(C, packed)]
+pub struct Rsdp {
+    signature: [u8; 8],   // "RSD PTR "
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,         // ACPI version
+    pub rsdt_address: u32,    // RSDT pointer (32-bit)
+    // If revision >= 2, these fields are available:
+    length: u32,          // Length of the entire RSDP (ACPI 2.0+)
+    xsdt_address: u64,    // XSDT pointer (64-bit)
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+const RSDP_V1_LENGTH: usize = 20;
+
+impl Rsdp {
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// The 64-bit XSDT pointer, if this is an ACPI 2.0+ RSDP whose extended checksum is valid.
+    pub fn xsdt_address(&self) -> Option<u64> {
+        let length = self.length as usize;
+        if self.revision < 2 || length < core::mem::size_of::<Rsdp>() {
+            return None;
+        }
+
+        let bytes = unsafe { core::slice::from_raw_parts(self as *const Rsdp as *const u8, length) };
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            serial_println!("RSDP extended checksum mismatch; ignoring the XSDT");
+            return None;
+        }
+
+        match self.xsdt_address {
+            0 => None,
+            xsdt_address => Some(xsdt_address),
+        }
+    }
+
+    /// Checks the ACPI 1.0 checksum over the first 20 bytes.
+    fn is_valid(&self) -> bool {
+        let bytes = unsafe { core::slice::from_raw_parts(self as *const Rsdp as *const u8, RSDP_V1_LENGTH) };
+        bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+    }
+}
+
+/// Zoek naar de RSDP in het geheugenbereik 0xE0000 - 0xFFFFF (BIOS RAM)
+///
+/// Reads through the bootloader's physical memory mapping, so it returns `None` until
+/// `kernel_main` has recorded the offset.
+pub fn find_rsdp() -> Option<&'static Rsdp> {
+    let offset = physical_memory_offset()?.as_u64();
+    let start_address: u64 = 0xE0000;
+    let end_address: u64 = 0xFFFFF;
+
+    for address in (start_address..end_address).step_by(16) {
+        let rsdp = unsafe { &*((offset + address) as *const Rsdp) };
+        if &rsdp.signature == b"RSD PTR " && rsdp.is_valid() {
+            return Some(rsdp);
+        }
+    }
+
+    None
+}
+
+fn print_rsdp(rsdp: &Rsdp) {
+    // Convert signature and oem_id arrays to strings for printing
+    let signature_str = core::str::from_utf8(&rsdp.signature).unwrap_or("Invalid signature");
+    let oem_id_str = core::str::from_utf8(&rsdp.oem_id).unwrap_or("Invalid OEM ID");
+
+    // Copy packed fields to properly aligned local variables
+    let rsdt_address = rsdp.rsdt_address;
+    let length = rsdp.length;
+    let xsdt_address = rsdp.xsdt_address;
+
+    // Print the basic fields of the RSDP
+    serial_println!("RSDP Found:");
+    serial_println!("  Signature: {}", signature_str);
+    serial_println!("  Checksum: {:#x}", rsdp.checksum);
+    serial_println!("  OEM ID: {}", oem_id_str);
+    serial_println!("  Revision: {}", rsdp.revision);
+    serial_println!("  RSDT Address: {:#x}", rsdt_address);
+
+    // If ACPI revision >= 2.0, print additional fields
+    if rsdp.revision >= 2 {
+        serial_println!("  Length: {}", length);
+        serial_println!("  XSDT Address: {:#x}", xsdt_address);
+        serial_println!("  Extended Checksum: {:#x}", rsdp.extended_checksum);
+    }
+}
+
+pub fn find_and_print_rsdp() {
+    if let Some(rsdp) = find_rsdp() {
+        print_rsdp(rsdp);
+    } else {
+        serial_println!("RSDP not found.");
+    }
+}
+
+/// The table listing every other ACPI table: the XSDT on ACPI 2.0+, otherwise the RSDT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootTable {
+    Rsdt(u64),
+    Xsdt(u64),
+}
+
+impl RootTable {
+    pub fn address(&self) -> u64 {
+        match *self {
+            RootTable::Rsdt(address) | RootTable::Xsdt(address) => address,
+        }
+    }
+
+    /// The RSDT lists 32-bit table pointers, the XSDT 64-bit ones.
+    fn entry_size(&self) -> usize {
+        match self {
+            RootTable::Rsdt(_) => 4,
+            RootTable::Xsdt(_) => 8,
+        }
+    }
+}
+
+/// Physical address of the XSDT, when the RSDP is ACPI 2.0+ and valid.
+pub fn find_xsdt() -> Option<u64> {
+    find_rsdp()?.xsdt_address()
+}
+
+/// Prefers the XSDT, since firmware may place tables above 4GB where the RSDT cannot point.
+pub fn find_root_table() -> Option<RootTable> {
+    let rsdp = find_rsdp()?;
+    match rsdp.xsdt_address() {
+        Some(xsdt_address) => Some(RootTable::Xsdt(xsdt_address)),
+        None => Some(RootTable::Rsdt(rsdp.rsdt_address as u64)),
+    }
+}
+
+/// Calls `f` with the physical address and header of every table listed in the root table,
+/// until it returns `false`. Tables that fail validation are skipped.
+pub fn walk_tables<M: Mapper<Size4KiB>>(
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    mut f: impl FnMut(u64, &SdtHeader) -> bool,
+) -> Result<(), &'static str> {
+    let root = find_root_table().ok_or("RSDP not found")?;
+    let root_table = Table::new(root.address(), mapper, frame_allocator)?;
+    let entry_size = root.entry_size();
+
+    for entry in 0..root_table.body().len() / entry_size {
+        let bytes = &root_table.body()[entry * entry_size..(entry + 1) * entry_size];
+        let address = match root {
+            RootTable::Rsdt(_) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64,
+            RootTable::Xsdt(_) => {
+                let mut pointer = [0u8; 8];
+                pointer.copy_from_slice(bytes);
+                u64::from_le_bytes(pointer)
+            }
+        };
+
+        // The root table keeps its pages mapped while each entry is briefly mapped as well
+        let header = match Table::new(address, &mut *root_table.mapper, frame_allocator) {
+            Ok(table) => table.header(),
+            Err(e) => {
+                serial_println!("Skipping ACPI table at {:#x}: {}", address, e);
+                continue;
+            }
+        };
+
+        if !f(address, &header) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Physical address of the first table with `signature`, e.g. `b"APIC"` for the MADT.
+pub fn find_acpi_table<M: Mapper<Size4KiB>>(
+    signature: &[u8; 4],
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Option<u64>, &'static str> {
+    let mut found = None;
+    walk_tables(mapper, frame_allocator, |address, header| {
+        if &header.signature == signature {
+            found = Some(address);
+        }
+        found.is_none()
+    })?;
+    Ok(found)
+}
+
+/// What `lsacpi` shows about a table, recorded once at boot so the shell needs no mapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableSummary {
+    pub address: u64,
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub oem_id: [u8; 6],
+}
+
+impl fmt::Display for TableSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let signature = core::str::from_utf8(&self.signature).unwrap_or("????");
+        let oem_id = core::str::from_utf8(&self.oem_id).unwrap_or("?").trim_end_matches([' ', '\0']);
+        write!(f, "{} len={} oem={}", signature, self.length, oem_id)
+    }
+}
+
+static TABLES: Mutex<Vec<TableSummary>> = Mutex::new(Vec::new());
+
+/// Walks the root table and remembers every valid table for `list_tables`. Needs the heap.
+pub fn discover_tables<M: Mapper<Size4KiB>>(
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, &'static str> {
+    let mut tables = Vec::new();
+    walk_tables(mapper, frame_allocator, |address, header| {
+        tables.push(TableSummary {
+            address,
+            signature: header.signature,
+            length: header.length,
+            oem_id: header.oem_id,
+        });
+        true
+    })?;
+
+    let count = tables.len();
+    *TABLES.lock() = tables;
+    Ok(count)
+}
+
+/// Prints one line per table found by `discover_tables`, e.g. `FACP len=244 oem=BOCHS`.
+pub fn list_tables(out: &mut dyn fmt::Write) {
+    let tables = TABLES.lock();
+
+    writeln!(out).unwrap();
+    if tables.is_empty() {
+        writeln!(out, "No ACPI tables found").unwrap();
+    }
+    for table in tables.iter() {
+        writeln!(out, "{}", table).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::hardware::mmio::Mmio;
+use crate::hardware::pit::{timer_ticks, PIT_HZ};
+use crate::interrupts::{InterruptIndex, PICS};
+use crate::mem::memory::MMIO_VIRT_BASE;
+use crate::serial_println;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_APIC_BIT: u32 = 1 << 9; // EDX of leaf 1
+
+// Register offsets from the LAPIC base
+const LAPIC_EOI: u32 = 0xB0;
+const LAPIC_SPURIOUS: u32 = 0xF0;
+const LAPIC_LVT_TIMER: u32 = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: u32 = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: u32 = 0x390;
+const LAPIC_TIMER_DIVIDE: u32 = 0x3E0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const CALIBRATION_TICKS: u64 = 10; // PIT ticks to count LAPIC timer decrements over
+
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0); // Virtual address, 0 until mapped
+static TIMER_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn lapic() -> Mmio {
+    Mmio::new(VirtAddr::new(LAPIC_BASE.load(Ordering::Acquire)))
+}
+
+/// Whether the CPU has a local APIC at all.
+pub fn is_supported() -> bool {
+    __cpuid(CPUID_FEATURES).edx & CPUID_APIC_BIT != 0
+}
+
+/// Whether timer interrupts come from the LAPIC timer rather than the PIT.
+pub fn timer_active() -> bool {
+    TIMER_ACTIVE.load(Ordering::Acquire)
+}
+
+/// Signals the end of an interrupt delivered by the local APIC.
+pub fn end_of_interrupt() {
+    if LAPIC_BASE.load(Ordering::Acquire) != 0 {
+        lapic().write32(LAPIC_EOI, 0);
+    }
+}
+
+/// Moves the timer interrupt from the PIT to the local APIC timer, at the same `PIT_HZ`.
+///
+/// The LAPIC timer runs at the bus clock, which we do not know, so it is first calibrated by
+/// counting how far it gets in `CALIBRATION_TICKS` PIT ticks. That needs interrupts enabled
+/// and the PIT running. Ticks keep landing on the same vector and in `timer_handler`, so
+/// `timer_ticks` and everything built on it keeps working. Leaves the PIT in charge on failure.
+pub fn init_timer<M: Mapper<Size4KiB>>(
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    if !is_supported() {
+        return Err("no local APIC");
+    }
+    if !interrupts::are_enabled() {
+        return Err("LAPIC timer calibration needs interrupts enabled");
+    }
+
+    let mut msr = Msr::new(IA32_APIC_BASE_MSR);
+    let apic_base = unsafe { msr.read() };
+    let phys = apic_base & APIC_BASE_ADDRESS_MASK;
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(MMIO_VIRT_BASE + phys));
+    if mapper.translate_page(page).is_err() {
+        let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)
+                .map_err(|_| "failed to map the local APIC")?
+                .flush();
+        }
+    }
+    LAPIC_BASE.store(MMIO_VIRT_BASE + phys, Ordering::Release);
+
+    // Software-enable the APIC; the MSR bit is normally already set by firmware
+    unsafe { msr.write(apic_base | APIC_BASE_ENABLE) };
+    let lapic = lapic();
+    lapic.write32(LAPIC_SPURIOUS, SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+
+    let per_tick = calibrate(&lapic);
+    if per_tick == 0 {
+        return Err("LAPIC timer did not count during calibration");
+    }
+    serial_println!(
+        "LAPIC timer: {} counts per tick, bus clock ~{} MHz",
+        per_tick,
+        per_tick * 16 * PIT_HZ / 1_000_000
+    );
+
+    // Switch over atomically, so no tick is acknowledged at the wrong controller
+    interrupts::without_interrupts(|| {
+        lapic.write32(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic.write32(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | InterruptIndex::Timer as u32);
+        lapic.write32(LAPIC_TIMER_INITIAL_COUNT, per_tick as u32);
+
+        unsafe {
+            let mut pics = PICS.lock();
+            let [primary, secondary] = pics.read_masks();
+            pics.write_masks(primary | 1, secondary); // IRQ 0 is the PIT
+        }
+        TIMER_ACTIVE.store(true, Ordering::Release);
+    });
+
+    Ok(())
+}
+
+/// LAPIC timer decrements per PIT tick, with the divider at 16.
+fn calibrate(lapic: &Mmio) -> u64 {
+    lapic.write32(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic.write32(LAPIC_LVT_TIMER, LVT_MASKED);
+
+    // Start on a tick edge so the window is a whole number of ticks
+    let edge = timer_ticks();
+    while timer_ticks() == edge {
+        core::hint::spin_loop();
+    }
+
+    let start = timer_ticks();
+    lapic.write32(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+    while timer_ticks() < start + CALIBRATION_TICKS {
+        core::hint::spin_loop();
+    }
+    let remaining = lapic.read32(LAPIC_TIMER_CURRENT_COUNT);
+    lapic.write32(LAPIC_TIMER_INITIAL_COUNT, 0); // Stop it
+
+    (u32::MAX - remaining) as u64 / CALIBRATION_TICKS
+}
@@ -0,0 +1,113 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::hardware::acpi::{find_acpi_table, Table};
+use crate::hardware::mmio::Mmio;
+use crate::mem::memory::MMIO_VIRT_BASE;
+use crate::serial_println;
+
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+const HPET_TABLE_ADDRESS: usize = 8; // Address field of the base address GAS in the table body
+
+// Register offsets from the HPET base
+const HPET_CAPABILITIES: u32 = 0x00;
+const HPET_CONFIGURATION: u32 = 0x10;
+const HPET_MAIN_COUNTER: u32 = 0xF0;
+
+const HPET_ENABLE: u64 = 1 << 0;
+const FEMTOSECONDS_PER_NANOSECOND: u128 = 1_000_000;
+const MAX_PERIOD_FS: u64 = 100_000_000; // The spec caps the tick period at 100 ns
+
+static HPET_BASE: AtomicU64 = AtomicU64::new(0); // Virtual address, 0 until init_hpet succeeds
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+fn hpet() -> Mmio {
+    Mmio::new(VirtAddr::new(HPET_BASE.load(Ordering::Acquire)))
+}
+
+/// Whether `init_hpet` found and started an HPET.
+pub fn is_available() -> bool {
+    HPET_BASE.load(Ordering::Acquire) != 0
+}
+
+/// Physical address of the HPET's registers, from the ACPI "HPET" table.
+pub fn find_hpet<M: Mapper<Size4KiB>>(
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Option<u64> {
+    let address = find_acpi_table(HPET_SIGNATURE, mapper, frame_allocator).ok()??;
+    let table = Table::new(address, mapper, frame_allocator).ok()?;
+    let field = table.body().get(HPET_TABLE_ADDRESS..HPET_TABLE_ADDRESS + 8)?;
+    Some(u64::from_le_bytes(field.try_into().ok()?))
+}
+
+/// Maps the HPET and starts its main counter, after which `timer_wait_ms` uses it.
+pub fn init_hpet<M: Mapper<Size4KiB>>(
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    let phys = find_hpet(mapper, frame_allocator).ok_or("no HPET table")?;
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(MMIO_VIRT_BASE + phys));
+    if mapper.translate_page(page).is_err() {
+        let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)
+                .map_err(|_| "failed to map the HPET")?
+                .flush();
+        }
+    }
+
+    let registers = Mmio::new(VirtAddr::new(MMIO_VIRT_BASE + phys));
+    let period = registers.read64(HPET_CAPABILITIES) >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        return Err("HPET reports an invalid counter period");
+    }
+
+    let config = registers.read64(HPET_CONFIGURATION);
+    registers.write64(HPET_CONFIGURATION, config | HPET_ENABLE);
+
+    PERIOD_FS.store(period, Ordering::Release);
+    HPET_BASE.store(MMIO_VIRT_BASE + phys, Ordering::Release);
+    serial_println!("HPET at {:#x}, {} MHz", phys, 1_000_000_000 / period);
+
+    Ok(())
+}
+
+/// The free-running main counter, or 0 without an HPET.
+pub fn hpet_read_counter() -> u64 {
+    if !is_available() {
+        return 0;
+    }
+    hpet().read64(HPET_MAIN_COUNTER)
+}
+
+/// Nanoseconds the main counter has advanced since it read `start`, or 0 without an HPET.
+pub fn ns_since(start: u64) -> u64 {
+    if !is_available() {
+        return 0;
+    }
+
+    let period = PERIOD_FS.load(Ordering::Acquire) as u128;
+    (hpet_read_counter().wrapping_sub(start) as u128 * period / FEMTOSECONDS_PER_NANOSECOND) as u64
+}
+
+/// Spins for at least `ns` nanoseconds against the main counter.
+///
+/// Unlike the tick-based waits this also works with interrupts disabled. Returns immediately
+/// without an HPET, so callers should check `is_available` first.
+pub fn hpet_wait_ns(ns: u64) {
+    if !is_available() {
+        return;
+    }
+
+    let period = PERIOD_FS.load(Ordering::Acquire) as u128;
+    let counts = (ns as u128 * FEMTOSECONDS_PER_NANOSECOND).div_ceil(period) as u64;
+    let start = hpet_read_counter();
+    while hpet_read_counter().wrapping_sub(start) < counts {
+        core::hint::spin_loop();
+    }
+}
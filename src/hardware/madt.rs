@@ -0,0 +1,126 @@
+use alloc::vec::Vec;
+
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+
+use crate::hardware::acpi::{find_acpi_table, Table};
+use crate::serial_println;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const MADT_PCAT_COMPAT: u32 = 1 << 0; // Legacy 8259 PICs are present as well
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32, // First global system interrupt this I/O APIC handles
+}
+
+/// An ISA IRQ that is wired to a different global system interrupt, e.g. the PIT on GSI 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16, // Polarity (bits 0-1) and trigger mode (bits 2-3)
+}
+
+/// Interrupt controllers described by the MADT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MadtInfo {
+    pub local_apic_address: u64,
+    pub has_legacy_pics: bool,
+    pub local_apic_ids: Vec<u8>, // Enabled or online-capable processors only
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+/// Locates the MADT through the XSDT/RSDT and collects the APICs it lists.
+pub fn parse_madt<M: Mapper<Size4KiB>>(
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Option<MadtInfo> {
+    let address = match find_acpi_table(MADT_SIGNATURE, mapper, frame_allocator) {
+        Ok(Some(address)) => address,
+        Ok(None) => {
+            serial_println!("No MADT found");
+            return None;
+        }
+        Err(e) => {
+            serial_println!("Failed to search ACPI tables: {}", e);
+            return None;
+        }
+    };
+
+    let table = Table::new(address, mapper, frame_allocator).ok()?;
+    parse_body(table.body())
+}
+
+/// Parses the MADT contents that follow the common SDT header.
+pub fn parse_body(body: &[u8]) -> Option<MadtInfo> {
+    let mut info = MadtInfo {
+        local_apic_address: read_u32(body, 0)? as u64,
+        has_legacy_pics: read_u32(body, 4)? & MADT_PCAT_COMPAT != 0,
+        local_apic_ids: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut offset = 8;
+    while offset + 2 <= body.len() {
+        let entry_type = body[offset];
+        let length = body[offset + 1] as usize;
+        if length < 2 || offset + length > body.len() {
+            serial_println!("Malformed MADT entry at offset {}", offset);
+            break;
+        }
+        let entry = &body[offset..offset + length];
+
+        match entry_type {
+            ENTRY_LOCAL_APIC => {
+                let flags = read_u32(entry, 4)?;
+                if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                    info.local_apic_ids.push(entry[3]);
+                }
+            }
+            ENTRY_IO_APIC => info.io_apics.push(IoApic {
+                id: entry[2],
+                address: read_u32(entry, 4)?,
+                gsi_base: read_u32(entry, 8)?,
+            }),
+            ENTRY_INTERRUPT_SOURCE_OVERRIDE => info.overrides.push(InterruptSourceOverride {
+                bus: entry[2],
+                source: entry[3],
+                gsi: read_u32(entry, 4)?,
+                flags: read_u16(entry, 8)?,
+            }),
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                info.local_apic_address = read_u64(entry, 4)?;
+            }
+            _ => {}
+        }
+
+        offset += length;
+    }
+
+    Some(info)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
@@ -0,0 +1,80 @@
+use alloc::vec::Vec;
+
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+
+use crate::hardware::acpi::{find_acpi_table, Table};
+use crate::serial_println;
+
+const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+const MCFG_RESERVED: usize = 8; // Reserved bytes between the header and the first allocation
+const ALLOCATION_SIZE: usize = 16;
+
+/// A PCIe enhanced configuration (ECAM) window covering `start_bus..=end_bus` of a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcamRegion {
+    pub base: u64, // Physical address of bus 0, even when `start_bus` is higher
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl EcamRegion {
+    /// Physical address of a function's 4 KiB configuration space.
+    pub fn function_address(&self, bus: u8, device: u8, function: u8) -> u64 {
+        self.base + ((bus as u64) << 20 | (device as u64) << 15 | (function as u64) << 12)
+    }
+
+    pub fn contains(&self, segment: u16, bus: u8) -> bool {
+        self.segment == segment && (self.start_bus..=self.end_bus).contains(&bus)
+    }
+
+    /// Bytes of configuration space from `start_bus` through `end_bus`.
+    pub fn size(&self) -> u64 {
+        (self.end_bus as u64 - self.start_bus as u64 + 1) << 20
+    }
+}
+
+/// Locates the MCFG through the XSDT/RSDT and returns the ECAM windows it lists.
+pub fn parse_mcfg<M: Mapper<Size4KiB>>(
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Vec<EcamRegion> {
+    let address = match find_acpi_table(MCFG_SIGNATURE, mapper, frame_allocator) {
+        Ok(Some(address)) => address,
+        Ok(None) => {
+            serial_println!("No MCFG found");
+            return Vec::new();
+        }
+        Err(e) => {
+            serial_println!("Failed to search ACPI tables: {}", e);
+            return Vec::new();
+        }
+    };
+
+    match Table::new(address, mapper, frame_allocator) {
+        Ok(table) => parse_body(table.body()),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Parses the MCFG contents that follow the common SDT header.
+pub fn parse_body(body: &[u8]) -> Vec<EcamRegion> {
+    let mut regions = Vec::new();
+    let allocations = body.get(MCFG_RESERVED..).unwrap_or(&[]);
+
+    for entry in allocations.chunks_exact(ALLOCATION_SIZE) {
+        let region = EcamRegion {
+            base: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+            segment: u16::from_le_bytes([entry[8], entry[9]]),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        };
+        if region.end_bus < region.start_bus {
+            serial_println!("Skipping MCFG allocation with bus range {}-{}", region.start_bus, region.end_bus);
+            continue;
+        }
+        regions.push(region);
+    }
+
+    regions
+}
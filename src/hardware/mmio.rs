@@ -0,0 +1,109 @@
+use x86_64::VirtAddr;
+
+use crate::hardware::hpet;
+use crate::hardware::pit::{ms_to_ticks, timer_ticks};
+
+/// A block of 32-bit device registers addressed by byte offset.
+///
+/// `wait_for_bit` only needs to read, which lets tests poll a mock instead of real hardware.
+pub trait RegisterBlock {
+    fn read_reg32(&self, offset: u32) -> u32;
+}
+
+/// Registers mapped at `base`, usually somewhere in the `MMIO_VIRT_BASE` window.
+pub struct Mmio {
+    base: VirtAddr,
+}
+
+impl Mmio {
+    pub const fn new(base: VirtAddr) -> Self {
+        Mmio { base }
+    }
+
+    pub fn read32(&self, offset: u32) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base.as_u64() + offset as u64) as *const u32) }
+    }
+
+    pub fn write32(&self, offset: u32, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base.as_u64() + offset as u64) as *mut u32, value) }
+    }
+
+    /// A single 64-bit access, for counters that must not tear between two 32-bit halves.
+    pub fn read64(&self, offset: u32) -> u64 {
+        unsafe { core::ptr::read_volatile((self.base.as_u64() + offset as u64) as *const u64) }
+    }
+
+    pub fn write64(&self, offset: u32, value: u64) {
+        unsafe { core::ptr::write_volatile((self.base.as_u64() + offset as u64) as *mut u64, value) }
+    }
+}
+
+impl RegisterBlock for Mmio {
+    fn read_reg32(&self, offset: u32) -> u32 {
+        self.read32(offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+/// Polls `bit` of the register at `offset` until it reads as `value`, giving up after
+/// `timeout_ms`.
+///
+/// The deadline is measured on the HPET when there is one. Otherwise it is measured in timer
+/// ticks, which only advance with interrupts enabled. The register is always read at least
+/// once, even with a zero timeout.
+pub fn wait_for_bit(
+    reg: &impl RegisterBlock,
+    offset: u32,
+    bit: u32,
+    value: bool,
+    timeout_ms: u64,
+) -> Result<(), Timeout> {
+    let hpet_start = hpet::is_available().then(hpet::hpet_read_counter);
+    let deadline = timer_ticks() + ms_to_ticks(timeout_ms);
+
+    loop {
+        if (reg.read_reg32(offset) >> bit) & 1 == value as u32 {
+            return Ok(());
+        }
+        let expired = match hpet_start {
+            Some(start) => hpet::ns_since(start) >= timeout_ms.saturating_mul(1_000_000),
+            None => timer_ticks() >= deadline,
+        };
+        if expired {
+            return Err(Timeout);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+struct FlippingRegister {
+    reads: core::cell::Cell<u32>,
+    flip_after: u32,
+}
+
+#[cfg(test)]
+impl RegisterBlock for FlippingRegister {
+    fn read_reg32(&self, _offset: u32) -> u32 {
+        let reads = self.reads.get() + 1;
+        self.reads.set(reads);
+        if reads > self.flip_after { 0x1 } else { 0x0 }
+    }
+}
+
+#[test_case]
+fn test_wait_for_bit_sees_flip() {
+    let reg = FlippingRegister { reads: core::cell::Cell::new(0), flip_after: 3 };
+
+    assert_eq!(wait_for_bit(&reg, 0x1C, 0, true, 100), Ok(()));
+    assert_eq!(reg.reads.get(), 4);
+}
+
+#[test_case]
+fn test_wait_for_bit_times_out() {
+    let reg = FlippingRegister { reads: core::cell::Cell::new(0), flip_after: u32::MAX };
+
+    assert_eq!(wait_for_bit(&reg, 0x1C, 0, true, 0), Err(Timeout));
+}
@@ -1,5 +1,11 @@
 
 pub mod vga;
 pub mod pci;
-pub mod rdsp;
+pub mod acpi;
+pub mod madt;
+pub mod mcfg;
 pub mod pit;
+pub mod mmio;
+pub mod apic;
+pub mod hpet;
+pub mod power;
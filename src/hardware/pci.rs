@@ -1,8 +1,16 @@
+use alloc::vec::Vec;
 use core::fmt::Write;
-use vga_buffer::Writer;
-use crate::{log, serial_println, vga_buffer};
+use crate::{log, serial_println};
+use crate::hardware::mcfg::{self, EcamRegion};
+use crate::mem::memory::MMIO_VIRT_BASE;
 
+use spin::Mutex;
 use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+// ECAM windows from the MCFG, mapped at MMIO_VIRT_BASE + base. Empty until init_ecam runs.
+static ECAM_REGIONS: Mutex<Vec<EcamRegion>> = Mutex::new(Vec::new());
 
 #[derive(Debug, Clone, Copy)]
 pub struct PciDevice {
@@ -17,31 +25,90 @@ pub struct PciDevice {
     pub revision_id: u8,
 }
 
-enum StorageCodes {
-    IDE,
-    SATA,
-    NVMe,
-    Unknown,
+/// Extra configuration space fields that are only read for `pci -v`, to keep full scans cheap.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDeviceDetails {
+    pub header_type: u8,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub has_capabilities: bool,
 }
 
-impl StorageCodes {
-    fn from_subclass(subclass: u8) -> Self {
-        match subclass {
-            0x01 => StorageCodes::IDE,
-            0x06 => StorageCodes::SATA,
-            0x08 => StorageCodes::NVMe,
-            _ => StorageCodes::Unknown,
-        }
-    }
+const PCI_STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
 
-    fn to_string(&self) -> &'static str {
-        match self {
-            StorageCodes::IDE => "IDE",
-            StorageCodes::SATA => "SATA",
-            StorageCodes::NVMe => "NVMe",
-            StorageCodes::Unknown => "Unknown",
-        }
-    }
+const PCI_CAPABILITIES_POINTER: u8 = 0x34;
+const PCI_MAX_CAPABILITIES: usize = 48; // (256 - 64) / 4, so a looping list cannot hang us
+
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+pub const PCI_CAP_ID_PCIE: u8 = 0x10;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+const PCI_HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+const PCI_SECONDARY_BUS: u8 = 0x19;
+
+const PCI_COMMAND: u8 = 0x04;
+const PCI_COMMAND_IO_SPACE: u16 = 1 << 0;
+const PCI_COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Name of the company behind a PCI vendor ID, for the IDs commonly seen in VMs and PCs.
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    let name = match vendor_id {
+        0x8086 => "Intel",
+        0x1022 => "AMD",
+        0x1002 => "AMD/ATI",
+        0x10de => "NVIDIA",
+        0x10ec => "Realtek",
+        0x14e4 => "Broadcom",
+        0x144d => "Samsung",
+        0x1b4b => "Marvell",
+        0x106b => "Apple",
+        0x1b36 => "Red Hat (QEMU)",
+        0x1af4 => "Red Hat (virtio)",
+        0x1234 => "QEMU/Bochs",
+        0x15ad => "VMware",
+        0x80ee => "VirtualBox",
+        0x1414 => "Microsoft",
+        0x5853 => "XenSource",
+        0x1d0f => "Amazon",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Description of a PCI class code, as specific as the subclass allows.
+pub fn class_name(class: u8, subclass: u8) -> Option<&'static str> {
+    let name = match (class, subclass) {
+        (0x00, 0x01) => "VGA-compatible device",
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI controller",
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x05) => "ATA controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x07) => "SAS controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, 0x00) => "Serial controller",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus controller",
+        (0x0C, _) => "Serial bus controller",
+        _ => return None,
+    };
+    Some(name)
 }
 
 fn pci_config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
@@ -73,6 +140,16 @@ fn read_pci_config_dword(address: u32) -> u32 {
     }
 }
 
+fn write_pci_config_dword(address: u32, value: u32) {
+    let mut address_port = Port::<u32>::new(0xCF8);
+    let mut data_port = Port::<u32>::new(0xCFC);
+
+    unsafe {
+        address_port.write(address);
+        data_port.write(value);
+    }
+}
+
 fn read_pci_config_byte(address: u32) -> u8 {
     let mut address_port = Port::<u32>::new(0xCF8);
     let mut data_port = Port::<u32>::new(0xCFC);
@@ -85,6 +162,67 @@ fn read_pci_config_byte(address: u32) -> u8 {
     }
 }
 
+/// Finds the ECAM windows in the MCFG and maps them, so `read_config_ecam` can reach extended
+/// configuration space. Needs the heap; without an MCFG, config reads keep using the ports.
+pub fn init_ecam<M: Mapper<Size4KiB>>(mapper: &mut M, frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
+    let regions = mcfg::parse_mcfg(mapper, frame_allocator);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let mut mapped = Vec::new();
+
+    for region in regions {
+        let start = region.function_address(region.start_bus, 0, 0);
+        let first_frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(PhysAddr::new(start));
+        let last_frame = PhysFrame::containing_address(PhysAddr::new(start + region.size() - 1));
+
+        let mut ok = true;
+        for frame in PhysFrame::range_inclusive(first_frame, last_frame) {
+            let page = Page::containing_address(VirtAddr::new(MMIO_VIRT_BASE + frame.start_address().as_u64()));
+            if mapper.translate_page(page).is_ok() {
+                continue;
+            }
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    ok = false;
+                    break;
+                }
+            }
+        }
+
+        if ok {
+            serial_println!(
+                "ECAM segment {} buses {:02x}-{:02x} at {:#x}",
+                region.segment, region.start_bus, region.end_bus, region.base
+            );
+            mapped.push(region);
+        } else {
+            serial_println!("Failed to map ECAM window at {:#x}", region.base);
+        }
+    }
+
+    *ECAM_REGIONS.lock() = mapped;
+}
+
+/// Reads the config dword at `offset` through ECAM, which reaches offsets up to 0xFFF.
+///
+/// Without a matching ECAM window this falls back to the 0xCF8/0xCFC ports, which only cover
+/// segment 0 and the first 256 bytes; anything else reads as all 1s, like an absent device.
+pub fn read_config_ecam(segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    let offset = offset & 0xFFC;
+    let region = ECAM_REGIONS.lock().iter().find(|region| region.contains(segment, bus)).copied();
+
+    match region {
+        Some(region) => {
+            let address = MMIO_VIRT_BASE + region.function_address(bus, device, function) + offset as u64;
+            unsafe { core::ptr::read_volatile(address as *const u32) }
+        }
+        None if segment == 0 && offset < 0x100 => {
+            read_pci_config_dword(pci_config_address(bus, device, function, offset as u8))
+        }
+        None => 0xFFFF_FFFF,
+    }
+}
+
 pub fn read_pci_bar(bus: u8, device: u8, function: u8, bar_num: u8) -> u32 {
     let bar_offset = 0x10 + (bar_num * 4);
     let address = pci_config_address(bus, device, function, bar_offset);
@@ -92,6 +230,46 @@ pub fn read_pci_bar(bus: u8, device: u8, function: u8, bar_num: u8) -> u32 {
     bar_value
 }
 
+/// Size in bytes of the region decoded by BAR `bar_num`, or 0 if the BAR is unimplemented.
+///
+/// Uses the usual sizing sequence: write all 1s, read back which address bits are hardwired to
+/// zero, then restore the original value. Memory and I/O decoding are switched off meanwhile
+/// so the device does not briefly claim addresses it should not. A 64-bit memory BAR is sized
+/// together with the BAR after it, which holds the upper half of the address.
+pub fn bar_size(bus: u8, device: u8, function: u8, bar_num: u8) -> u64 {
+    let address = pci_config_address(bus, device, function, 0x10 + bar_num * 4);
+    let original = read_pci_config_dword(address);
+    let is_io = original & 1 == 1;
+    let is_64bit = !is_io && (original >> 1) & 0b11 == 0b10;
+
+    let command = read_pci_config_u16(bus, device, function, PCI_COMMAND);
+    let command_address = pci_config_address(bus, device, function, PCI_COMMAND);
+    write_pci_config_dword(command_address, (command & !(PCI_COMMAND_MEMORY_SPACE | PCI_COMMAND_IO_SPACE)) as u32);
+
+    write_pci_config_dword(address, 0xFFFF_FFFF);
+    let low_mask = if is_io { 0xFFFF_FFFC } else { 0xFFFF_FFF0 };
+    let mut mask = (read_pci_config_dword(address) & low_mask) as u64;
+    write_pci_config_dword(address, original);
+
+    if is_64bit {
+        let upper_address = pci_config_address(bus, device, function, 0x10 + (bar_num + 1) * 4);
+        let upper_original = read_pci_config_dword(upper_address);
+        write_pci_config_dword(upper_address, 0xFFFF_FFFF);
+        mask |= (read_pci_config_dword(upper_address) as u64) << 32;
+        write_pci_config_dword(upper_address, upper_original);
+    } else {
+        // Pretend the missing upper half is all 1s, so inverting only covers the low 32 bits
+        mask |= 0xFFFF_FFFF_0000_0000;
+    }
+
+    write_pci_config_dword(command_address, command as u32);
+
+    if mask & 0xFFFF_FFFF == 0 && !is_64bit {
+        return 0;
+    }
+    (!mask).wrapping_add(1)
+}
+
 pub(crate) fn get_pci_device(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
     let address = pci_config_address(bus, device, function, 0x00);
     let vendor_id = read_pci_config_word(address);
@@ -120,40 +298,279 @@ pub(crate) fn get_pci_device(bus: u8, device: u8, function: u8) -> Option<PciDev
     })
 }
 
-pub fn debug_storage_scan(writer: &mut Writer) {
+fn read_pci_config_u16(bus: u8, device: u8, function: u8, offset: u8) -> u16 {
+    let address = pci_config_address(bus, device, function, offset);
+    let shift = ((offset & 0x02) * 8) as u32;
+    ((read_pci_config_dword(address) >> shift) & 0xFFFF) as u16
+}
+
+/// Sets `bits` in the command register and reads it back, returning whether they stuck.
+fn set_command_bits(bus: u8, device: u8, function: u8, bits: u16) -> bool {
+    let command = read_pci_config_u16(bus, device, function, PCI_COMMAND);
+
+    // The status register shares the dword; its error bits are write-1-to-clear, so write 0s
+    let address = pci_config_address(bus, device, function, PCI_COMMAND);
+    write_pci_config_dword(address, (command | bits) as u32);
+
+    read_pci_config_u16(bus, device, function, PCI_COMMAND) & bits == bits
+}
+
+/// Lets the function initiate DMA. Returns `false` if the bit did not stick.
+pub fn enable_bus_mastering(bus: u8, device: u8, function: u8) -> bool {
+    set_command_bits(bus, device, function, PCI_COMMAND_BUS_MASTER)
+}
+
+/// Lets the function respond to accesses to its memory BARs. Returns `false` if the bit did
+/// not stick.
+pub fn enable_memory_space(bus: u8, device: u8, function: u8) -> bool {
+    set_command_bits(bus, device, function, PCI_COMMAND_MEMORY_SPACE)
+}
+
+/// Calls `f` with the ID and offset of every capability in the function's capability list,
+/// until it returns `false`.
+fn walk_capabilities(bus: u8, device: u8, function: u8, mut f: impl FnMut(u8, u8) -> bool) {
+    let status = read_pci_config_u16(bus, device, function, 0x06);
+    if status & PCI_STATUS_CAPABILITIES_LIST == 0 {
+        return;
+    }
+
+    let base = pci_config_address(bus, device, function, 0x00);
+    // The bottom two bits of every pointer are reserved
+    let mut offset = read_pci_config_byte(base + PCI_CAPABILITIES_POINTER as u32) & 0xFC;
+    for _ in 0..PCI_MAX_CAPABILITIES {
+        if offset < 0x40 {
+            break;
+        }
+
+        let id = read_pci_config_byte(base + offset as u32);
+        if !f(id, offset) {
+            break;
+        }
+        offset = read_pci_config_byte(base + offset as u32 + 1) & 0xFC;
+    }
+}
+
+/// Every capability of the function as `(id, offset)` pairs, in list order.
+pub fn capabilities(bus: u8, device: u8, function: u8) -> Vec<(u8, u8)> {
+    let mut found = Vec::new();
+    walk_capabilities(bus, device, function, |id, offset| {
+        found.push((id, offset));
+        true
+    });
+    found
+}
+
+/// Config space offset of the first capability with `cap_id`, e.g. `PCI_CAP_ID_MSIX`.
+pub fn find_capability(bus: u8, device: u8, function: u8, cap_id: u8) -> Option<u8> {
+    let mut found = None;
+    walk_capabilities(bus, device, function, |id, offset| {
+        if id == cap_id {
+            found = Some(offset);
+        }
+        found.is_none()
+    });
+    found
+}
+
+pub fn get_pci_device_details(bus: u8, device: u8, function: u8) -> PciDeviceDetails {
+    let header_type = read_pci_config_byte(pci_config_address(bus, device, function, 0x00) + 0x0E) & 0x7F;
+    let status = read_pci_config_u16(bus, device, function, 0x06);
+
+    // Only general devices (header type 0) have subsystem IDs at 0x2C/0x2E
+    let (subsystem_vendor_id, subsystem_id) = if header_type == 0x00 {
+        (read_pci_config_u16(bus, device, function, 0x2C), read_pci_config_u16(bus, device, function, 0x2E))
+    } else {
+        (0, 0)
+    };
+
+    PciDeviceDetails {
+        header_type,
+        subsystem_vendor_id,
+        subsystem_id,
+        has_capabilities: status & PCI_STATUS_CAPABILITIES_LIST != 0,
+    }
+}
+
+fn header_type(bus: u8, device: u8, function: u8) -> u8 {
+    read_pci_config_byte(pci_config_address(bus, device, function, 0x00) + 0x0E)
+}
+
+fn is_pci_bridge(pci_device: &PciDevice) -> bool {
+    pci_device.class_code == 0x06 && pci_device.subclass_code == 0x04
+}
+
+/// Secondary bus number of a PCI-to-PCI bridge.
+pub fn secondary_bus(bus: u8, device: u8, function: u8) -> u8 {
+    read_pci_config_byte(pci_config_address(bus, device, function, 0x00) + PCI_SECONDARY_BUS as u32)
+}
+
+/// Calls `f` for every function reachable from bus 0, following PCI-to-PCI bridges.
+///
+/// Only functions 1-7 of multi-function devices are probed, and every bus is scanned at most
+/// once, so each function is reported exactly once. This does not allocate, so it can run
+/// before the heap is up.
+pub fn for_each_device(mut f: impl FnMut(&PciDevice)) {
+    let mut scanned = [false; 256];
+
+    // With a multi-function host bridge, function N is the host controller for bus N
+    if header_type(0, 0, 0) & PCI_HEADER_MULTI_FUNCTION == 0 {
+        scan_bus(0, &mut scanned, &mut f);
+    } else {
+        for function in 0..8 {
+            if get_pci_device(0, 0, function).is_some() {
+                scan_bus(function, &mut scanned, &mut f);
+            }
+        }
+    }
+}
+
+fn scan_bus(bus: u8, scanned: &mut [bool; 256], f: &mut impl FnMut(&PciDevice)) {
+    if scanned[bus as usize] {
+        return;
+    }
+    scanned[bus as usize] = true;
+
+    for device in 0..32 {
+        if get_pci_device(bus, device, 0).is_none() {
+            continue;
+        }
+
+        let functions = if header_type(bus, device, 0) & PCI_HEADER_MULTI_FUNCTION != 0 { 8 } else { 1 };
+        for function in 0..functions {
+            let Some(pci_device) = get_pci_device(bus, device, function) else {
+                continue;
+            };
+            f(&pci_device);
+
+            if is_pci_bridge(&pci_device) {
+                let secondary = secondary_bus(bus, device, function);
+                if secondary != 0 {
+                    scan_bus(secondary, scanned, f);
+                }
+            }
+        }
+    }
+}
+
+/// Every PCI function reachable from bus 0, in discovery order.
+pub fn enumerate_pci() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for_each_device(|pci_device| devices.push(*pci_device));
+    devices
+}
+
+/// Prints the devices found by `enumerate_pci`, noting which bus each bridge leads to.
+pub fn lspci(writer: &mut dyn Write) {
+    let devices = enumerate_pci();
+
+    writeln!(writer).unwrap();
+    for pci_device in &devices {
+        write!(
+            writer,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}{:02x}",
+            pci_device.bus,
+            pci_device.device,
+            pci_device.function,
+            pci_device.vendor_id,
+            pci_device.device_id,
+            pci_device.class_code,
+            pci_device.subclass_code
+        ).unwrap();
+        if is_pci_bridge(pci_device) {
+            write!(writer, " bridge -> bus {:02x}", secondary_bus(pci_device.bus, pci_device.device, pci_device.function)).unwrap();
+        }
+        writeln!(writer).unwrap();
+    }
+    writeln!(writer, "{} functions", devices.len()).unwrap();
+}
+
+/// Lists every PCI function, adding subsystem IDs and capability presence when `verbose` is set.
+pub fn display_devices(writer: &mut dyn Write, verbose: bool) {
+    writeln!(writer).unwrap();
     for bus in 0..=255 {
-        for device in 0..31 {
-            for function in 0..7 {
+        for device in 0..32 {
+            for function in 0..8 {
                 if let Some(pci_device) = get_pci_device(bus, device, function) {
-                    if pci_device.class_code == 0x01 {
-                        let storage_type = StorageCodes::from_subclass(pci_device.subclass_code);
-                        log!(
+                    write!(
+                        writer,
+                        "{:02x}:{:02x}.{} {:04x}:{:04x}",
+                        pci_device.bus,
+                        pci_device.device,
+                        pci_device.function,
+                        pci_device.vendor_id,
+                        pci_device.device_id
+                    ).unwrap();
+
+                    // Unknown IDs are already shown in hex above
+                    if let Some(vendor) = vendor_name(pci_device.vendor_id) {
+                        write!(writer, " {}", vendor).unwrap();
+                    }
+                    match class_name(pci_device.class_code, pci_device.subclass_code) {
+                        Some(class) => write!(writer, " {}", class).unwrap(),
+                        None => write!(writer, " class {:02x}{:02x}", pci_device.class_code, pci_device.subclass_code).unwrap(),
+                    }
+
+                    if verbose {
+                        let details = get_pci_device_details(bus, device, function);
+                        write!(
                             writer,
-                            "Found PCI Storage Device: Bus {}, Device {}, Function {}, Vendor ID: {:04x}, Device ID: {:04x}, Class Code: {:02x}, Subclass Code: {:02x}, Prog IF: {:02x}, Revision ID: {:02x}, Type: {}",
-                            pci_device.bus,
-                            pci_device.device,
-                            pci_device.function,
-                            pci_device.vendor_id,
-                            pci_device.device_id,
-                            pci_device.class_code,
-                            pci_device.subclass_code,
-                            pci_device.prog_if,
-                            pci_device.revision_id,
-                            storage_type.to_string()
-                        );
+                            " subsys {:04x}:{:04x} caps {}",
+                            details.subsystem_vendor_id,
+                            details.subsystem_id,
+                            if details.has_capabilities { "yes" } else { "no" }
+                        ).unwrap();
+                        for (id, _) in capabilities(bus, device, function) {
+                            write!(writer, " {:02x}", id).unwrap();
+                        }
                     }
+
+                    writeln!(writer).unwrap();
                 }
             }
         }
     }
 }
 
+pub fn debug_storage_scan(writer: &mut dyn Write) {
+    for pci_device in enumerate_pci() {
+        if pci_device.class_code == 0x01 {
+            let storage_type = class_name(pci_device.class_code, pci_device.subclass_code).unwrap_or("Unknown");
+            log!(
+                writer,
+                "Found PCI Storage Device: Bus {}, Device {}, Function {}, Vendor ID: {:04x}, Device ID: {:04x}, Class Code: {:02x}, Subclass Code: {:02x}, Prog IF: {:02x}, Revision ID: {:02x}, Type: {}",
+                pci_device.bus,
+                pci_device.device,
+                pci_device.function,
+                pci_device.vendor_id,
+                pci_device.device_id,
+                pci_device.class_code,
+                pci_device.subclass_code,
+                pci_device.prog_if,
+                pci_device.revision_id,
+                storage_type
+            );
+        }
+    }
+}
+
 // Functie om de PCI-bus te scannen
-pub fn display_disks(writer: &mut Writer) {
+pub fn display_disks(writer: &mut dyn Write) {
 
     log!(writer, "Start scanning PCI Bus for Mass Storage Controllers...");
 
     // read_nvme(writer);
 
     // serial_println!("PCI scan completed, storage devices displayed.");
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_pci_names() {
+    assert_eq!(vendor_name(0x8086), Some("Intel"));
+    assert_eq!(vendor_name(0x1b36), Some("Red Hat (QEMU)"));
+    assert_eq!(vendor_name(0xABCD), None);
+
+    assert_eq!(class_name(0x01, 0x08), Some("NVMe controller"));
+    assert_eq!(class_name(0x01, 0x42), Some("Mass storage controller"));
+    assert_eq!(class_name(0x06, 0x04), Some("PCI bridge"));
+    assert_eq!(class_name(0x40, 0x00), None);
+}
@@ -1,13 +1,17 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use x86_64::instructions::port::Port;
+use crate::hardware::hpet;
 use crate::serial_println;
 
 const PIT_FREQUENCY: u64 = 1_193_182;
-const PIT_HZ: u64 = 100;
+pub const PIT_HZ: u64 = 100;
 const PIT_COMMAND_PORT: u16 = 0x43;
 const PIT_CHANNEL_0_PORT: u16 = 0x40;
 const PIT_MODE_2: u8 = 0b00110100;
 
-static mut TIMER_TICKS: u64 = 0;
+// Only the timer interrupt writes this, so a plain atomic is enough to share it with readers
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
 
 pub fn pit_init() {
     let divisor = (PIT_FREQUENCY / PIT_HZ) as u16;
@@ -23,40 +27,62 @@ pub fn pit_init() {
 }
 
 pub fn timer_handler() {
-    unsafe {
-        TIMER_TICKS += 1;
+    let ticks = TIMER_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
 
-        if TIMER_TICKS % PIT_HZ == 0
-        {
-            // serial_println!("One second has passed\n");
-        }
+    if ticks % PIT_HZ == 0
+    {
+        // serial_println!("One second has passed\n");
     }
 }
 
-pub fn timer_wait_sec(seconds: u64) {
-    unsafe {
-        let ticks = TIMER_TICKS;
-        let ticks_to_wait = PIT_HZ * seconds;
+/// Returns the number of timer interrupts seen since the PIT was programmed.
+pub fn timer_ticks() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed)
+}
 
-        while TIMER_TICKS < ticks + ticks_to_wait {
-            // Wacht totdat de juiste hoeveelheid ticks is verstreken
-        }
+/// Timer ticks since boot; monotonic, at `PIT_HZ` per second.
+pub fn uptime_ticks() -> u64 {
+    timer_ticks()
+}
+
+/// Milliseconds since boot, at the timer's resolution of `1000 / PIT_HZ` ms.
+pub fn uptime_ms() -> u64 {
+    uptime_ticks() * 1000 / PIT_HZ
+}
 
-        serial_println!("Time taken: {} ticks", ticks_to_wait);
+/// Converts a duration to timer ticks, rounding up so short waits are never zero ticks.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * PIT_HZ).div_ceil(1000)
+}
+
+pub fn timer_wait_sec(seconds: u64) {
+    let ticks = timer_ticks();
+    let ticks_to_wait = PIT_HZ * seconds;
+
+    while timer_ticks() < ticks + ticks_to_wait {
+        // Wacht totdat de juiste hoeveelheid ticks is verstreken
     }
+
+    serial_println!("Time taken: {} ticks", ticks_to_wait);
 }
 
+/// Busy-waits for `ms` milliseconds, on the HPET when there is one and on timer ticks otherwise.
+///
+/// The tick fallback needs interrupts enabled and rounds up to whole ticks of `1000 / PIT_HZ` ms.
 pub fn timer_wait_ms(ms: u64) {
-    unsafe {
-        let ticks = TIMER_TICKS;
+    if hpet::is_available() {
+        hpet::hpet_wait_ns(ms * 1_000_000);
+        return;
+    }
 
-        let ticks_to_wait = ms / 10;
+    let ticks = timer_ticks();
 
-        // Wacht totdat de gewenste hoeveelheid ticks verstreken is
-        while TIMER_TICKS < ticks + ticks_to_wait {
+    let ticks_to_wait = ms_to_ticks(ms);
 
-        }
+    // Wacht totdat de gewenste hoeveelheid ticks verstreken is
+    while timer_ticks() < ticks + ticks_to_wait {
 
-        // serial_println!("Time taken: {} ticks", ticks_to_wait);
     }
-}
\ No newline at end of file
+
+    // serial_println!("Time taken: {} ticks", ticks_to_wait);
+}
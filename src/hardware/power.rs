@@ -0,0 +1,202 @@
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+
+use crate::hardware::acpi::{find_acpi_table, Table};
+use crate::{platform, serial_println};
+
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+// Offsets into the FADT body, i.e. after the 36-byte SDT header
+const FADT_DSDT: usize = 4;
+const FADT_SMI_COMMAND: usize = 12;
+const FADT_ACPI_ENABLE: usize = 16;
+const FADT_PM1A_CONTROL: usize = 28;
+const FADT_PM1B_CONTROL: usize = 32;
+const FADT_X_DSDT: usize = 104; // ACPI 2.0+
+
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+const ACPI_ENABLE_SPINS: u32 = 1_000_000;
+
+// AML encoding of the \_S5 package
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+// QEMU's PIIX4/ICH9 PM1a control port and the S5 value its firmware uses
+const QEMU_PM1A_CONTROL: u16 = 0x604;
+const QEMU_SLEEP_TYPE_S5: u16 = 0;
+
+/// What it takes to enter S5 (soft off), collected from the FADT and DSDT at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepControl {
+    pub pm1a_control: u16,
+    pub pm1b_control: Option<u16>,
+    pub sleep_type_a: u16,
+    pub sleep_type_b: u16,
+    smi_command: u16,
+    acpi_enable: u8,
+}
+
+static SLEEP_CONTROL: Mutex<Option<SleepControl>> = Mutex::new(None);
+
+/// Reads the PM1 control blocks from the FADT and the S5 sleep types from the DSDT, so
+/// `shutdown` can run later without a mapper. Needs the heap.
+pub fn init<M: Mapper<Size4KiB>>(
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    let fadt_address = find_acpi_table(FADT_SIGNATURE, mapper, frame_allocator)?.ok_or("no FADT")?;
+    let fadt = Table::new(fadt_address, mapper, frame_allocator)?;
+    let body = fadt.body();
+
+    let pm1a_control = read_u32(body, FADT_PM1A_CONTROL).ok_or("FADT too short")? as u16;
+    let pm1b_control = read_u32(body, FADT_PM1B_CONTROL).unwrap_or(0) as u16;
+    let smi_command = read_u32(body, FADT_SMI_COMMAND).unwrap_or(0) as u16;
+    let acpi_enable = body.get(FADT_ACPI_ENABLE).copied().unwrap_or(0);
+
+    // Prefer the 64-bit X_DSDT when the FADT is long enough to have one and it is set
+    let dsdt_address = match read_u64(body, FADT_X_DSDT) {
+        Some(address) if address != 0 => address,
+        _ => read_u32(body, FADT_DSDT).ok_or("FADT too short")? as u64,
+    };
+    drop(fadt);
+
+    let dsdt = Table::new(dsdt_address, mapper, frame_allocator)?;
+    let (sleep_type_a, sleep_type_b) = find_s5(dsdt.body()).ok_or("no \\_S5 object in the DSDT")?;
+
+    let control = SleepControl {
+        pm1a_control,
+        pm1b_control: if pm1b_control != 0 { Some(pm1b_control) } else { None },
+        sleep_type_a: sleep_type_a as u16,
+        sleep_type_b: sleep_type_b as u16,
+        smi_command,
+        acpi_enable,
+    };
+    serial_println!("ACPI S5: PM1a {:#x}, SLP_TYPa {}", control.pm1a_control, control.sleep_type_a);
+    *SLEEP_CONTROL.lock() = Some(control);
+
+    Ok(())
+}
+
+/// Finds the `\_S5` package in AML and returns its first two elements, SLP_TYPa and SLP_TYPb.
+///
+/// This is a pattern match rather than an AML interpreter. It relies on `_S5_` being a plain
+/// `Name` of a `Package` whose elements are integer constants, which is how every firmware we
+/// have seen encodes it. A `_S5_` that is a method, or whose values are computed, is not
+/// recognised, and neither is one inside a scope that an `If` makes conditional.
+pub fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let position = aml.windows(4).position(|window| window == b"_S5_")?;
+
+    // Expect NameOp before the name, possibly with a root prefix in between
+    let is_name = match position {
+        0 => false,
+        p if aml[p - 1] == AML_NAME_OP => true,
+        p => p >= 2 && aml[p - 1] == b'\\' && aml[p - 2] == AML_NAME_OP,
+    };
+    if !is_name {
+        return None;
+    }
+
+    let mut offset = position + 4;
+    if *aml.get(offset)? != AML_PACKAGE_OP {
+        return None;
+    }
+    offset += 1;
+
+    // PkgLength: bits 6-7 of the lead byte count the bytes that follow it
+    let extra_length_bytes = (*aml.get(offset)? >> 6) as usize;
+    offset += 1 + extra_length_bytes;
+    offset += 1; // NumElements
+
+    let (sleep_type_a, next) = read_aml_integer(aml, offset)?;
+    let (sleep_type_b, _) = read_aml_integer(aml, next).unwrap_or((0, next));
+    Some((sleep_type_a, sleep_type_b))
+}
+
+/// Reads a small integer constant, returning it and the offset just past it.
+fn read_aml_integer(aml: &[u8], offset: usize) -> Option<(u8, usize)> {
+    match *aml.get(offset)? {
+        AML_ZERO_OP => Some((0, offset + 1)),
+        AML_ONE_OP => Some((1, offset + 1)),
+        AML_BYTE_PREFIX => Some((*aml.get(offset + 1)?, offset + 2)),
+        _ => None,
+    }
+}
+
+/// Powers the machine off through ACPI S5.
+///
+/// Without usable ACPI data this falls back to QEMU's fixed PM1a port when running under QEMU,
+/// and halts when nothing worked.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+    serial_println!("Shutting down");
+
+    let control = *SLEEP_CONTROL.lock();
+
+    if let Some(control) = control {
+        enable_acpi(&control);
+        unsafe {
+            write_sleep(control.pm1a_control, control.sleep_type_a);
+            if let Some(pm1b_control) = control.pm1b_control {
+                write_sleep(pm1b_control, control.sleep_type_b);
+            }
+        }
+    }
+
+    if platform::is_qemu() {
+        unsafe { write_sleep(QEMU_PM1A_CONTROL, QEMU_SLEEP_TYPE_S5) };
+    }
+
+    serial_println!("ACPI shutdown failed; halting");
+    crate::halt();
+}
+
+/// Hands power management from SMM to the OS if the firmware has not done so already.
+fn enable_acpi(control: &SleepControl) {
+    let mut pm1a = Port::<u16>::new(control.pm1a_control);
+    if unsafe { pm1a.read() } & PM1_SCI_ENABLE != 0 || control.smi_command == 0 || control.acpi_enable == 0 {
+        return;
+    }
+
+    unsafe { Port::<u8>::new(control.smi_command).write(control.acpi_enable) };
+    for _ in 0..ACPI_ENABLE_SPINS {
+        if unsafe { pm1a.read() } & PM1_SCI_ENABLE != 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    serial_println!("ACPI enable timed out");
+}
+
+unsafe fn write_sleep(port: u16, sleep_type: u16) {
+    Port::<u16>::new(port).write((sleep_type << PM1_SLEEP_TYPE_SHIFT) | PM1_SLEEP_ENABLE);
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+#[test_case]
+fn test_find_s5() {
+    // Name (_S5_, Package (0x04) { Zero, Zero, Zero, Zero }) as QEMU's DSDT has it
+    let qemu = [0x10, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+    assert_eq!(find_s5(&qemu), Some((0, 0)));
+
+    // Root prefix and byte-prefixed values, as many real firmwares write it
+    let prefixed = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x0A, 0x04, 0x0A, 0x07, 0x0A, 0x07, 0x00, 0x00];
+    assert_eq!(find_s5(&prefixed), Some((7, 7)));
+
+    // A method named _S5_ is not something we can evaluate
+    let method = [0x14, 0x08, b'_', b'S', b'5', b'_', 0x00];
+    assert_eq!(find_s5(&method), None);
+    assert_eq!(find_s5(&[]), None);
+}
@@ -0,0 +1,98 @@
+use core::future::{poll_fn, Future};
+use core::task::{Context, Poll};
+
+use futures_util::task::AtomicWaker;
+
+use crate::print;
+use crate::serial_println;
+use crate::sync::BoundedQueue;
+use crate::task::keyboard::KeyEvent;
+use crate::vga_buffer::WRITER;
+
+// Fed by the `forward` tasks and drained by the shell task. All of them run on the same executor,
+// so there is only ever one producer active at a time.
+static INPUT_QUEUE: BoundedQueue<InputEvent, 256> = BoundedQueue::new();
+static INPUT_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// What the shell receives: typed characters, plus key presses that do not produce one
+/// (function keys, arrows, Ctrl combinations).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    Char(char),
+    Key(KeyEvent),
+}
+
+/// A device that produces input for the shell, such as the PS/2 keyboard or the serial port.
+/// Every source yields the same events, so the shell treats all of them alike.
+pub trait InputSource {
+    /// Returns the next event if one is ready, and otherwise arranges for `cx` to be woken
+    /// once the device may have more.
+    fn poll_event(&mut self, cx: &mut Context) -> Poll<InputEvent>;
+}
+
+/// Waits for the next event from `source`.
+pub fn next_event<S: InputSource>(source: &mut S) -> impl Future<Output = InputEvent> + '_ {
+    poll_fn(move |cx| source.poll_event(cx))
+}
+
+/// Moves every event from `source` into the shell's input queue. Each device runs this as a
+/// task of its own, so input keeps being read while the shell is busy with a command.
+pub async fn forward(mut source: impl InputSource) {
+    loop {
+        push_event(next_event(&mut source).await);
+    }
+}
+
+fn push_event(event: InputEvent) {
+    if INPUT_QUEUE.push(event).is_err() {
+        serial_println!("WARNING: input queue full; dropping input");
+    } else {
+        INPUT_WAKER.wake();
+    }
+}
+
+/// Echoes a single typed character to the screen and into the writer's input buffer.
+pub fn handle_char(character: char) {
+    use x86_64::instructions::interrupts;
+
+    match character {
+        '\u{8}' | '\u{7f}' => {
+            interrupts::without_interrupts(|| {
+                WRITER.lock().write_byte(0x08);
+            });
+        }
+        '\r' => print!("\n"),
+        // Other control characters are not text, and a typed ESC would start an escape sequence
+        // in the writer that swallows the next key
+        character if character.is_ascii_control() && character != '\t' && character != '\n' => {}
+        character => print!("{}", character),
+    }
+}
+
+/// The events queued by `forward` from every device, as one source for the shell task.
+pub struct InputStream {
+    _private: (),
+}
+
+impl InputStream {
+    pub fn new() -> Self {
+        InputStream { _private: () }
+    }
+}
+
+impl InputSource for InputStream {
+    fn poll_event(&mut self, cx: &mut Context) -> Poll<InputEvent> {
+        if let Some(event) = INPUT_QUEUE.pop() {
+            return Poll::Ready(event);
+        }
+
+        INPUT_WAKER.register(&cx.waker());
+        match INPUT_QUEUE.pop() {
+            Some(event) => {
+                INPUT_WAKER.take();
+                Poll::Ready(event)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
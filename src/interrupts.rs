@@ -1,5 +1,4 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use x86_64::instructions::port::Port;
 use pic8259::ChainedPics;
 use spin;
 
@@ -9,7 +8,10 @@ use crate::gdt;
 use crate::hlt_loop;
 
 use lazy_static::lazy_static;
-use crate::hardware::pit::timer_handler;
+use crate::hardware::apic;
+use crate::hardware::pit::{timer_handler, timer_ticks};
+use crate::task::timer::wake_expired;
+use crate::serial;
 use crate::vga_buffer::WRITER;
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -57,6 +59,9 @@ lazy_static! {
 
         idt.page_fault.set_handler_fn(page_fault_handler);
 
+        idt[apic::SPURIOUS_VECTOR as usize]
+            .set_handler_fn(spurious_interrupt_handler);
+
         idt
     };
 }
@@ -64,38 +69,77 @@ pub fn init_idt() {
     IDT.load();
 }
 
+const TIMER_CHECK_SPINS: u64 = 50_000_000;
+
+/// Last line of defence before the idle loop: with interrupts masked, or without a working timer,
+/// `hlt` never wakes up again and the machine looks dead.
+pub fn verify_before_idle() {
+    if !x86_64::instructions::interrupts::are_enabled() {
+        serial_println!("WARNING: interrupts were disabled before entering the idle loop; re-enabling");
+        x86_64::instructions::interrupts::enable();
+    }
+
+    let start = timer_ticks();
+    for _ in 0..TIMER_CHECK_SPINS {
+        if timer_ticks() != start {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+
+    serial_println!("WARNING: no timer interrupt received; the idle loop may never wake up");
+}
+
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+/// Runs on its own IST stack (see `gdt`), so a kernel stack overflow ends up here instead of
+/// escalating to a triple fault and a silent reboot.
 extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64) -> !
+    stack_frame: InterruptStackFrame, error_code: u64) -> !
 {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    use core::fmt::Write;
+
+    // The faulting code may hold the serial lock as well, so take it over like the writer's below
+    serial::print_fatal(format_args!(
+        "EXCEPTION: DOUBLE FAULT (error code {:#x})\n{:#?}\n", error_code, stack_frame
+    ));
+
+    // As in the page fault handler, we never return, so the writer lock can be taken over
+    if WRITER.is_locked() {
+        unsafe { WRITER.force_unlock() };
+    }
+    let mut writer = WRITER.lock();
+    let _ = write!(writer, "\nEXCEPTION: DOUBLE FAULT\n");
+    let _ = write!(writer, "Instruction: {:?}\n", stack_frame.instruction_pointer);
+    let _ = write!(writer, "Stack: {:?}\n", stack_frame.stack_pointer);
+    drop(writer);
+
+    crate::halt();
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
-    static mut COUNTER: u32 = 0;
+    timer_handler();
+    wake_expired(timer_ticks());
 
-    unsafe {
-        COUNTER += 1;
-
-        // Bevestig de interrupt door EOI naar de APIC te sturen
-        timer_handler()
+    // Once the LAPIC timer drives this vector the PIT is masked, so the PIC raised nothing to acknowledge
+    if apic::timer_active() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        }
     }
+}
 
-    // Additionally, if you're using the legacy PIC, send the EOI there too
-    unsafe {
-        let mut port = Port::new(0x20);
-        port.write(0x20u8);
-
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // Spurious LAPIC interrupts must not be acknowledged
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(
@@ -128,16 +172,68 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    use core::fmt::Write;
     use x86_64::registers::control::Cr2;
 
-    println!("EXPECTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    let address = Cr2::read();
+    let (cause, access, mode) = describe_page_fault(error_code);
+
+    serial_println!("EXCEPTION: PAGE FAULT");
+    serial_println!("Accessed Address: {:?} ({}, {} access, {} mode)", address, cause, access, mode);
+    serial_println!("Error code: {:?}", error_code);
+    serial_println!("{:#?}", stack_frame);
+
+    // The fault may have hit while the writer was locked, e.g. inside a shell command. We never
+    // return to that code, so taking the lock over is safe and println! would deadlock.
+    if WRITER.is_locked() {
+        unsafe { WRITER.force_unlock() };
+    }
+    let mut writer = WRITER.lock();
+    let _ = write!(writer, "\nEXCEPTION: PAGE FAULT\n");
+    let _ = write!(writer, "Accessed Address: {:?}\n", address);
+    let _ = write!(writer, "{}, {} access, {} mode\n", cause, access, mode);
+    let _ = write!(writer, "Instruction: {:?}\n", stack_frame.instruction_pointer);
+    drop(writer);
+
     hlt_loop();
 }
 
+/// Spells out the error code bits: what went wrong, which kind of access, and from which ring.
+fn describe_page_fault(error_code: PageFaultErrorCode) -> (&'static str, &'static str, &'static str) {
+    let cause = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+    let mode = if error_code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" };
+
+    (cause, access, mode)
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn test_describe_page_fault() {
+    assert_eq!(
+        describe_page_fault(PageFaultErrorCode::empty()),
+        ("page not present", "read", "kernel")
+    );
+    assert_eq!(
+        describe_page_fault(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE),
+        ("protection violation", "write", "kernel")
+    );
+    assert_eq!(
+        describe_page_fault(PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::USER_MODE),
+        ("page not present", "instruction fetch", "user")
+    );
+}
@@ -5,19 +5,27 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+pub mod arch;
 pub mod hardware;
 pub mod filesystem;
 pub mod mem;
 pub mod task;
 pub mod serial;
+pub mod input;
+pub mod sync;
+pub mod platform;
+pub mod qemu;
 
 pub mod interrupts;
 pub mod gdt;
 pub mod vga_buffer;
+pub mod commands;
 pub mod logger;
 
 extern crate alloc;
 
+pub use qemu::{exit_qemu, QemuExitCode};
+
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
 
@@ -38,28 +46,38 @@ where
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum QemuExitCode {
-    Success = 0x10,
-    Failed = 0x11,
-}
-
-pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
-
-    unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
-    }
-}
-
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
     }
 }
 
+/// Stops the system for good. Interrupts stay masked, so the `hlt` never wakes up again.
+pub fn halt() -> ! {
+    x86_64::instructions::interrupts::disable();
+    hlt_loop();
+}
+
+/// Assertion for early boot code that reports over serial and halts.
+///
+/// Unlike `assert!` it goes through neither the panic handler nor the VGA writer, and it never
+/// allocates, so it is safe to use before the heap and the console are set up.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        $crate::kassert!($cond, "assertion failed: {}", stringify!($cond))
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::serial_println!(
+                "[KASSERT] {}:{}:{}: {}",
+                file!(), line!(), column!(), format_args!($($arg)+)
+            );
+            $crate::halt();
+        }
+    };
+}
+
 #[cfg(test)]
 entry_point!(test_kernel_main);
 
@@ -72,6 +90,7 @@ fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
 }
 
 pub fn test_runner(tests: &[&dyn Testable]) {
+    platform::set_headless();
     serial_println!("Running {} tests", tests.len());
     for test in tests {
         test.run();
@@ -1,3 +1,116 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::hardware::pit;
+use crate::serial_println;
+use crate::vga_buffer::WRITER;
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static MIRROR_TO_SCREEN: AtomicBool = AtomicBool::new(false);
+
+/// How important a message is; each level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+impl Level {
+    const ALL: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+    /// Parses a level name as printed by `name`, ignoring case.
+    pub fn from_name(name: &str) -> Option<Level> {
+        Level::ALL.into_iter().find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+/// Drops every message less important than `level` from now on.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// The least important level that is still logged.
+pub fn max_level() -> Level {
+    let value = MAX_LEVEL.load(Ordering::Relaxed);
+    Level::ALL.into_iter().find(|level| *level as u8 == value).unwrap_or(Level::Info)
+}
+
+/// Whether a message at `level` gets through the filter.
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Also shows logged messages on the VGA screen, besides the serial port.
+pub fn set_screen_mirror(enabled: bool) {
+    MIRROR_TO_SCREEN.store(enabled, Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let ms = pit::uptime_ms();
+    serial_println!("[{:5}.{:03}] {} {}", ms / 1000, ms % 1000, level.prefix(), args);
+
+    if MIRROR_TO_SCREEN.load(Ordering::Relaxed) {
+        // Shell commands log while holding the writer; skip the screen then instead of deadlocking
+        interrupts::without_interrupts(|| {
+            if let Some(mut writer) = WRITER.try_lock() {
+                let _ = fmt::Write::write_fmt(&mut *writer, format_args!("\n{} {}", level.prefix(), args));
+            }
+        });
+    }
+}
+
+/// Logs at `Level::Error` to serial, with the uptime in front.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::logger::_log($crate::logger::Level::Error, format_args!($($arg)*)));
+}
+
+/// Logs at `Level::Warn` to serial, with the uptime in front.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::logger::_log($crate::logger::Level::Warn, format_args!($($arg)*)));
+}
+
+/// Logs at `Level::Info` to serial, with the uptime in front.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::logger::_log($crate::logger::Level::Info, format_args!($($arg)*)));
+}
+
+/// Logs at `Level::Debug` to serial, with the uptime in front.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::logger::_log($crate::logger::Level::Debug, format_args!($($arg)*)));
+}
+
 #[macro_export]
 macro_rules! log {
     ($writer:expr, $($arg:tt)*) => {
@@ -10,4 +123,20 @@ macro_rules! err {
     ($writer:expr, $($arg:tt)*) => {
         writeln!($writer, "\n[ERR] {}", format_args!($($arg)*)).expect("Failed to write log to VGA buffer");
     };
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_level_filter() {
+    let before = max_level();
+
+    set_max_level(Level::Warn);
+    assert!(enabled(Level::Error));
+    assert!(enabled(Level::Warn));
+    assert!(!enabled(Level::Info));
+    assert_eq!(max_level(), Level::Warn);
+
+    assert_eq!(Level::from_name("DEBUG"), Some(Level::Debug));
+    assert_eq!(Level::from_name("verbose"), None);
+
+    set_max_level(before);
+}
@@ -12,12 +12,15 @@ use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
 use x86_64::VirtAddr;
 
-use seraphine::{println};
+use seraphine::{platform, println, serial_println};
+use seraphine::input::{self, InputStream};
 use seraphine::print;
-use seraphine::task::keyboard;
-use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+use seraphine::task::{caret, heartbeat, keyboard, serial_input, shell};
+use seraphine::mem::bitmap::BitmapFrameAllocator;
+use seraphine::mem::memory;
 use seraphine::mem::allocator;
 use seraphine::filesystem::nvme;
+use seraphine::hardware::{acpi, apic, hpet, pci, power};
 use seraphine::task::{Task};
 use seraphine::task::executor::Executor;
 
@@ -30,25 +33,71 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Type 'help' to see available commands.");
     println!(" ");
     seraphine::init();
+    if cfg!(feature = "headless") {
+        platform::set_headless();
+    }
 
+    // Checked before anything reads or writes through the physical memory mapping
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    memory::check_memory_layout(phys_mem_offset, &boot_info.memory_map);
+    memory::set_physical_memory_offset(phys_mem_offset);
+
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    }.expect("no usable region can hold the frame bitmap");
 
     //Mapping BIOS
     memory::map_bios_area(&mut mapper, &mut frame_allocator);
 
-    //MAPPING HARD DRIVES
-    nvme::init_controller(&mut mapper, &mut frame_allocator);
+    // Calibrated against the PIT, which map_bios_area has just programmed
+    if let Err(e) = apic::init_timer(&mut mapper, &mut frame_allocator) {
+        serial_println!("Keeping the PIT as timer: {}", e);
+    }
 
     // HEAP ALLOCATOR
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
 
+    // Nothing past this point works without a heap, so drop to a console that needs none
+    if let Err(e) = allocator::self_check() {
+        serial_println!("Heap self-check failed: {}", e);
+        println!("Heap self-check failed: {}", e);
+        println!("Continuing on the serial console only.");
+        seraphine::serial::fallback_console();
+    }
+    serial_println!("Heap self-check passed, {} KB heap", allocator::heap_stats().size / 1024);
+
+    // Everything below walks the ACPI tables, which needs the heap
+    match acpi::discover_tables(&mut mapper, &mut frame_allocator) {
+        Ok(count) => serial_println!("Found {} ACPI tables", count),
+        Err(e) => serial_println!("Failed to list ACPI tables: {}", e),
+    }
+
+    if let Err(e) = power::init(&mut mapper, &mut frame_allocator) {
+        serial_println!("ACPI shutdown unavailable: {}", e);
+    }
+
+    // Extended PCI config space, now that the ACPI tables can be walked
+    pci::init_ecam(&mut mapper, &mut frame_allocator);
+    if let Err(e) = hpet::init_hpet(&mut mapper, &mut frame_allocator) {
+        serial_println!("No HPET, delays use timer ticks: {}", e);
+    }
+
+    //MAPPING HARD DRIVES
+    // After the HPET, so the reset and ready waits are timed on it
+    nvme::init_controller(&mut mapper, &mut frame_allocator);
+
+    memory::init_runtime_paging(mapper, frame_allocator);
+
     let mut executor = Executor::new(); // new
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(input::forward(keyboard::KeyboardSource::new())));
+    executor.spawn(Task::new(input::forward(serial_input::SerialSource::new())));
+    executor.spawn(Task::new(shell::run_shell(InputStream::new())));
+    executor.spawn(Task::new(heartbeat::run_heartbeat()));
+    executor.spawn(Task::new(caret::blink_caret()));
+
+    seraphine::interrupts::verify_before_idle();
     executor.run();
 
     #[cfg(test)]
@@ -59,7 +108,12 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    use seraphine::arch::backtrace::{print_backtrace, Registers};
+
+    let registers = Registers::capture();
+    serial_println!("{}", info);
+    print_backtrace(registers);
+    seraphine::vga_buffer::print_panic(format_args!("{}", info));
     seraphine::hlt_loop();
 }
 
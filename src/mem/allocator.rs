@@ -10,8 +10,20 @@ use x86_64::{
     VirtAddr,
 };
 
+use crate::mem::memory::UsableMemory;
+
 pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// Smallest heap `init_heap` sets up, however little memory the machine has.
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Largest heap `init_heap` sets up; `extend_heap` can still grow it further.
+pub const HEAP_INITIAL_MAX_SIZE: usize = 512 * 1024 * 1024; // 512 MiB
+/// Virtual address space set aside for the heap; `extend_heap` never grows it past this.
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+
+const SELF_CHECK_SIZE: usize = 4 * 1024;
+const PAGE_SIZE: usize = 4096;
+// The initial heap gets this fraction (1/n) of usable memory
+const HEAP_MEMORY_FRACTION: u64 = 4;
 
 pub struct Dummy;
 
@@ -28,31 +40,163 @@ unsafe impl GlobalAlloc for Dummy {
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
+/// Heap size for a machine with `usable_memory` bytes of RAM: a quarter of it, in whole pages,
+/// kept between `HEAP_SIZE` and `HEAP_INITIAL_MAX_SIZE`.
+pub fn heap_size_for(usable_memory: u64) -> usize {
+    let share = (usable_memory / HEAP_MEMORY_FRACTION).min(HEAP_INITIAL_MAX_SIZE as u64) as usize;
+    (share / PAGE_SIZE * PAGE_SIZE).max(HEAP_SIZE)
+}
+
+/// Maps the kernel heap at `HEAP_START`, sized by `heap_size_for` from the usable memory that
+/// `frame_allocator` knows about.
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + UsableMemory),
 ) -> Result<(), MapToError<Size4KiB>> {
+    let heap_size = heap_size_for(frame_allocator.usable_memory());
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_end = heap_start + heap_size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
+        map_heap_page(page, mapper, frame_allocator)?;
+    }
+
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, heap_size);
     }
 
+    Ok(())
+}
+
+/// Maps `additional` more bytes, rounded up to whole pages, right after the current heap end
+/// and hands them to the allocator. Returns how many bytes the heap grew by.
+///
+/// If frames run out or the next page is already taken partway through, the pages mapped up to
+/// then are still added to the heap before the error is returned.
+pub fn extend_heap(
+    additional: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, &'static str> {
+    let top = heap_stats().end;
+    if top == 0 {
+        return Err("heap is not initialized");
+    }
+
+    let additional = additional.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    if top + additional > HEAP_START + HEAP_MAX_SIZE {
+        return Err("heap would grow past its reserved address range");
+    }
+
+    let mut grown = 0;
+    let mut result = Ok(());
+    while grown < additional {
+        let page = Page::containing_address(VirtAddr::new((top + grown) as u64));
+        result = map_heap_page(page, mapper, frame_allocator).map_err(|err| match err {
+            MapToError::FrameAllocationFailed => "out of physical frames",
+            MapToError::PageAlreadyMapped(_) => "address range after the heap is already mapped",
+            MapToError::ParentEntryHugePage => "address range after the heap is in a huge page",
+        });
+        if result.is_err() {
+            break;
+        }
+        grown += PAGE_SIZE;
+    }
+
+    if grown > 0 {
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            ALLOCATOR.lock().extend(grown);
+        });
+    }
+
+    result.map(|()| grown)
+}
+
+fn map_heap_page(
+    page: Page<Size4KiB>,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush()
+    };
+    Ok(())
+}
+
+/// Kernel heap usage in bytes, and the address range it currently covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub start: usize,
+    pub end: usize,
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let heap = ALLOCATOR.lock();
+        HeapStats {
+            start: heap.bottom(),
+            end: heap.top(),
+            size: heap.size(),
+            used: heap.used(),
+            free: heap.free(),
+        }
+    })
+}
+
+/// Bytes still available on the kernel heap.
+pub fn heap_free() -> usize {
+    heap_stats().free
+}
+
+/// Allocates a few KB, fills them with a pattern and reads it back, so a misplaced or unmapped
+/// heap is caught right after `init_heap` instead of on the first allocation deep in the shell.
+pub fn self_check() -> Result<(), &'static str> {
+    let layout = Layout::from_size_align(SELF_CHECK_SIZE, 16)
+        .map_err(|_| "invalid self-check layout")?;
+
+    unsafe {
+        let ptr = alloc::alloc::alloc(layout);
+        if ptr.is_null() {
+            return Err("heap allocation failed");
+        }
+
+        for i in 0..SELF_CHECK_SIZE {
+            ptr.add(i).write_volatile(i as u8 ^ 0xA5);
+        }
+        let intact = (0..SELF_CHECK_SIZE).all(|i| ptr.add(i).read_volatile() == i as u8 ^ 0xA5);
+
+        alloc::alloc::dealloc(ptr, layout);
+
+        if !intact {
+            return Err("heap readback did not match the written pattern");
+        }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_heap_size_for() {
+    const MIB: u64 = 1024 * 1024;
+
+    assert_eq!(heap_size_for(16 * MIB), 4 * MIB as usize);
+    assert_eq!(heap_size_for(2048 * MIB), 512 * MIB as usize);
+    assert_eq!(heap_size_for(64 * 1024 * MIB), HEAP_INITIAL_MAX_SIZE);
+    assert_eq!(heap_size_for(0), HEAP_SIZE);
+    assert_eq!(heap_size_for(MIB / 2), HEAP_SIZE);
+
+    // Rounded down to whole pages
+    assert_eq!(heap_size_for(16 * MIB + 3 * 4096), 4 * MIB as usize);
+}
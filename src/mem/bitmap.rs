@@ -0,0 +1,162 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+    VirtAddr,
+};
+
+use crate::mem::memory::{total_usable_memory, ContiguousFrames, UsableMemory};
+use crate::{info, warn};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// Frame allocator that keeps one bit per frame, set while the frame is free.
+///
+/// The bitmap lives in the first usable region large enough to hold it, so it needs no heap and
+/// can be set up before `init_heap`. Allocation picks the lowest free frame at or after the
+/// first word that may still have one, which makes it O(1) apart from skipping full words.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    usable_memory: u64,
+    free_frames: usize,
+    next_word: usize, // No free frame below this word
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the bitmap for `memory_map`, writing it through the bootloader's mapping of all
+    /// physical memory at `physical_memory_offset`. The frames holding the bitmap are marked used.
+    ///
+    /// Returns `None` if the map has no usable region big enough for the bitmap.
+    ///
+    /// The caller must make sure the usable regions in `memory_map` are really unused, and
+    /// that only one allocator is built from them.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Option<Self> {
+        let usable_regions = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_addr() / FRAME_SIZE)
+            .max()? as usize;
+        let words = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = (words as u64 * 8).next_multiple_of(FRAME_SIZE);
+
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_bytes)?
+            .range.start_addr();
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+        bitmap.fill(0);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            usable_memory: total_usable_memory(memory_map),
+            free_frames: 0,
+            next_word: 0,
+        };
+
+        for region in usable_regions() {
+            let first = region.range.start_addr().div_ceil(FRAME_SIZE);
+            let end = region.range.end_addr() / FRAME_SIZE;
+            for index in first..end {
+                allocator.set_free(index as usize);
+            }
+        }
+
+        let bitmap_first = bitmap_start / FRAME_SIZE;
+        for index in bitmap_first..bitmap_first + bitmap_bytes / FRAME_SIZE {
+            allocator.set_used(index as usize);
+        }
+
+        info!(
+            "Frame bitmap: {} KB at {:#x}, {} free frames",
+            bitmap_bytes / 1024, bitmap_start, allocator.free_frames
+        );
+        Some(allocator)
+    }
+
+    /// Number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    fn is_free(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_free(&mut self, index: usize) {
+        if !self.is_free(index) {
+            self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+            self.free_frames += 1;
+        }
+    }
+
+    fn set_used(&mut self, index: usize) {
+        if self.is_free(index) {
+            self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+            self.free_frames -= 1;
+        }
+    }
+}
+
+impl UsableMemory for BitmapFrameAllocator {
+    fn usable_memory(&self) -> u64 {
+        self.usable_memory
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        while let Some(&word) = self.bitmap.get(self.next_word) {
+            if word != 0 {
+                let index = self.next_word * BITS_PER_WORD + word.trailing_zeros() as usize;
+                self.set_used(index);
+                return Some(PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE)));
+            }
+            self.next_word += 1;
+        }
+
+        None
+    }
+}
+
+impl ContiguousFrames for BitmapFrameAllocator {
+    /// Takes the lowest run of `count` free frames. Only frames in that run are marked used, so
+    /// fragmentation below it costs nothing.
+    fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut run = 0;
+        for index in self.next_word * BITS_PER_WORD..self.bitmap.len() * BITS_PER_WORD {
+            if !self.is_free(index) {
+                run = 0;
+                continue;
+            }
+
+            run += 1;
+            if run == count {
+                let first = index + 1 - count;
+                for frame in first..=index {
+                    self.set_used(frame);
+                }
+                return Some(PhysFrame::containing_address(PhysAddr::new(first as u64 * FRAME_SIZE)));
+            }
+        }
+
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    /// Returns `frame` to the allocator. The caller must make sure it is no longer mapped.
+    ///
+    /// Frames outside the bitmap or already free are ignored with a warning.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        if index / BITS_PER_WORD >= self.bitmap.len() || self.is_free(index) {
+            warn!("frame {:?} is not allocated; ignoring free", frame);
+            return;
+        }
+
+        self.set_free(index);
+        self.next_word = self.next_word.min(index / BITS_PER_WORD);
+    }
+}
@@ -1,54 +1,188 @@
+use alloc::vec::Vec;
+
 use x86_64::{
-    structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags as Flags, PhysFrame, Size4KiB},
+    structures::paging::{mapper::{MapToError, UnmapError}, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags as Flags, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB},
     PhysAddr,
     VirtAddr,
 };
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
 use crate::hardware::pit::{pit_init};
-use crate::hardware::rdsp::find_rsdp;
-use crate::serial_println;
+use crate::mem::bitmap::BitmapFrameAllocator;
+use crate::hardware::acpi::find_rsdp;
+use crate::{debug, kassert, warn};
+
+/// Virtual base used for device registers and DMA buffers, which are mapped at `MMIO_VIRT_BASE + phys`.
+///
+/// This window is mapped page by page by the drivers themselves and is separate from the
+/// bootloader's mapping of all physical memory, whose base is `physical_memory_offset()`.
+pub const MMIO_VIRT_BASE: u64 = 0xffff_8000_0000_0000;
+
+const FOUR_GIB: u64 = 0x1_0000_0000;
+
+// Where the bootloader mapped all of physical memory, recorded by `kernel_main`.
+static PHYS_MEM_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+// Handed over by `kernel_main` once boot-time mapping is done, so commands can map on demand.
+static RUNTIME_PAGING: Mutex<Option<RuntimePaging>> = Mutex::new(None);
+
+struct RuntimePaging {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BitmapFrameAllocator,
+}
+
+/// A frame allocator that knows how much usable RAM it hands frames out from, so the heap can
+/// be sized to match.
+pub trait UsableMemory {
+    fn usable_memory(&self) -> u64;
+}
+
+/// A frame allocator that can hand out physically adjacent frames, for DMA buffers that a
+/// device reads as one block.
+pub trait ContiguousFrames {
+    /// Allocates `count` adjacent frames and returns the first, or `None` if no free run is
+    /// long enough. No frames are taken when it fails.
+    fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame>;
+}
 
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
-    next: usize,
+    region: usize,  // Index in the memory map of the region handed out from
+    next_addr: u64, // Lowest address in that region not handed out yet
+    free_frames: Vec<PhysFrame>, // Returned frames, handed out again before the cursor moves on
 }
 
 impl BootInfoFrameAllocator {
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
         BootInfoFrameAllocator {
             memory_map,
-            next: 0,
+            region: 0,
+            next_addr: 0,
+            free_frames: Vec::new(),
         }
     }
 }
 
 impl BootInfoFrameAllocator {
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    /// Total bytes of usable RAM in the memory map this allocator hands out frames from.
+    pub fn usable_memory(&self) -> u64 {
+        total_usable_memory(self.memory_map)
+    }
+}
+
+impl UsableMemory for BootInfoFrameAllocator {
+    fn usable_memory(&self) -> u64 {
+        BootInfoFrameAllocator::usable_memory(self)
     }
 }
 
 pub struct EmptyFrameAllocator;
 
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        if let Some(frame) = self.free_frames.pop() {
+            return Some(frame);
+        }
+
+        // Walk the map with a cursor, so each frame costs O(1) instead of a rescan from the start
+        while let Some(region) = self.memory_map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable {
+                let addr = self.next_addr.max(region.range.start_addr());
+                if addr + 4096 <= region.range.end_addr() {
+                    self.next_addr = addr + 4096;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+                }
+            }
+            self.region += 1;
+            self.next_addr = 0;
+        }
+
+        None
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// Returns `frame` to the allocator. The caller must make sure it is no longer mapped.
+    ///
+    /// The free list lives on the heap, so frames returned before `init_heap` are leaked.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        if crate::mem::allocator::heap_stats().size == 0 {
+            warn!("frame {:?} freed before the heap exists; leaking it", frame);
+            return;
+        }
+
+        self.free_frames.push(frame);
+    }
+}
+
+/// Returns the end of the highest region in the memory map, usable or not.
+pub fn max_physical_address(memory_map: &MemoryMap) -> u64 {
+    memory_map.iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the number of usable bytes in the memory map.
+pub fn total_usable_memory(memory_map: &MemoryMap) -> u64 {
+    memory_map.iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+        .map(|r| r.range.end_addr() - r.range.start_addr())
+        .sum()
+}
+
+/// Returns the number of usable bytes located above the 4 GiB boundary.
+pub fn usable_memory_above_4g(memory_map: &MemoryMap) -> u64 {
+    memory_map.iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+        .map(|r| {
+            let start = r.range.start_addr().max(FOUR_GIB);
+            r.range.end_addr().saturating_sub(start)
+        })
+        .sum()
+}
+
+/// Logs the physical memory layout and makes sure the MMIO window at `MMIO_VIRT_BASE`
+/// cannot collide with the bootloader's mapping of all physical memory.
+pub fn check_memory_layout(physical_memory_offset: VirtAddr, memory_map: &MemoryMap) {
+    let max_phys = max_physical_address(memory_map);
+    let above_4g = usable_memory_above_4g(memory_map);
+
+    debug!("Highest physical address: {:#x}", max_phys);
+    debug!("Usable memory above 4GB: {} KB", above_4g / 1024);
+
+    // The window has to cover RAM as well as 32-bit BARs, which live just below 4 GiB.
+    let span = max_phys.max(FOUR_GIB);
+    let phys_map_start = physical_memory_offset.as_u64();
+    let phys_map_end = phys_map_start.saturating_add(span);
+    let mmio_end = MMIO_VIRT_BASE.saturating_add(span);
+
+    kassert!(
+        phys_map_end <= MMIO_VIRT_BASE || mmio_end <= phys_map_start,
+        "physical memory mapping {:#x}..{:#x} overlaps MMIO window {:#x}..{:#x}",
+        phys_map_start, phys_map_end, MMIO_VIRT_BASE, mmio_end
+    );
+}
+
+/// Records the offset of the bootloader's physical memory mapping, once `check_memory_layout` has
+/// accepted it. Only the first call has an effect.
+pub fn set_physical_memory_offset(offset: VirtAddr) {
+    let _ = PHYS_MEM_OFFSET.try_init_once(|| offset);
+}
+
+/// The offset recorded by `set_physical_memory_offset`, or `None` before `kernel_main` set it.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    PHYS_MEM_OFFSET.get().copied()
+}
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
@@ -89,7 +223,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
     let mut frame = level_4_table_frame;
 
     // traverse the multi-level page table
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // convert the frame into a page table reference
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -100,7 +234,15 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            Err(FrameError::HugeFrame) => {
+                // A 1GiB page at P3 or a 2MiB page at P2 covers the rest of the address
+                let page_size: u64 = match level {
+                    1 => Size1GiB::SIZE,
+                    2 => Size2MiB::SIZE,
+                    _ => return None, // the huge bit is reserved at P4
+                };
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
         };
     }
 
@@ -108,23 +250,106 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
 
+/// Maps `size` bytes of NVMe registers at `nvme_base_addr` to `virt_addr`.
+///
+/// The doorbells start at offset 0x1000 and every queue adds more, so the whole BAR has to be
+/// mapped rather than just the first page.
 pub fn map_nvme_base(
     nvme_base_addr: u64,
     virt_addr: VirtAddr,
+    size: u64,
     mapper: &mut OffsetPageTable,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) {
-    let frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(PhysAddr::new(nvme_base_addr));
-    let page: Page<Size4KiB> = Page::containing_address(virt_addr);
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE;
+    let pages = size.max(1).div_ceil(4096);
+
+    for i in 0..pages {
+        let frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(PhysAddr::new(nvme_base_addr + i * 4096));
+        let page: Page<Size4KiB> = Page::containing_address(virt_addr + i * 4096);
 
-    let map_to_result = unsafe {
-        mapper.map_to(page, frame, flags, frame_allocator)
+        let map_to_result = unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)
+        };
+
+        if i == 0 {
+            debug!("{:?}", map_to_result);
+        }
+
+        map_to_result.expect("map_to failed").flush();
+    }
+    debug!("Mapped {} pages of NVMe registers", pages);
+}
+
+/// Keeps the page table and frame allocator for mapping after boot. Called once by `kernel_main`
+/// after its last boot-time mapping.
+pub fn init_runtime_paging(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) {
+    *RUNTIME_PAGING.lock() = Some(RuntimePaging { mapper, frame_allocator });
+}
+
+/// Makes `len` bytes of physical memory at `phys` readable through the MMIO window and returns
+/// the virtual address of `phys`. Pages that are already mapped are left alone.
+pub fn map_physical_range(phys: u64, len: u64) -> Result<VirtAddr, &'static str> {
+    if len == 0 {
+        return Err("empty range");
+    }
+    let last = phys.checked_add(len - 1).ok_or("range wraps around")?;
+    PhysAddr::try_new(last).map_err(|_| "address beyond the physical address width")?;
+    let start_virt = MMIO_VIRT_BASE.checked_add(phys).and_then(|addr| VirtAddr::try_new(addr).ok());
+    let last_virt = MMIO_VIRT_BASE.checked_add(last).and_then(|addr| VirtAddr::try_new(addr).ok());
+    let (start_virt, last_virt) = match (start_virt, last_virt) {
+        (Some(start), Some(last)) => (start, last),
+        _ => return Err("address not canonical in the MMIO window"),
     };
 
-    serial_println!("{:?}", map_to_result);
+    let mut paging = RUNTIME_PAGING.lock();
+    let paging = paging.as_mut().ok_or("paging not initialized")?;
+    let offset = paging.mapper.phys_offset();
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE;
+
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start_virt),
+        Page::containing_address(last_virt),
+    );
+    for page in pages {
+        if unsafe { translate_addr(page.start_address(), offset) }.is_some() {
+            continue;
+        }
+
+        let frame = PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64() - MMIO_VIRT_BASE));
+        unsafe {
+            paging.mapper.map_to(page, frame, flags, &mut paging.frame_allocator)
+                .map_err(|_| "failed to map page")?
+                .flush();
+        }
+    }
+
+    Ok(start_virt)
+}
+
+/// Maps the 2MiB `page` to `frame` with a single P2 entry. `HUGE_PAGE` is added to `flags` by
+/// the mapper; `frame_allocator` is only used for a missing P3 or P2 table.
+pub fn map_huge_page(
+    page: Page<Size2MiB>,
+    frame: PhysFrame<Size2MiB>,
+    flags: Flags,
+    mapper: &mut impl Mapper<Size2MiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size2MiB>> {
+    unsafe {
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    }
+    Ok(())
+}
 
-    map_to_result.expect("map_to failed").flush();
+/// Removes the mapping for `page` and flushes it from the TLB.
+///
+/// The frame is handed back rather than freed, since only the caller knows whether it belongs
+/// to the frame allocator or to a device.
+pub fn unmap_page(page: Page<Size4KiB>, mapper: &mut OffsetPageTable) -> Result<PhysFrame<Size4KiB>, UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    Ok(frame)
 }
 
 pub fn map_bios_area(
@@ -1,3 +1,4 @@
 
 pub mod memory;
-pub mod allocator;
\ No newline at end of file
+pub mod allocator;
+pub mod bitmap;
\ No newline at end of file
@@ -0,0 +1,54 @@
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_HYPERVISOR_BIT: u32 = 1 << 31; // ECX of leaf 1
+const CPUID_HYPERVISOR_VENDOR: u32 = 0x4000_0000;
+
+const QEMU_TCG_SIGNATURE: &[u8; 12] = b"TCGTCGTCGTCG";
+const KVM_SIGNATURE: &[u8; 12] = b"KVMKVMKVM\0\0\0";
+
+// Set at boot when a script or test harness drives the session, see `set_headless`.
+static HEADLESS: AtomicBool = AtomicBool::new(false);
+
+/// The hypervisor's 12-byte vendor signature, or `None` when running on bare metal.
+pub fn hypervisor_vendor() -> Option<[u8; 12]> {
+    if __cpuid(CPUID_FEATURES).ecx & CPUID_HYPERVISOR_BIT == 0 {
+        return None;
+    }
+
+    let leaf = __cpuid(CPUID_HYPERVISOR_VENDOR);
+    let mut vendor = [0u8; 12];
+    vendor[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&leaf.edx.to_le_bytes());
+    Some(vendor)
+}
+
+/// Best-effort check for QEMU, either emulated (TCG) or accelerated by KVM.
+///
+/// Device timeouts were tuned against QEMU; anything else gets the more generous ones.
+pub fn is_qemu() -> bool {
+    matches!(hypervisor_vendor(), Some(vendor) if &vendor == QEMU_TCG_SIGNATURE || &vendor == KVM_SIGNATURE)
+}
+
+/// Short description of the platform for `sysinfo`.
+pub fn name() -> &'static str {
+    match hypervisor_vendor() {
+        Some(vendor) if &vendor == QEMU_TCG_SIGNATURE => "QEMU (TCG)",
+        Some(vendor) if &vendor == KVM_SIGNATURE => "QEMU/KVM",
+        Some(_) => "Other hypervisor",
+        None => "Real hardware",
+    }
+}
+
+/// Marks this boot as a headless session, driven over serial by a script or test harness
+/// rather than by someone at the keyboard. Set by test builds and by the `headless` feature.
+pub fn set_headless() {
+    HEADLESS.store(true, Ordering::Release);
+}
+
+/// Whether `set_headless` was called, which is what allows commands such as `exit` to end QEMU.
+pub fn is_headless() -> bool {
+    HEADLESS.load(Ordering::Acquire)
+}
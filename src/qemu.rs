@@ -0,0 +1,24 @@
+use x86_64::instructions::port::Port;
+
+/// The isa-debug-exit device, added by the `test-args` in Cargo.toml.
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Values for `exit_qemu`. QEMU exits with `(code << 1) | 1`, so these become 33 and 35; 33 is
+/// the `test-success-exit-code` bootimage maps back to a passing run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Ends the QEMU process with `exit_code`.
+///
+/// Returns when QEMU was started without the isa-debug-exit device (or on real hardware),
+/// so callers must decide what to do next.
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    unsafe {
+        let mut port = Port::new(ISA_DEBUG_EXIT_PORT);
+        port.write(exit_code as u32);
+    }
+}
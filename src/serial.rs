@@ -1,15 +1,113 @@
+use alloc::string::String;
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+
+const SERIAL1_BASE: u16 = 0x3F8;
+const LINE_STATUS_OFFSET: u16 = 5;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(SERIAL1_BASE) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// Returns the next byte received on COM1, or `None` if nothing is waiting.
+pub fn read_byte() -> Option<u8> {
+    let mut line_status = Port::<u8>::new(SERIAL1_BASE + LINE_STATUS_OFFSET);
+    let mut data = Port::<u8>::new(SERIAL1_BASE);
+
+    unsafe {
+        if line_status.read() & LINE_STATUS_DATA_READY == 0 {
+            return None;
+        }
+        Some(data.read())
+    }
+}
+
+/// Reads one line from COM1 into `buf`, without the line ending, echoing it back as it is typed.
+///
+/// Spins until Enter arrives, so this is meant for early or headless use; the shell gets serial
+/// input from `task::serial_input::SerialSource`, which polls `read_byte` instead.
+pub fn read_line(buf: &mut String) {
+    loop {
+        let Some(byte) = read_byte() else {
+            core::hint::spin_loop();
+            continue;
+        };
+
+        match byte {
+            b'\r' | b'\n' => {
+                _print(format_args!("\n"));
+                return;
+            }
+            0x08 | 0x7F => {
+                if buf.pop().is_some() {
+                    _print(format_args!("\u{8} \u{8}"));
+                }
+            }
+            byte => {
+                buf.push(byte as char);
+                _print(format_args!("{}", byte as char));
+            }
+        }
+    }
+}
+
+/// A command loop on COM1 that never allocates, for when the heap failed its self-check and
+/// the shell cannot run. Only knows `help`, `reboot` and `halt`.
+pub fn fallback_console() -> ! {
+    let mut line = [0u8; 64];
+    let mut len = 0;
+    let mut last_was_cr = false;
+
+    _print(format_args!("\nSerial fallback console; type 'help' for commands\n> "));
+    loop {
+        let Some(byte) = read_byte() else {
+            core::hint::spin_loop();
+            continue;
+        };
+
+        // Terminals send "\r\n" or just "\r" for Enter; only handle the line once
+        if byte == b'\n' && last_was_cr {
+            last_was_cr = false;
+            continue;
+        }
+        last_was_cr = byte == b'\r';
+
+        match byte {
+            b'\r' | b'\n' => {
+                _print(format_args!("\n"));
+                match core::str::from_utf8(&line[..len]).unwrap_or("").trim() {
+                    "" => {}
+                    "help" => _print(format_args!("help   - Show this list\nreboot - Restart the machine\nhalt   - Stop the system\n")),
+                    "reboot" => crate::arch::reset::reboot(),
+                    "halt" => crate::halt(),
+                    other => _print(format_args!("Unknown command: {}\n", other)),
+                }
+                len = 0;
+                _print(format_args!("> "));
+            }
+            0x08 | 0x7F => {
+                if len > 0 {
+                    len -= 1;
+                    _print(format_args!("\u{8} \u{8}"));
+                }
+            }
+            byte if len < line.len() => {
+                line[len] = byte;
+                len += 1;
+                _print(format_args!("{}", byte as char));
+            }
+            _ => {}
+        }
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -23,6 +121,20 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Prints to COM1 from a handler that never returns, even if the port was locked when it ran.
+///
+/// The code holding the lock will not run again, so the lock is broken rather than waited for.
+pub fn print_fatal(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::disable();
+    if SERIAL1.is_locked() {
+        unsafe { SERIAL1.force_unlock() };
+    }
+    let _ = SERIAL1.lock().write_fmt(args);
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -0,0 +1,130 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Runs `f` on the contents of `mutex` with interrupts disabled.
+///
+/// Any global that is touched from both an interrupt handler and normal code must be locked
+/// through this, the same way `vga_buffer::_print` does it. Otherwise the handler can interrupt
+/// a holder of the lock and spin on it forever. Keep `f` short: timer ticks do not advance while
+/// it runs.
+pub fn with_locked_irqsafe<T, R>(mutex: &Mutex<T>, f: impl FnOnce(&mut T) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut mutex.lock()))
+}
+
+/// Fixed-capacity ring buffer for handing values from an interrupt handler to a task.
+///
+/// The queue is single-producer/single-consumer: exactly one context may `push` (usually an
+/// interrupt handler) and exactly one context may `pop` (usually an async task). Neither side
+/// allocates, blocks or takes a lock, so `push` is safe to call from interrupt context even
+/// while the consumer is in the middle of a `pop`. Pushing from two contexts at once, or
+/// popping from two, can lose or duplicate elements.
+pub struct BoundedQueue<T, const N: usize> {
+    buffer: [UnsafeCell<MaybeUninit<T>>; N],
+    head: AtomicUsize, // Next slot to pop, only written by the consumer
+    tail: AtomicUsize, // Next slot to push, only written by the producer
+}
+
+unsafe impl<T: Send, const N: usize> Sync for BoundedQueue<T, N> {}
+
+impl<T, const N: usize> BoundedQueue<T, N> {
+    pub const fn new() -> Self {
+        BoundedQueue {
+            buffer: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Appends `value`, handing it back if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+
+        if tail.wrapping_sub(head) == N {
+            return Err(value);
+        }
+
+        unsafe {
+            (*self.buffer[tail % N].get()).write(value);
+        }
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Removes the oldest value, if any.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        let value = unsafe { (*self.buffer[head % N].get()).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Drop for BoundedQueue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[test_case]
+fn test_bounded_queue_fifo() {
+    let queue: BoundedQueue<u8, 4> = BoundedQueue::new();
+    queue.push(1).unwrap();
+    queue.push(2).unwrap();
+    queue.push(3).unwrap();
+
+    assert_eq!(queue.len(), 3);
+    assert_eq!(queue.pop(), Some(1));
+    assert_eq!(queue.pop(), Some(2));
+    assert_eq!(queue.pop(), Some(3));
+    assert_eq!(queue.pop(), None);
+}
+
+#[test_case]
+fn test_bounded_queue_full() {
+    let queue: BoundedQueue<u8, 2> = BoundedQueue::new();
+    queue.push(1).unwrap();
+    queue.push(2).unwrap();
+
+    assert!(queue.is_full());
+    assert_eq!(queue.push(3), Err(3));
+}
+
+#[test_case]
+fn test_bounded_queue_wraps_around() {
+    let queue: BoundedQueue<usize, 3> = BoundedQueue::new();
+
+    for i in 0..10 {
+        queue.push(i).unwrap();
+        assert_eq!(queue.pop(), Some(i));
+    }
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,15 @@
+use x86_64::instructions::interrupts;
+
+use crate::task::timer::sleep_ms;
+use crate::vga_buffer::WRITER;
+
+const BLINK_INTERVAL_MS: u64 = 500;
+
+/// Blinks the input caret on the VGA screen. Commands run with the writer locked, so the caret
+/// stays off while they print and comes back at the next prompt.
+pub async fn blink_caret() {
+    loop {
+        sleep_ms(BLINK_INTERVAL_MS).await;
+        interrupts::without_interrupts(|| WRITER.lock().blink_caret());
+    }
+}
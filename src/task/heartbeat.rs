@@ -0,0 +1,76 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use futures_util::future::{self, Either};
+use futures_util::task::AtomicWaker;
+
+use crate::hardware::pit::{uptime_ms, uptime_ticks};
+use crate::mem::allocator;
+use crate::serial_println;
+use crate::task::timer::sleep_ms;
+
+// Seconds between heartbeats, 0 means off. Bumping GENERATION restarts the current wait.
+static INTERVAL_SECS: AtomicU64 = AtomicU64::new(0);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static CHANGE_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Sets the heartbeat interval in seconds; `None` turns the heartbeat off.
+pub fn set_interval(seconds: Option<u64>) {
+    INTERVAL_SECS.store(seconds.unwrap_or(0), Ordering::SeqCst);
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    CHANGE_WAKER.wake();
+}
+
+/// Emits one line to serial every interval, so an external monitor can tell the kernel is alive.
+///
+/// The task idles while the heartbeat is off and picks up a new interval immediately.
+pub async fn run_heartbeat() {
+    loop {
+        let generation = GENERATION.load(Ordering::SeqCst);
+        let seconds = INTERVAL_SECS.load(Ordering::SeqCst);
+
+        if seconds == 0 {
+            IntervalChanged { seen: generation }.await;
+            continue;
+        }
+
+        match future::select(sleep_ms(seconds * 1000), IntervalChanged { seen: generation }).await {
+            Either::Left(_) => emit(),
+            Either::Right(_) => continue,
+        }
+    }
+}
+
+fn emit() {
+    serial_println!(
+        "[heartbeat] uptime={}s ticks={} heap_free={}",
+        uptime_ms() / 1000,
+        uptime_ticks(),
+        allocator::heap_free()
+    );
+}
+
+/// Resolves once `set_interval` has been called since `seen` was read.
+struct IntervalChanged {
+    seen: u64,
+}
+
+impl Future for IntervalChanged {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if GENERATION.load(Ordering::SeqCst) != self.seen {
+            return Poll::Ready(());
+        }
+
+        CHANGE_WAKER.register(cx.waker());
+        if GENERATION.load(Ordering::SeqCst) != self.seen {
+            CHANGE_WAKER.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
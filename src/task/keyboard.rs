@@ -1,42 +1,247 @@
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use core::{pin::Pin, task::{Poll, Context}};
+use core::sync::atomic::{AtomicBool, Ordering};
 use futures_util::stream::Stream;
 use futures_util::task::AtomicWaker;
 
 use crate::println;
 
-use futures_util::stream::StreamExt;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::layouts::{self, AnyLayout};
+use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use spin::Mutex;
+pub use pc_keyboard::KeyCode;
 use crate::print;
-use crate::vga_buffer::WRITER;
+use crate::input::{InputEvent, InputSource};
+use crate::serial_println;
+use crate::sync::BoundedQueue;
 
+// Raw scancodes from the IRQ1 handler. Decoding happens in `KeyboardSource`, so the
+// handler only reads port 0x60 and pushes here.
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
 
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(ScancodeSet1::new(),
-                                     layouts::Us104Key, HandleControl::Ignore);
-
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => {
-                        if character == '\u{8}' {
-                            WRITER.lock().write_byte(0x08);
-                        } else {
-                            print!("{}", character);
-                        }
-                    },
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+// Decoded keys for an open `KeyStream`. Only filled while one exists, so nobody listening
+// does not mean a full queue.
+static KEY_QUEUE: BoundedQueue<DecodedKey, 64> = BoundedQueue::new();
+static KEY_WAKER: AtomicWaker = AtomicWaker::new();
+static KEY_STREAM_OPEN: AtomicBool = AtomicBool::new(false);
+
+// Picked up by the decode task on the next scancode, so a switch never splits a key event.
+static LAYOUT: Mutex<KeyLayout> = Mutex::new(KeyLayout::Us);
+
+/// Keyboard layouts the decoder can switch between at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyLayout {
+    /// US QWERTY, 104 keys.
+    Us,
+    /// German QWERTZ, 105 keys. AltGr gives `@`, `{`, `[`, `]`, `}`, `\`, `~` and `|`.
+    De,
+    /// UK QWERTY, 105 keys.
+    Uk,
+}
+
+impl KeyLayout {
+    pub fn from_name(name: &str) -> Option<KeyLayout> {
+        [KeyLayout::Us, KeyLayout::De, KeyLayout::Uk]
+            .into_iter()
+            .find(|layout| layout.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyLayout::Us => "us",
+            KeyLayout::De => "de",
+            KeyLayout::Uk => "uk",
+        }
+    }
+
+    fn to_any(self) -> AnyLayout {
+        match self {
+            KeyLayout::Us => AnyLayout::Us104Key(layouts::Us104Key),
+            KeyLayout::De => AnyLayout::De105Key(layouts::De105Key),
+            KeyLayout::Uk => AnyLayout::Uk105Key(layouts::Uk105Key),
+        }
+    }
+}
+
+/// Switches the layout used to decode keyboard input from the next key press on.
+pub fn set_layout(layout: KeyLayout) {
+    *LAYOUT.lock() = layout;
+}
+
+/// The layout keyboard input is currently decoded with.
+pub fn layout() -> KeyLayout {
+    *LAYOUT.lock()
+}
+
+/// The PS/2 keyboard as an input source for the shell. Decoded keys are also handed to the
+/// `KeyStream`, if one is open.
+pub struct KeyboardSource {
+    scancodes: ScancodeStream,
+    keyboard: KeyboardInput,
+}
+
+impl KeyboardSource {
+    pub fn new() -> Self {
+        KeyboardSource { scancodes: ScancodeStream::new(), keyboard: KeyboardInput::new() }
+    }
+}
+
+impl InputSource for KeyboardSource {
+    fn poll_event(&mut self, cx: &mut Context) -> Poll<InputEvent> {
+        loop {
+            let scancode = match Pin::new(&mut self.scancodes).poll_next(cx) {
+                Poll::Ready(Some(scancode)) => scancode,
+                _ => return Poll::Pending,
+            };
+
+            self.keyboard.set_layout(layout());
+            if let Some(event) = self.keyboard.shell_event(scancode) {
+                return Poll::Ready(event);
             }
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Up,
+    Down,
+}
+
+/// Modifier keys in effect when a key event happened. Either side counts for shift/ctrl/alt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+}
+
+/// A single key press or release, for consumers that need more than the decoded character
+/// (arrows, function keys, Ctrl combinations).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: Modifiers,
+}
+
+/// Decodes PS/2 scancodes into key events and characters for the shell.
+pub struct KeyboardInput {
+    keyboard: Keyboard<AnyLayout, ScancodeSet1>,
+    layout: KeyLayout,
+    // Held state per physical key, so releasing one shift does not clear the other
+    lshift: bool,
+    rshift: bool,
+    lctrl: bool,
+    rctrl: bool,
+    lalt: bool,
+    ralt: bool,
+    caps_lock: bool,
+}
+
+impl KeyboardInput {
+    /// Creates a decoder for the currently selected layout.
+    pub fn new() -> Self {
+        KeyboardInput::with_layout(layout())
+    }
+
+    fn with_layout(layout: KeyLayout) -> Self {
+        KeyboardInput {
+            keyboard: Keyboard::new(ScancodeSet1::new(),
+                                    layout.to_any(), HandleControl::Ignore),
+            layout,
+            lshift: false,
+            rshift: false,
+            lctrl: false,
+            rctrl: false,
+            lalt: false,
+            ralt: false,
+            caps_lock: false,
+        }
+    }
+
+    /// Decodes further scancodes with `layout`. The new decoder starts without held modifiers
+    /// or a half-received extended sequence, so call this between key presses.
+    pub fn set_layout(&mut self, layout: KeyLayout) {
+        if layout == self.layout {
+            return;
+        }
+
+        // The tracked modifiers go with the decoder, so the two agree on what is held
+        *self = KeyboardInput::with_layout(layout);
+    }
+
+    /// Feeds one scancode. Once it completes a key event, returns that event together with
+    /// the character it produced, if any.
+    pub fn feed(&mut self, scancode: u8) -> Option<(KeyEvent, Option<DecodedKey>)> {
+        let raw_event = match self.keyboard.add_byte(scancode) {
+            Ok(Some(raw_event)) => raw_event,
+            _ => return None,
+        };
+
+        let state = match raw_event.state {
+            pc_keyboard::KeyState::Up => KeyState::Up,
+            _ => KeyState::Down,
+        };
+        self.track_modifier(raw_event.code, state);
+
+        let event = KeyEvent {
+            code: raw_event.code,
+            state,
+            modifiers: self.modifiers(),
+        };
+        Some((event, self.keyboard.process_keyevent(raw_event)))
+    }
+
+    /// Feeds one scancode and returns what the shell should see of it, if anything.
+    pub fn shell_event(&mut self, scancode: u8) -> Option<InputEvent> {
+        let (event, key) = self.feed(scancode)?;
+
+        if let Some(key) = key {
+            publish_key(key);
+        }
+
+        match key {
+            // Delete decodes to DEL, which the shell would take for a backspace
+            Some(DecodedKey::Unicode(_)) if event.code == KeyCode::Delete => Some(InputEvent::Key(event)),
+            // Ctrl combinations are shortcuts, not text
+            Some(DecodedKey::Unicode(character)) if !event.modifiers.ctrl => Some(InputEvent::Char(character)),
+            _ if event.state == KeyState::Down => Some(InputEvent::Key(event)),
+            _ => None,
+        }
+    }
+
+    pub fn decode(&mut self, scancode: u8) -> Option<DecodedKey> {
+        self.feed(scancode).and_then(|(_, key)| key)
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.lshift || self.rshift,
+            ctrl: self.lctrl || self.rctrl,
+            alt: self.lalt || self.ralt,
+            caps_lock: self.caps_lock,
+        }
+    }
+
+    fn track_modifier(&mut self, code: KeyCode, state: KeyState) {
+        let down = state == KeyState::Down;
+        match code {
+            KeyCode::LShift => self.lshift = down,
+            KeyCode::RShift => self.rshift = down,
+            KeyCode::LControl => self.lctrl = down,
+            KeyCode::RControl => self.rctrl = down,
+            KeyCode::LAlt => self.lalt = down,
+            KeyCode::RAltGr => self.ralt = down,
+            KeyCode::CapsLock if down => self.caps_lock = !self.caps_lock,
+            _ => {}
+        }
+    }
+}
+
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
@@ -83,4 +288,226 @@ impl Stream for ScancodeStream {
             None => Poll::Pending,
         }
     }
-}
\ No newline at end of file
+}
+
+fn publish_key(key: DecodedKey) {
+    if !KEY_STREAM_OPEN.load(Ordering::Acquire) {
+        return;
+    }
+
+    if KEY_QUEUE.push(key).is_err() {
+        serial_println!("WARNING: key stream full; dropping key");
+    } else {
+        KEY_WAKER.wake();
+    }
+}
+
+/// Stream of decoded keys, for tasks that want keyboard input next to the shell.
+///
+/// The shell keeps getting its input either way; this only sees keys decoded while the stream
+/// is open. Only one `KeyStream` can exist at a time.
+pub struct KeyStream {
+    _private: (),
+}
+
+impl KeyStream {
+    pub fn new() -> Self {
+        let already_open = KEY_STREAM_OPEN.swap(true, Ordering::AcqRel);
+        assert!(!already_open, "only one KeyStream can be open at a time");
+        KeyStream { _private: () }
+    }
+}
+
+impl Drop for KeyStream {
+    fn drop(&mut self) {
+        KEY_STREAM_OPEN.store(false, Ordering::Release);
+        while KEY_QUEUE.pop().is_some() {}
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        if let Some(key) = KEY_QUEUE.pop() {
+            return Poll::Ready(Some(key));
+        }
+
+        KEY_WAKER.register(&cx.waker());
+        match KEY_QUEUE.pop() {
+            Some(key) => {
+                KEY_WAKER.take();
+                Poll::Ready(Some(key))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_key_stream_only_queues_while_open() {
+    use futures_util::task::noop_waker_ref;
+
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    publish_key(DecodedKey::Unicode('a'));
+    let mut stream = KeyStream::new();
+    publish_key(DecodedKey::Unicode('b'));
+
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut context),
+               Poll::Ready(Some(DecodedKey::Unicode('b'))));
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Pending);
+
+    drop(stream);
+    publish_key(DecodedKey::Unicode('c'));
+    assert!(KEY_QUEUE.is_empty());
+}
+
+#[test_case]
+fn test_key_event_tracks_shift() {
+    let mut keyboard = KeyboardInput::new();
+
+    // Set 1: 0x2A/0xAA is left shift press/release, 0x1E is 'A'
+    let (shift, _) = keyboard.feed(0x2A).expect("shift press should complete an event");
+    assert_eq!(shift.code, KeyCode::LShift);
+    assert_eq!(shift.state, KeyState::Down);
+    assert!(shift.modifiers.shift);
+
+    let (a, key) = keyboard.feed(0x1E).expect("'A' press should complete an event");
+    assert_eq!(a.code, KeyCode::A);
+    assert!(a.modifiers.shift);
+    assert_eq!(key, Some(DecodedKey::Unicode('A')));
+
+    keyboard.feed(0xAA);
+    assert!(!keyboard.modifiers().shift);
+}
+
+#[test_case]
+fn test_extended_prefix_selects_navigation_keys() {
+    let mut keyboard = KeyboardInput::new();
+
+    // Without the 0xE0 prefix 0x4B is keypad 4, which types a digit while Num Lock is on
+    let (keypad, key) = keyboard.feed(0x4B).expect("keypad press should complete an event");
+    assert_eq!(keypad.code, KeyCode::Numpad4);
+    assert_eq!(key, Some(DecodedKey::Unicode('4')));
+    keyboard.feed(0xCB);
+
+    // The prefix is consumed without an event of its own
+    for (code, expected) in [(0x4B, KeyCode::ArrowLeft), (0x4D, KeyCode::ArrowRight),
+                             (0x48, KeyCode::ArrowUp), (0x50, KeyCode::ArrowDown),
+                             (0x47, KeyCode::Home), (0x4F, KeyCode::End)] {
+        assert!(keyboard.feed(0xE0).is_none());
+        let (event, key) = keyboard.feed(code).expect("extended press should complete an event");
+        assert_eq!(event.code, expected);
+        assert_eq!(event.state, KeyState::Down);
+        assert!(!matches!(key, Some(DecodedKey::Unicode(_))));
+
+        assert!(keyboard.feed(0xE0).is_none());
+        let (event, _) = keyboard.feed(code | 0x80).expect("extended release should complete an event");
+        assert_eq!(event.state, KeyState::Up);
+    }
+}
+
+#[test_case]
+fn test_key_event_tracks_ctrl_and_alt() {
+    let mut keyboard = KeyboardInput::new();
+
+    // Right Ctrl is the extended variant of left Ctrl's 0x1D; 0x38 is left Alt
+    keyboard.feed(0xE0);
+    keyboard.feed(0x1D);
+    keyboard.feed(0x38);
+    assert!(keyboard.modifiers().ctrl);
+    assert!(keyboard.modifiers().alt);
+
+    keyboard.feed(0xE0);
+    keyboard.feed(0x9D);
+    keyboard.feed(0xB8);
+    assert!(!keyboard.modifiers().ctrl);
+    assert!(!keyboard.modifiers().alt);
+}
+
+#[test_case]
+fn test_shell_events_separate_text_from_keys() {
+    let mut keyboard = KeyboardInput::with_layout(KeyLayout::Us);
+
+    assert_eq!(keyboard.shell_event(0x1E), Some(InputEvent::Char('a')));
+    assert_eq!(keyboard.shell_event(0x9E), None);
+
+    // Delete and Ctrl+C produce characters, but reach the shell as key presses
+    assert_eq!(keyboard.shell_event(0xE0), None);
+    assert!(matches!(keyboard.shell_event(0x53),
+                     Some(InputEvent::Key(KeyEvent { code: KeyCode::Delete, .. }))));
+    keyboard.shell_event(0xE0);
+    keyboard.shell_event(0xD3);
+
+    assert!(matches!(keyboard.shell_event(0x1D), Some(InputEvent::Key(_))));
+    match keyboard.shell_event(0x2E) {
+        Some(InputEvent::Key(event)) => {
+            assert_eq!(event.code, KeyCode::C);
+            assert!(event.modifiers.ctrl);
+        }
+        other => panic!("Ctrl+C reached the shell as {:?}", other),
+    }
+}
+
+#[test_case]
+fn test_german_layout_swaps_y_and_z() {
+    let mut keyboard = KeyboardInput::new();
+    keyboard.set_layout(KeyLayout::De);
+
+    // Scancodes name physical positions: 0x15 is US 'Y', 0x2C is US 'Z'
+    assert_eq!(keyboard.decode(0x15), Some(DecodedKey::Unicode('z')));
+    keyboard.decode(0x95);
+    assert_eq!(keyboard.decode(0x2C), Some(DecodedKey::Unicode('y')));
+    keyboard.decode(0xAC);
+}
+
+#[test_case]
+fn test_german_layout_altgr() {
+    let mut keyboard = KeyboardInput::new();
+    keyboard.set_layout(KeyLayout::De);
+
+    // AltGr is the extended right Alt, 0xE0 0x38; 0x10 is the Q key
+    keyboard.decode(0xE0);
+    keyboard.decode(0x38);
+    assert_eq!(keyboard.decode(0x10), Some(DecodedKey::Unicode('@')));
+    keyboard.decode(0x90);
+    keyboard.decode(0xE0);
+    keyboard.decode(0xB8);
+
+    assert_eq!(keyboard.decode(0x10), Some(DecodedKey::Unicode('q')));
+}
+
+#[test_case]
+fn test_uk_layout_shifted_digits() {
+    let mut keyboard = KeyboardInput::new();
+    keyboard.set_layout(KeyLayout::Uk);
+
+    keyboard.decode(0x2A);
+    assert_eq!(keyboard.decode(0x03), Some(DecodedKey::Unicode('"')));
+    keyboard.decode(0x83);
+    assert_eq!(keyboard.decode(0x04), Some(DecodedKey::Unicode('£')));
+    keyboard.decode(0x84);
+    keyboard.decode(0xAA);
+
+    keyboard.set_layout(KeyLayout::Us);
+    keyboard.decode(0x2A);
+    assert_eq!(keyboard.decode(0x03), Some(DecodedKey::Unicode('@')));
+}
+
+#[test_case]
+fn test_layout_switch_releases_modifiers() {
+    let mut keyboard = KeyboardInput::new();
+    keyboard.set_layout(KeyLayout::Us);
+
+    keyboard.feed(0x2A);
+    keyboard.feed(0x1D);
+    assert!(keyboard.modifiers().shift);
+
+    // The new decoder does not know shift is held, so neither may the modifiers
+    keyboard.set_layout(KeyLayout::Uk);
+    assert_eq!(keyboard.modifiers(), Modifiers::default());
+    let (event, key) = keyboard.feed(0x1E).expect("'a' press should complete an event");
+    assert!(!event.modifiers.shift);
+    assert_eq!(key, Some(DecodedKey::Unicode('a')));
+}
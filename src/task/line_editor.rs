@@ -0,0 +1,152 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const HISTORY_CAPACITY: usize = 64;
+
+/// Previously executed command lines, oldest first.
+pub struct History {
+    entries: Vec<String>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History { entries: Vec::new() }
+    }
+
+    /// Records `command`, skipping blank lines and repeats of the previous entry.
+    pub fn push(&mut self, command: &str) {
+        let command = command.trim();
+        if command.is_empty() || self.entries.last().map(String::as_str) == Some(command) {
+            return;
+        }
+
+        if self.entries.len() == HISTORY_CAPACITY {
+            self.entries.remove(0);
+        }
+        self.entries.push(String::from(command));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+
+    /// Index of the newest entry older than `before` that contains `query`.
+    pub fn search(&self, query: &str, before: usize) -> Option<usize> {
+        self.entries[..before.min(self.entries.len())]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+}
+
+/// Position while stepping through the history with the arrow keys.
+pub struct HistoryBrowser {
+    position: Option<usize>, // None while on the fresh, not yet executed line
+}
+
+impl HistoryBrowser {
+    pub fn new() -> Self {
+        HistoryBrowser { position: None }
+    }
+
+    /// Steps to the previous entry, staying on the oldest one once reached.
+    pub fn up<'a>(&mut self, history: &'a History) -> Option<&'a str> {
+        let position = match self.position {
+            _ if history.is_empty() => return None,
+            None => history.len() - 1,
+            Some(position) => position.saturating_sub(1),
+        };
+        self.position = Some(position);
+        history.get(position)
+    }
+
+    /// Steps to the next entry. Stepping past the newest returns to the fresh line, given as `""`.
+    pub fn down<'a>(&mut self, history: &'a History) -> &'a str {
+        match self.position {
+            Some(position) if position + 1 < history.len() => {
+                self.position = Some(position + 1);
+                history.get(position + 1).unwrap_or("")
+            }
+            _ => {
+                self.position = None;
+                ""
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.position = None;
+    }
+}
+
+/// State of an incremental reverse search (Ctrl+R) over the history.
+pub struct ReverseSearch {
+    query: String,
+    found: Option<usize>,
+    saved_input: String,
+}
+
+impl ReverseSearch {
+    /// Starts a search; `saved_input` is what the input line held, restored on cancel.
+    pub fn new(saved_input: String) -> Self {
+        ReverseSearch {
+            query: String::new(),
+            found: None,
+            saved_input,
+        }
+    }
+
+    /// Extends the query and searches again from the newest entry.
+    pub fn push_char(&mut self, character: char, history: &History) {
+        self.query.push(character);
+        self.found = history.search(&self.query, history.len());
+    }
+
+    pub fn pop_char(&mut self, history: &History) {
+        self.query.pop();
+        self.found = history.search(&self.query, history.len());
+    }
+
+    /// Moves to the next older match, staying on the current one if there is none.
+    pub fn search_older(&mut self, history: &History) {
+        let before = self.found.unwrap_or(history.len());
+        if let Some(index) = history.search(&self.query, before) {
+            self.found = Some(index);
+        }
+    }
+
+    pub fn matched<'a>(&self, history: &'a History) -> Option<&'a str> {
+        self.found.and_then(|index| history.get(index))
+    }
+
+    /// The line to show while searching, in the usual readline format.
+    pub fn display(&self, history: &History) -> String {
+        let label = if self.matched(history).is_some() || self.query.is_empty() {
+            "reverse-i-search"
+        } else {
+            "failed reverse-i-search"
+        };
+        format!("({}) `{}': {}", label, self.query, self.matched(history).unwrap_or(""))
+    }
+
+    /// Ends the search, giving back the input line as it was before.
+    pub fn cancel(self) -> String {
+        self.saved_input
+    }
+
+    /// Ends the search, giving the matched entry, or the original input if nothing matched.
+    pub fn accept(self, history: &History) -> String {
+        match self.matched(history) {
+            Some(entry) => String::from(entry),
+            None => self.saved_input,
+        }
+    }
+}
@@ -6,6 +6,12 @@ use core::sync::atomic::{AtomicU64, Ordering};
 pub mod simple_executor;
 pub mod keyboard;
 pub mod executor;
+pub mod shell;
+pub mod line_editor;
+pub mod timer;
+pub mod heartbeat;
+pub mod serial_input;
+pub mod caret;
 
 pub struct Task {
     id: TaskId,
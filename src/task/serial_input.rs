@@ -0,0 +1,58 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::input::{InputEvent, InputSource};
+use crate::serial;
+use crate::serial_print;
+use crate::task::timer::{sleep_ms, Sleep};
+
+const POLL_INTERVAL_MS: u64 = 10;
+
+/// Characters typed on the serial console, so commands typed over COM1 run exactly like
+/// keyboard input.
+///
+/// The UART is polled rather than driven by IRQ 4: the input queue allows one producer at a
+/// time, which holds as long as every source is forwarded by a task on the same executor. Typed
+/// characters are echoed back to the serial side, since the shell itself only draws on the screen.
+pub struct SerialSource {
+    last_was_cr: bool,
+    poll_delay: Option<Sleep>,
+}
+
+impl SerialSource {
+    pub fn new() -> Self {
+        SerialSource { last_was_cr: false, poll_delay: None }
+    }
+}
+
+impl InputSource for SerialSource {
+    fn poll_event(&mut self, cx: &mut Context) -> Poll<InputEvent> {
+        loop {
+            if let Some(delay) = self.poll_delay.as_mut() {
+                if Pin::new(delay).poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.poll_delay = None;
+            }
+
+            while let Some(byte) = serial::read_byte() {
+                // Terminals send "\r\n" or just "\r" for Enter; only run the command once
+                if byte == b'\n' && self.last_was_cr {
+                    self.last_was_cr = false;
+                    continue;
+                }
+                self.last_was_cr = byte == b'\r';
+
+                match byte {
+                    b'\r' | b'\n' => { serial_print!("\r\n"); }
+                    0x08 | 0x7F => { serial_print!("\u{8} \u{8}"); }
+                    byte => { serial_print!("{}", byte as char); }
+                }
+                return Poll::Ready(InputEvent::Char(byte as char));
+            }
+
+            self.poll_delay = Some(sleep_ms(POLL_INTERVAL_MS));
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use x86_64::instructions::interrupts;
+
+use crate::commands::{CommandAction, UnlockedCommand};
+use crate::filesystem::block::BlockError;
+use crate::filesystem::fat::{FatError, FatVolume};
+use crate::filesystem::nvme::{self, NvmeNamespace};
+use crate::input::{self, InputEvent, InputSource};
+use crate::task::keyboard::{KeyCode, KeyEvent};
+use crate::task::line_editor::{History, HistoryBrowser, ReverseSearch};
+use crate::vga_buffer::{Writer, WRITER};
+
+const FUNCTION_KEYS: [KeyCode; 12] = [
+    KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4, KeyCode::F5, KeyCode::F6,
+    KeyCode::F7, KeyCode::F8, KeyCode::F9, KeyCode::F10, KeyCode::F11, KeyCode::F12,
+];
+
+const LETTER_KEYS: [KeyCode; 26] = [
+    KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F, KeyCode::G,
+    KeyCode::H, KeyCode::I, KeyCode::J, KeyCode::K, KeyCode::L, KeyCode::M, KeyCode::N,
+    KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R, KeyCode::S, KeyCode::T, KeyCode::U,
+    KeyCode::V, KeyCode::W, KeyCode::X, KeyCode::Y, KeyCode::Z,
+];
+
+// Page Up/Down keep one line of the previous screen in view
+const SCROLL_PAGE_LINES: usize = 24;
+
+/// A key, optionally with Ctrl held, that can be bound to a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KeyBinding {
+    code: KeyCode,
+    ctrl: bool,
+}
+
+impl KeyBinding {
+    /// Parses `F1`..`F12` or `Ctrl+<letter>`, case-insensitively.
+    fn parse(spec: &str) -> Option<Self> {
+        let (ctrl, key) = match spec.get(..5) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("ctrl+") => (true, &spec[5..]),
+            _ => (false, spec),
+        };
+
+        let code = if ctrl {
+            let mut chars = key.chars();
+            match (chars.next(), chars.next()) {
+                (Some(letter), None) if letter.is_ascii_alphabetic() => {
+                    LETTER_KEYS[(letter.to_ascii_uppercase() as u8 - b'A') as usize]
+                }
+                _ => return None,
+            }
+        } else {
+            let number: usize = key.strip_prefix(['F', 'f'])?.parse().ok()?;
+            *FUNCTION_KEYS.get(number.checked_sub(1)?)?
+        };
+
+        Some(KeyBinding { code, ctrl })
+    }
+
+    fn matches(&self, event: &KeyEvent) -> bool {
+        self.code == event.code && self.ctrl == event.modifiers.ctrl
+    }
+}
+
+/// Shortcut keys and the command lines they run.
+struct Bindings {
+    entries: Vec<(KeyBinding, String)>,
+}
+
+impl Bindings {
+    fn with_defaults() -> Self {
+        let mut bindings = Bindings { entries: Vec::new() };
+        bindings.set(KeyBinding { code: KeyCode::L, ctrl: true }, "clear".to_string());
+        bindings.set(KeyBinding { code: KeyCode::F1, ctrl: false }, "help".to_string());
+        bindings
+    }
+
+    fn set(&mut self, key: KeyBinding, command: String) {
+        self.remove(key);
+        self.entries.push((key, command));
+    }
+
+    fn remove(&mut self, key: KeyBinding) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(bound, _)| *bound != key);
+        self.entries.len() != before
+    }
+
+    fn lookup(&self, event: &KeyEvent) -> Option<&str> {
+        self.entries.iter()
+            .find(|(key, _)| key.matches(event))
+            .map(|(_, command)| command.as_str())
+    }
+
+    /// Handles `bind`, `bind <key>` (unbind) and `bind <key> <command...>`.
+    fn command(&mut self, writer: &mut Writer, arguments: &str) {
+        let mut parts = arguments.trim().splitn(2, ' ');
+        let spec = parts.next().unwrap_or("");
+        let command = parts.next().map(str::trim).unwrap_or("");
+
+        if spec.is_empty() {
+            writer.write_string("\n");
+            for (key, command) in &self.entries {
+                let ctrl = if key.ctrl { "Ctrl+" } else { "" };
+                write!(writer, "{}{:?} -> {}\n", ctrl, key.code, command).unwrap();
+            }
+            return;
+        }
+
+        let key = match KeyBinding::parse(spec) {
+            Some(key) => key,
+            None => {
+                writer.write_string("\nUsage: bind [F1-F12|Ctrl+<letter>] [command]\n");
+                return;
+            }
+        };
+
+        if command.is_empty() {
+            if self.remove(key) {
+                write!(writer, "\nUnbound {}\n", spec).unwrap();
+            } else {
+                write!(writer, "\n{} is not bound\n", spec).unwrap();
+            }
+        } else {
+            self.set(key, command.to_string());
+            write!(writer, "\nBound {} to '{}'\n", spec, command).unwrap();
+        }
+    }
+}
+
+/// Reads events from `input`, echoes typed characters and runs each completed command
+/// line. Bound shortcut keys run their command when nothing has been typed yet, the up and down
+/// arrows recall earlier commands and Ctrl+R searches back through the history. Left, right, Home
+/// and End move the cursor within the line, and Delete removes the character under it. Page Up
+/// and Page Down scroll through earlier output until something is typed.
+///
+/// Input devices only queue events, so they keep being processed while a command is
+/// running, and a command can `.await` without stalling the keyboard.
+pub async fn run_shell(mut input: impl InputSource) {
+    let mut bindings = Bindings::with_defaults();
+    let mut history = History::new();
+    let mut browser = HistoryBrowser::new();
+    let mut search: Option<ReverseSearch> = None;
+    show_prompt();
+
+    loop {
+        let event = input::next_event(&mut input).await;
+        if let Some(active) = search.as_mut() {
+            match event {
+                InputEvent::Char('\n') | InputEvent::Char('\r') => {
+                    let line = search.take().unwrap().accept(&history);
+                    with_writer(|writer| writer.set_input(&line));
+                    browser.reset();
+                    run_input(&mut bindings, &mut history).await;
+                }
+                InputEvent::Char('\u{1b}') => {
+                    let line = search.take().unwrap().cancel();
+                    with_writer(|writer| writer.set_input(&line));
+                }
+                InputEvent::Char('\u{8}') | InputEvent::Char('\u{7f}') => active.pop_char(&history),
+                InputEvent::Char(character) => active.push_char(character, &history),
+                InputEvent::Key(key) if is_reverse_search(&key) => active.search_older(&history),
+                InputEvent::Key(_) => {}
+            }
+
+            if let Some(active) = search.as_ref() {
+                let display = active.display(&history);
+                with_writer(|writer| writer.show_input_line(&display));
+            }
+            continue;
+        }
+
+        match event {
+            InputEvent::Char('\n') | InputEvent::Char('\r') => {
+                browser.reset();
+                run_input(&mut bindings, &mut history).await;
+            }
+            InputEvent::Char(character) => input::handle_char(character),
+            InputEvent::Key(key) if is_reverse_search(&key) => {
+                let active = ReverseSearch::new(with_writer(|writer| String::from(writer.input())));
+                let display = active.display(&history);
+                with_writer(|writer| writer.show_input_line(&display));
+                search = Some(active);
+            }
+            InputEvent::Key(key) if key.code == KeyCode::ArrowUp => {
+                if let Some(entry) = browser.up(&history) {
+                    with_writer(|writer| writer.set_input(entry));
+                }
+            }
+            InputEvent::Key(key) if key.code == KeyCode::ArrowDown => {
+                let entry = browser.down(&history);
+                with_writer(|writer| writer.set_input(entry));
+            }
+            InputEvent::Key(key) if key.code == KeyCode::ArrowLeft => {
+                with_writer(|writer| writer.set_input_cursor(writer.input_cursor().saturating_sub(1)));
+            }
+            InputEvent::Key(key) if key.code == KeyCode::ArrowRight => {
+                with_writer(|writer| writer.set_input_cursor(writer.input_cursor() + 1));
+            }
+            InputEvent::Key(key) if key.code == KeyCode::Home => {
+                with_writer(|writer| writer.set_input_cursor(0));
+            }
+            InputEvent::Key(key) if key.code == KeyCode::End => {
+                with_writer(|writer| writer.set_input_cursor(usize::MAX));
+            }
+            InputEvent::Key(key) if key.code == KeyCode::PageUp => {
+                with_writer(|writer| writer.scroll_up(SCROLL_PAGE_LINES));
+            }
+            InputEvent::Key(key) if key.code == KeyCode::PageDown => {
+                with_writer(|writer| writer.scroll_down(SCROLL_PAGE_LINES));
+            }
+            InputEvent::Key(key) if key.code == KeyCode::Delete => {
+                with_writer(|writer| writer.delete_forward());
+            }
+            InputEvent::Key(key) => {
+                let command = match bindings.lookup(&key) {
+                    Some(command) if !with_writer(|writer| writer.has_input()) => command.to_string(),
+                    _ => continue,
+                };
+
+                // Echo the command as if it had been typed, then run it
+                with_writer(|writer| writer.write_string(&command));
+                browser.reset();
+                run_input(&mut bindings, &mut history).await;
+            }
+        }
+    }
+}
+
+fn is_reverse_search(key: &KeyEvent) -> bool {
+    key.code == KeyCode::R && key.modifiers.ctrl
+}
+
+/// Runs the typed command line, records it in the history and shows a fresh prompt.
+async fn run_input(bindings: &mut Bindings, history: &mut History) {
+    let command = with_writer(|writer| writer.take_input());
+    history.push(&command);
+    execute(bindings, command).await;
+    show_prompt();
+}
+
+async fn execute(bindings: &mut Bindings, command: String) {
+    let action = with_writer(|writer| writer.execute_command(&command));
+
+    match action {
+        CommandAction::RunUnlocked(command) => run_unlocked(command),
+        CommandAction::Bind(arguments) => with_writer(|writer| {
+            // Bindings are shell state, so `dispatch` leaves `bind` to the shell
+            bindings.command(writer, arguments);
+            writer.write_byte(b'\n');
+        }),
+        _ => with_writer(|writer| writer.write_byte(b'\n')),
+    }
+}
+
+fn run_unlocked(command: UnlockedCommand) {
+    match command {
+        UnlockedCommand::Breakpoint => breakpoint(),
+        UnlockedCommand::ReadBlock(lba) => read_block(lba),
+        UnlockedCommand::WriteBlock(lba) => write_block(lba),
+        UnlockedCommand::ListFiles => list_files(),
+        UnlockedCommand::CatFile(name) => cat_file(name),
+    }
+}
+
+/// Executes `int3` and reports back once the handler has returned.
+///
+/// This runs without the writer lock held, because the breakpoint handler prints to the screen
+/// itself and would otherwise spin on the lock forever.
+fn breakpoint() {
+    with_writer(|writer| writer.write_string("\nTriggering a breakpoint exception (int3)\n"));
+    x86_64::instructions::interrupts::int3();
+    with_writer(|writer| writer.write_string("Breakpoint handler returned, execution continues\n\n"));
+}
+
+/// Reads one block from the default NVMe namespace and dumps it to the screen.
+///
+/// Like `breakpoint`, this runs without the writer lock: the read polls the timer for its
+/// completion, and ticks do not advance while the lock holds interrupts off.
+fn read_block(lba: u64) {
+    let nsid = match nvme::resolve_namespace(None) {
+        Ok(nsid) => nsid,
+        Err(e) => {
+            with_writer(|writer| { let _ = write!(writer, "\n{}\n", e); });
+            return;
+        }
+    };
+
+    let mut block = vec![0u8; nvme::block_size(nsid).unwrap_or(512)];
+    let result = nvme::read_blocks(nsid, lba, 1, &mut block);
+
+    with_writer(|writer| {
+        match result {
+            Ok(()) => {
+                let _ = write!(writer, "\nNamespace {} LBA {}:\n", nsid, lba);
+                for (i, row) in block.chunks(16).enumerate() {
+                    let _ = write!(writer, "{:04x}:", i * 16);
+                    for byte in row {
+                        let _ = write!(writer, " {:02x}", byte);
+                    }
+                    writer.write_byte(b'\n');
+                }
+            }
+            Err(e) => {
+                let _ = write!(writer, "\nRead failed: {}\n", e);
+            }
+        }
+    });
+}
+
+/// Writes a test pattern to one block of the default NVMe namespace, flushes it and reads it
+/// back to check the round trip. Runs without the writer lock for the same reason as
+/// `read_block`.
+fn write_block(lba: u64) {
+    let nsid = match nvme::resolve_namespace(None) {
+        Ok(nsid) => nsid,
+        Err(e) => {
+            with_writer(|writer| { let _ = write!(writer, "\n{}\n", e); });
+            return;
+        }
+    };
+
+    // Mixes in the LBA, so data left over from a write to another block does not match
+    let block_size = nvme::block_size(nsid).unwrap_or(512);
+    let pattern: Vec<u8> = (0..block_size).map(|i| (i as u8).wrapping_mul(31) ^ lba as u8).collect();
+    let mut readback = vec![0u8; block_size];
+
+    let result = nvme::write_blocks(nsid, lba, 1, &pattern)
+        .and_then(|()| nvme::flush(nsid))
+        .and_then(|()| nvme::read_blocks(nsid, lba, 1, &mut readback));
+
+    with_writer(|writer| {
+        match result {
+            Ok(()) => match pattern.iter().zip(&readback).position(|(written, read)| written != read) {
+                None => {
+                    let _ = write!(writer, "\nWrote and verified {} bytes at namespace {} LBA {}\n", block_size, nsid, lba);
+                }
+                Some(offset) => {
+                    let _ = write!(writer, "\nRead back differs at offset {:#x}: wrote {:02x}, read {:02x}\n",
+                                   offset, pattern[offset], readback[offset]);
+                }
+            },
+            Err(e) => {
+                let _ = write!(writer, "\nWrite failed: {}\n", e);
+            }
+        }
+    });
+}
+
+/// Opens the FAT volume on the default NVMe namespace. Like the other disk commands, callers
+/// must not hold the writer lock.
+fn open_volume() -> Result<FatVolume<NvmeNamespace>, FatError> {
+    let namespace = NvmeNamespace::open_default()
+        .ok_or(FatError::Block(BlockError::Device("no identified NVMe namespace")))?;
+    FatVolume::open(namespace)
+}
+
+/// Lists the root directory of the FAT volume on the NVMe disk.
+fn list_files() {
+    let result = open_volume().and_then(|mut volume| volume.root_dir());
+
+    with_writer(|writer| {
+        match result {
+            Ok(entries) => {
+                writer.write_byte(b'\n');
+                for entry in &entries {
+                    if entry.is_dir {
+                        let _ = write!(writer, "{:<12}  <DIR>\n", entry.name);
+                    } else {
+                        let _ = write!(writer, "{:<12}  {}\n", entry.name, entry.size);
+                    }
+                }
+                let _ = write!(writer, "{} entries\n", entries.len());
+            }
+            Err(e) => {
+                let _ = write!(writer, "\nls failed: {}\n", e);
+            }
+        }
+    });
+}
+
+/// Prints a file from the root directory of the FAT volume on the NVMe disk.
+fn cat_file(name: &str) {
+    let result = open_volume().and_then(|mut volume| volume.read_file(name));
+
+    with_writer(|writer| {
+        match result {
+            Ok(contents) => {
+                writer.write_byte(b'\n');
+                // Same substitution as write_string: anything else shows as a block
+                for &byte in &contents {
+                    match byte {
+                        b'\r' => {}
+                        0x20..=0x7e | b'\n' | b'\t' => writer.write_byte(byte),
+                        _ => writer.write_byte(0xfe),
+                    }
+                }
+                if contents.last() != Some(&b'\n') {
+                    writer.write_byte(b'\n');
+                }
+            }
+            Err(e) => {
+                let _ = write!(writer, "\ncat: {}: {}\n", name, e);
+            }
+        }
+    });
+}
+
+fn show_prompt() {
+    with_writer(|writer| writer.toggle_prompt(true));
+}
+
+fn with_writer<R>(f: impl FnOnce(&mut Writer) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut WRITER.lock()))
+}
+
+#[test_case]
+fn test_parse_key_binding() {
+    assert_eq!(KeyBinding::parse("F1"), Some(KeyBinding { code: KeyCode::F1, ctrl: false }));
+    assert_eq!(KeyBinding::parse("f12"), Some(KeyBinding { code: KeyCode::F12, ctrl: false }));
+    assert_eq!(KeyBinding::parse("Ctrl+L"), Some(KeyBinding { code: KeyCode::L, ctrl: true }));
+    assert_eq!(KeyBinding::parse("ctrl+r"), Some(KeyBinding { code: KeyCode::R, ctrl: true }));
+    assert_eq!(KeyBinding::parse("F13"), None);
+    assert_eq!(KeyBinding::parse("F0"), None);
+    assert_eq!(KeyBinding::parse("Ctrl+"), None);
+    assert_eq!(KeyBinding::parse("x"), None);
+}
@@ -0,0 +1,59 @@
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use spin::Mutex;
+
+use crate::hardware::pit::{ms_to_ticks, timer_ticks};
+use crate::sync::with_locked_irqsafe;
+
+// Sleeping tasks, keyed by the tick they want to be woken at. Drained by the timer interrupt.
+static SLEEPERS: Mutex<Vec<(u64, Waker)>> = Mutex::new(Vec::new());
+
+/// Future that resolves once the timer tick counter reaches `deadline`.
+pub struct Sleep {
+    deadline: u64,
+}
+
+/// Yields to the executor for at least `ms` milliseconds, rounded up to whole timer ticks.
+pub fn sleep_ms(ms: u64) -> Sleep {
+    Sleep { deadline: timer_ticks() + ms_to_ticks(ms) }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let deadline = self.deadline;
+
+        // Check and register under the same lock, so a tick in between cannot be missed
+        with_locked_irqsafe(&SLEEPERS, |sleepers| {
+            if timer_ticks() >= deadline {
+                return Poll::Ready(());
+            }
+
+            // One entry per task: a task racing two sleeps must be woken for the earlier one, and
+            // re-registers the later one when it polls it again
+            match sleepers.iter_mut().find(|(_, waker)| waker.will_wake(cx.waker())) {
+                Some(entry) => entry.0 = entry.0.min(deadline),
+                None => sleepers.push((deadline, cx.waker().clone())),
+            }
+            Poll::Pending
+        })
+    }
+}
+
+/// Wakes every sleeper whose deadline has passed. Called from the timer interrupt handler.
+pub fn wake_expired(now: u64) {
+    let mut sleepers = SLEEPERS.lock();
+    let mut i = 0;
+    while i < sleepers.len() {
+        if sleepers[i].0 <= now {
+            let (_, waker) = sleepers.swap_remove(i);
+            waker.wake();
+        } else {
+            i += 1;
+        }
+    }
+}
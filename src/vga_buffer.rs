@@ -1,12 +1,11 @@
 use core::fmt;
-use alloc::string::{String, ToString};
-use alloc::vec::Vec;
+use alloc::string::String;
 
 use spin::Mutex;
 use lazy_static::lazy_static;
 use volatile::Volatile;
 
-use crate::{hardware};
+use crate::commands::{self, CommandAction};
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -30,6 +29,41 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    pub fn from_name(name: &str) -> Option<Color> {
+        let color = match name {
+            "black" => Color::Black,
+            "blue" => Color::Blue,
+            "green" => Color::Green,
+            "cyan" => Color::Cyan,
+            "red" => Color::Red,
+            "magenta" => Color::Magenta,
+            "brown" => Color::Brown,
+            "lightgray" => Color::LightGray,
+            "darkgray" => Color::DarkGray,
+            "lightblue" => Color::LightBlue,
+            "lightgreen" => Color::LightGreen,
+            "lightcyan" => Color::LightCyan,
+            "lightred" => Color::LightRed,
+            "pink" => Color::Pink,
+            "yellow" => Color::Yellow,
+            "white" => Color::White,
+            _ => return None,
+        };
+        Some(color)
+    }
+}
+
+// ANSI color numbers 0-7 in VGA terms, normal and bright
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black, Color::Red, Color::Green, Color::Brown,
+    Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray,
+];
+const ANSI_BRIGHT_COLORS: [Color; 8] = [
+    Color::DarkGray, Color::LightRed, Color::LightGreen, Color::Yellow,
+    Color::LightBlue, Color::Pink, Color::LightCyan, Color::White,
+];
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 struct ColorCode(u8);
@@ -38,6 +72,39 @@ impl ColorCode {
     fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    fn with_foreground(self, foreground: Color) -> ColorCode {
+        ColorCode((self.0 & 0xF0) | foreground as u8)
+    }
+
+    fn with_background(self, background: Color) -> ColorCode {
+        ColorCode((background as u8) << 4 | (self.0 & 0x0F))
+    }
+
+    fn foreground_of(self, other: ColorCode) -> ColorCode {
+        ColorCode((self.0 & 0xF0) | (other.0 & 0x0F))
+    }
+
+    fn background_of(self, other: ColorCode) -> ColorCode {
+        ColorCode((other.0 & 0xF0) | (self.0 & 0x0F))
+    }
+}
+
+const ANSI_MAX_PARAMS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    Ground,
+    Escape, // Saw ESC
+    Csi,    // Saw ESC [, collecting parameters
+}
+
+/// Progress through an ANSI escape sequence. Kept in the writer, so a sequence split across
+/// two `write_string` calls is still recognised instead of printed literally.
+struct AnsiParser {
+    state: AnsiState,
+    params: [u16; ANSI_MAX_PARAMS],
+    count: usize,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -49,60 +116,143 @@ struct ScreenChar {
 
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
+const TAB_WIDTH: usize = 4;
 
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+const SCROLLBACK_LINES: usize = 500;
+const BLANK_ROW: [ScreenChar; BUFFER_WIDTH] = [ScreenChar { ascii_character: b' ', color_code: ColorCode(0) }; BUFFER_WIDTH];
+
+// Kept out of `Writer`, which lazy_static builds on the stack before moving it into place.
+// Only touched while WRITER is held.
+static SCROLLBACK: Mutex<Scrollback> = Mutex::new(Scrollback::new());
+
+/// Rows that scrolled off the top of the screen, oldest first, plus a copy of the screen while
+/// an earlier part is shown in its place.
+struct Scrollback {
+    lines: [[ScreenChar; BUFFER_WIDTH]; SCROLLBACK_LINES],
+    next: usize, // Slot the next line goes into
+    len: usize,
+    live: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        Scrollback {
+            lines: [BLANK_ROW; SCROLLBACK_LINES],
+            next: 0,
+            len: 0,
+            live: [BLANK_ROW; BUFFER_HEIGHT],
+        }
+    }
+
+    /// Keeps `row`, dropping the oldest line once the buffer is full.
+    fn push(&mut self, row: [ScreenChar; BUFFER_WIDTH]) {
+        self.lines[self.next] = row;
+        self.next = (self.next + 1) % SCROLLBACK_LINES;
+        self.len = (self.len + 1).min(SCROLLBACK_LINES);
+    }
+
+    /// Line `index` of the saved lines followed by the live screen, counting from the oldest.
+    fn row(&self, index: usize) -> &[ScreenChar; BUFFER_WIDTH] {
+        if index < self.len {
+            &self.lines[(self.next + SCROLLBACK_LINES - self.len + index) % SCROLLBACK_LINES]
+        } else {
+            &self.live[index - self.len]
+        }
+    }
+}
+
+/// Longest prompt `set_prompt` keeps; the rest of the row is left for input.
+pub const PROMPT_MAX_LEN: usize = 32;
+
 pub struct Writer {
     prompt_position: usize,
+    prompt: [u8; PROMPT_MAX_LEN],
+    prompt_len: usize,
+    prompt_color: ColorCode,
     cursor_position: usize,
     input_buffer: String,
     color_code: ColorCode,
+    default_color_code: ColorCode,
+    ansi: AnsiParser,
     buffer: &'static mut Buffer,
     user_input_mode: bool,
+    caret: Option<ScreenChar>, // Cell under the caret while the underscore is drawn
+    scroll_offset: usize, // Lines scrolled back from the live screen; 0 while showing it
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        prompt_position: 1,
-        cursor_position: 3,
-        input_buffer: String::new(),
-        color_code: ColorCode::new(Color::Red, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-        user_input_mode: false,
+    pub static ref WRITER: Mutex<Writer> = Mutex::new({
+        let mut writer = Writer {
+            prompt_position: 1,
+            prompt: [0; PROMPT_MAX_LEN],
+            prompt_len: 0,
+            prompt_color: ColorCode::new(Color::Red, Color::Black),
+            cursor_position: 0,
+            input_buffer: String::new(),
+            color_code: ColorCode::new(Color::Red, Color::Black),
+            default_color_code: ColorCode::new(Color::Red, Color::Black),
+            ansi: AnsiParser { state: AnsiState::Ground, params: [0; ANSI_MAX_PARAMS], count: 0 },
+            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+            user_input_mode: false,
+            caret: None,
+            scroll_offset: 0,
+        };
+        writer.set_prompt(">");
+        writer.cursor_position = writer.input_start();
+        writer
     });
 }
 
 impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
+        self.show_live();
+        self.hide_caret();
         match byte {
             b'\n' => {
-                if self.user_input_mode {
-                    self.user_input_mode = false;
-                    self.execute_command();
-                }
                 self.new_line();
             }
+            b'\t' => {
+                // Pad to the next tab stop, but never wrap onto a new line for it
+                let spaces = TAB_WIDTH - (self.cursor_position % TAB_WIDTH);
+                for _ in 0..spaces {
+                    if self.cursor_position >= BUFFER_WIDTH {
+                        break;
+                    }
+                    self.write_byte(b' ');
+                }
+            }
             b'\x08' => {
-                if self.cursor_position > self.prompt_position + 2 {
+                if self.cursor_position > self.input_start() {
                     self.move_cursor_left();
-                    let row = BUFFER_HEIGHT - 1;
-                    let col = self.cursor_position;
-
-                    // Wis het karakter van het scherm door een spatie te schrijven
-                    self.buffer.chars[row][col].write(ScreenChar {
-                        ascii_character: b' ',
-                        color_code: self.color_code,
-                    });
-
-                    // Verwijder het laatste karakter van de invoerbuffer, indien we in gebruikersmodus zijn
-                    if self.user_input_mode && !self.input_buffer.is_empty() {
-                        self.input_buffer.pop();
+
+                    if self.user_input_mode {
+                        // Shift the rest of the input left over the removed character
+                        self.delete_forward();
+                    } else {
+                        // Wis het karakter van het scherm door een spatie te schrijven
+                        self.buffer.chars[BUFFER_HEIGHT - 1][self.cursor_position].write(ScreenChar {
+                            ascii_character: b' ',
+                            color_code: self.color_code,
+                        });
                     }
                 }
             }
+            byte if self.user_input_mode => {
+                // The input stays on one row, so a full row takes no more characters
+                if self.input_start() + self.input_len() >= BUFFER_WIDTH {
+                    return;
+                }
+
+                let index = self.input_byte_index(self.input_cursor());
+                self.input_buffer.insert(index, byte as char);
+                self.cursor_position += 1;
+                self.redraw_input();
+            }
             byte => {
 
                 if self.cursor_position >= BUFFER_WIDTH {
@@ -118,11 +268,6 @@ impl Writer {
                     color_code,
                 });
 
-                // Alleen toevoegen aan de input buffer als we in de gebruikersinvoer-modus zijn
-                if self.user_input_mode {
-                    self.input_buffer.push(byte as char);
-                }
-
                 self.cursor_position += 1;
             }
         }
@@ -131,15 +276,66 @@ impl Writer {
 
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
-            match byte {
-                // ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+            match self.ansi.state {
+                AnsiState::Ground => match byte {
+                    0x1b => self.ansi.state = AnsiState::Escape,
+                    // ASCII byte or newline
+                    0x20..=0x7e | b'\n' | b'\t' => self.write_byte(byte),
+                    _ => self.write_byte(0xfe),
+                },
+                AnsiState::Escape => {
+                    if byte == b'[' {
+                        self.ansi.state = AnsiState::Csi;
+                        self.ansi.params = [0; ANSI_MAX_PARAMS];
+                        self.ansi.count = 0;
+                    } else {
+                        // Only CSI sequences are supported, drop anything else
+                        self.ansi.state = AnsiState::Ground;
+                    }
+                }
+                AnsiState::Csi => match byte {
+                    b'0'..=b'9' => {
+                        let param = &mut self.ansi.params[self.ansi.count];
+                        *param = param.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                    }
+                    b';' => self.ansi.count = (self.ansi.count + 1).min(ANSI_MAX_PARAMS - 1),
+                    0x40..=0x7e => {
+                        if byte == b'm' {
+                            self.apply_sgr();
+                        }
+                        self.ansi.state = AnsiState::Ground;
+                    }
+                    _ => self.ansi.state = AnsiState::Ground,
+                },
             }
         }
     }
 
+    /// Applies a Select Graphic Rendition sequence (`ESC [ ... m`) to the current color.
+    fn apply_sgr(&mut self) {
+        for i in 0..=self.ansi.count {
+            let param = self.ansi.params[i] as usize;
+            self.color_code = match param {
+                0 => self.default_color_code,
+                30..=37 => self.color_code.with_foreground(ANSI_COLORS[param - 30]),
+                39 => self.color_code.foreground_of(self.default_color_code),
+                40..=47 => self.color_code.with_background(ANSI_COLORS[param - 40]),
+                49 => self.color_code.background_of(self.default_color_code),
+                90..=97 => self.color_code.with_foreground(ANSI_BRIGHT_COLORS[param - 90]),
+                100..=107 => self.color_code.with_background(ANSI_BRIGHT_COLORS[param - 100]),
+                _ => self.color_code,
+            };
+        }
+    }
+
+    /// Sets the default text color, used from now on and restored by `ESC [0m`.
+    pub fn set_color(&mut self, foreground: Color) {
+        self.default_color_code = self.default_color_code.with_foreground(foreground);
+        self.color_code = self.default_color_code;
+    }
+
     fn new_line(&mut self) {
+        SCROLLBACK.lock().push(self.read_row(0));
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
@@ -148,70 +344,254 @@ impl Writer {
         }
 
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.cursor_position = 3;
+        // Output lines up with the input column
+        self.cursor_position = self.input_start();
         self.input_buffer.clear();
     }
 
+    /// Draws the prompt, or blanks it, and starts taking input right after it.
     pub fn toggle_prompt(&mut self, visible: bool) {
+        self.show_live();
         let row = BUFFER_HEIGHT - 1;
-        let col = self.prompt_position;
 
-        let color_code = self.color_code;
-        let ascii_character = if visible { b'>' } else { b' ' };
-
-        self.buffer.chars[row][col].write(ScreenChar {
-            ascii_character,
-            color_code,
-        });
+        for i in 0..self.prompt_len {
+            let ascii_character = if visible { self.prompt[i] } else { b' ' };
+            self.buffer.chars[row][self.prompt_position + i].write(ScreenChar {
+                ascii_character,
+                color_code: self.prompt_color,
+            });
+        }
 
+        self.cursor_position = self.input_start();
         self.user_input_mode = true;
     }
 
+    /// Sets the prompt shown by `toggle_prompt` from now on, cut to `PROMPT_MAX_LEN` characters.
+    pub fn set_prompt(&mut self, prompt: &str) {
+        self.prompt_len = 0;
+        for character in prompt.chars().take(PROMPT_MAX_LEN) {
+            self.prompt[self.prompt_len] = match character {
+                ' '..='~' => character as u8,
+                _ => 0xfe,
+            };
+            self.prompt_len += 1;
+        }
+    }
+
+    /// Sets the color the prompt is drawn in, independent of the text color.
+    pub fn set_prompt_color(&mut self, foreground: Color) {
+        self.prompt_color = self.prompt_color.with_foreground(foreground);
+    }
+
+    /// First column of the input line after the prompt and the space that follows it.
+    fn input_start(&self) -> usize {
+        self.prompt_position + self.prompt_len + 1
+    }
+
+    /// Whether anything has been typed since the prompt was shown.
+    pub fn has_input(&self) -> bool {
+        !self.input_buffer.is_empty()
+    }
+
+    /// The command line typed so far.
+    pub fn input(&self) -> &str {
+        &self.input_buffer
+    }
+
+    /// Redraws the input line with `text`, without changing the typed input.
+    pub fn show_input_line(&mut self, text: &str) {
+        self.show_live();
+        self.hide_caret();
+        let row = BUFFER_HEIGHT - 1;
+        let start = self.input_start();
+
+        for col in start..BUFFER_WIDTH {
+            self.buffer.chars[row][col].write(ScreenChar {
+                ascii_character: b' ',
+                color_code: self.color_code,
+            });
+        }
+
+        // Stays on one line; whatever does not fit is cut off
+        let mut col = start;
+        for character in text.chars().take(BUFFER_WIDTH - start) {
+            let ascii_character = match character {
+                ' '..='~' => character as u8,
+                _ => 0xfe,
+            };
+            self.buffer.chars[row][col].write(ScreenChar {
+                ascii_character,
+                color_code: self.color_code,
+            });
+            col += 1;
+        }
+        self.cursor_position = col;
+    }
+
+    /// Replaces the typed input with `text` and shows it on the input line.
+    pub fn set_input(&mut self, text: &str) {
+        self.show_input_line(text);
+        self.input_buffer.clear();
+        self.input_buffer.push_str(text);
+    }
+
+    /// Ends user input mode and hands over the typed command line.
+    pub fn take_input(&mut self) -> String {
+        self.hide_caret();
+        // Output continues after the input, even if the cursor was moved back into it
+        self.cursor_position = (self.input_start() + self.input_len()).min(BUFFER_WIDTH);
+        self.user_input_mode = false;
+        core::mem::take(&mut self.input_buffer)
+    }
+
+    /// Where the cursor is within the typed input, in characters from its start.
+    pub fn input_cursor(&self) -> usize {
+        self.cursor_position.saturating_sub(self.input_start())
+    }
+
+    /// Moves the cursor to character `index` of the typed input, or to its end if the input is
+    /// shorter. Typed characters are inserted there.
+    pub fn set_input_cursor(&mut self, index: usize) {
+        self.hide_caret();
+        self.cursor_position = self.input_start() + index.min(self.input_len());
+    }
+
+    /// Removes the typed character under the cursor and shifts the rest of the input left.
+    pub fn delete_forward(&mut self) {
+        if !self.user_input_mode || self.input_cursor() >= self.input_len() {
+            return;
+        }
+
+        let index = self.input_byte_index(self.input_cursor());
+        self.input_buffer.remove(index);
+        self.redraw_input();
+    }
+
+    /// Number of characters typed, which is also the number of cells they take on screen.
+    fn input_len(&self) -> usize {
+        self.input_buffer.chars().count()
+    }
+
+    /// Byte offset in `input_buffer` of the character at `index`.
+    fn input_byte_index(&self, index: usize) -> usize {
+        self.input_buffer.char_indices()
+            .nth(index)
+            .map_or(self.input_buffer.len(), |(offset, _)| offset)
+    }
+
+    /// Shows `input_buffer` on the input line again, keeping the cursor where it is.
+    fn redraw_input(&mut self) {
+        let cursor = self.cursor_position;
+        let input = core::mem::take(&mut self.input_buffer);
+        self.show_input_line(&input);
+        self.input_buffer = input;
+        self.cursor_position = cursor;
+    }
+
     pub fn move_cursor_left(&mut self) {
-        if self.cursor_position > self.prompt_position {
+        self.hide_caret();
+        if self.cursor_position > self.input_start() {
             self.cursor_position -= 1;
         }
     }
-}
 
-impl Writer {
-    pub fn execute_command(&mut self) {
-        let command = self.input_buffer.trim().to_string();
-
-        let mut parts = command.split_whitespace();
-        let command_name = parts.next().unwrap_or("");
-        let arguments: Vec<&str> = parts.collect();
-
-        match command_name {
-            "help" => {
-                self.write_string("\n");
-                self.write_string("\nAvailable commands:\n");
-                self.write_string("help  - Show this help message\n");
-                self.write_string("clear - Clear the screen\n");
-                self.write_string("echo  - Echo the input text\n");
-            }
-            "clear" => {
-                self.clear_screen();
-            }
-            "echo" => {
-                self.write_string("\n");
-                for arg in arguments {
-                    self.write_string(arg);
-                    self.write_string(" ");
-                }
-                self.write_string("\n");
-            }
-            "scan" => {
-                hardware::pci::display_disks(self);
+    /// Draws or erases the underscore caret at the cursor; called twice a second for the blink.
+    /// The caret only shows while the prompt takes input, and any output erases it first.
+    pub fn blink_caret(&mut self) {
+        if self.caret.is_some() {
+            self.hide_caret();
+            return;
+        }
+        if !self.user_input_mode || self.scroll_offset > 0 || self.cursor_position >= BUFFER_WIDTH {
+            return;
+        }
+
+        let cell = &mut self.buffer.chars[BUFFER_HEIGHT - 1][self.cursor_position];
+        let under = cell.read();
+        cell.write(ScreenChar {
+            ascii_character: b'_',
+            color_code: self.color_code,
+        });
+        self.caret = Some(under);
+    }
+
+    /// Shows `lines` earlier lines of output, as far back as the scrollback goes.
+    pub fn scroll_up(&mut self, lines: usize) {
+        let mut scrollback = SCROLLBACK.lock();
+        if self.scroll_offset == 0 {
+            self.hide_caret();
+            for (row, line) in scrollback.live.iter_mut().enumerate() {
+                *line = self.read_row(row);
             }
-            _ => {
-                self.write_string("\nUnknown command: ");
-                self.write_string(&command);
-                self.write_string("\nType 'help' to see available commands.\n");
+        }
+
+        self.scroll_offset = (self.scroll_offset + lines).min(scrollback.len);
+        self.draw_scrollback(&scrollback);
+    }
+
+    /// Shows `lines` later lines of output, back to the live screen at the end.
+    pub fn scroll_down(&mut self, lines: usize) {
+        if self.scroll_offset == 0 {
+            return;
+        }
+
+        self.scroll_offset = self.scroll_offset.saturating_sub(lines);
+        let scrollback = SCROLLBACK.lock();
+        self.draw_scrollback(&scrollback);
+    }
+
+    /// Leaves the scrollback, if it is showing, so output goes to the live screen again.
+    fn show_live(&mut self) {
+        if self.scroll_offset > 0 {
+            self.scroll_offset = 0;
+            let scrollback = SCROLLBACK.lock();
+            self.draw_scrollback(&scrollback);
+        }
+    }
+
+    /// Draws the screen `scroll_offset` lines back; at 0 that is the saved live screen.
+    fn draw_scrollback(&mut self, scrollback: &Scrollback) {
+        let top = scrollback.len - self.scroll_offset;
+        for row in 0..BUFFER_HEIGHT {
+            let line = scrollback.row(top + row);
+            for (cell, character) in self.buffer.chars[row].iter_mut().zip(line) {
+                cell.write(*character);
             }
         }
+    }
 
-        self.input_buffer.clear();
+    fn read_row(&self, row: usize) -> [ScreenChar; BUFFER_WIDTH] {
+        let mut line = BLANK_ROW;
+        for (character, cell) in line.iter_mut().zip(&self.buffer.chars[row]) {
+            *character = cell.read();
+        }
+        line
+    }
+
+    /// Puts back the character the caret was drawn over, if it is showing.
+    fn hide_caret(&mut self) {
+        if let Some(under) = self.caret.take() {
+            self.buffer.chars[BUFFER_HEIGHT - 1][self.cursor_position].write(under);
+        }
+    }
+}
+
+impl Writer {
+    /// Runs `command` through the shared command set and applies its effect on this screen.
+    ///
+    /// Actions the screen cannot carry out itself, such as commands that must run without the
+    /// writer lock, are returned for the caller; anything else returns `CommandAction::None`.
+    pub fn execute_command<'a>(&mut self, command: &'a str) -> CommandAction<'a> {
+        match commands::dispatch(command, self) {
+            CommandAction::ClearScreen => self.clear_screen(),
+            CommandAction::SetColor(color) => self.set_color(color),
+            CommandAction::SetPrompt(prompt) => self.set_prompt(prompt),
+            CommandAction::SetPromptColor(color) => self.set_prompt_color(color),
+            action @ (CommandAction::RunUnlocked(_) | CommandAction::Bind(_)) => return action,
+            CommandAction::None => {}
+        }
+
+        CommandAction::None
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -225,10 +605,12 @@ impl Writer {
     }
 
     pub fn clear_screen(&mut self) {
+        self.show_live();
+        self.caret = None;
         for row in 0..BUFFER_HEIGHT {
             self.clear_row(row);
         }
-        self.cursor_position = self.prompt_position + 2; // Reset cursorpositie na de prompt
+        self.cursor_position = self.input_start(); // Reset cursorpositie na de prompt
     }
 }
 
@@ -253,6 +635,11 @@ macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
 }
 
+// Locking discipline: WRITER is only ever locked with interrupts disabled, as `_print` does, so
+// an interrupt handler that prints can never spin on a lock held by the code it interrupted.
+// Shell commands run with the lock held, so anything that can panic inside one ends up in
+// `print_panic`, which must not wait for the lock.
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
@@ -263,6 +650,27 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+/// Prints a panic message on screen, even if the panic happened while the writer was locked.
+///
+/// Nothing runs after a panic, so a lock that is taken is broken rather than waited for; the
+/// worst that can happen is a garbled line from the interrupted write.
+pub fn print_panic(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::disable();
+    let mut writer = match WRITER.try_lock() {
+        Some(writer) => writer,
+        None => {
+            unsafe { WRITER.force_unlock() };
+            WRITER.lock()
+        }
+    };
+    // Out of input mode, so the message is not copied into the input buffer, which allocates
+    writer.take_input();
+    let _ = writer.write_fmt(format_args!("\n{}\n", args));
+}
+
 // ----------------------------------------------------------------------------------------
 // Tests
 
@@ -281,8 +689,138 @@ fn test_println_output() {
         let mut writer = WRITER.lock();
         writeln!(writer, "\n{}", s).expect("writeln failed");
         for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i + writer.prompt_position + 2].read();
+            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i + writer.input_start()].read();
             assert_eq!(char::from(screen_char.ascii_character), c);
         }
     });
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_tab_expansion() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\na\tb").expect("writeln failed");
+        let row = BUFFER_HEIGHT - 2;
+        let start = writer.input_start();
+        let tab_stop = (start + 1 + TAB_WIDTH) / TAB_WIDTH * TAB_WIDTH;
+        assert_eq!(char::from(writer.buffer.chars[row][start].read().ascii_character), 'a');
+        assert_eq!(char::from(writer.buffer.chars[row][start + 1].read().ascii_character), ' ');
+        assert_eq!(char::from(writer.buffer.chars[row][tab_stop].read().ascii_character), 'b');
+    });
+}
+
+#[test_case]
+fn test_prompt_after_scrolling_output() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+
+        // Same sequence as the shell: command output, a trailing newline, then the prompt
+        for i in 0..BUFFER_HEIGHT + 5 {
+            writeln!(writer, "line {}", i).expect("writeln failed");
+        }
+        write!(writer, "last").expect("write failed");
+        writer.write_byte(b'\n');
+        writer.toggle_prompt(true);
+
+        let prompt = writer.buffer.chars[BUFFER_HEIGHT - 1][writer.prompt_position].read();
+        assert_eq!(char::from(prompt.ascii_character), '>');
+
+        let start = writer.input_start();
+        for (i, c) in "last".chars().enumerate() {
+            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][start + i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+
+        writer.take_input();
+    });
+}
+
+#[test_case]
+fn test_ansi_color_split_across_writes() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\n\x1b[3").expect("write failed");
+        write!(writer, "2mX\x1b[0mY").expect("write failed");
+
+        let row = BUFFER_HEIGHT - 1;
+        let start = writer.input_start();
+        let x = writer.buffer.chars[row][start].read();
+        let y = writer.buffer.chars[row][start + 1].read();
+        assert_eq!(char::from(x.ascii_character), 'X');
+        assert_eq!(x.color_code, writer.default_color_code.with_foreground(Color::Green));
+        assert_eq!(char::from(y.ascii_character), 'Y');
+        assert_eq!(y.color_code, writer.default_color_code);
+    });
+}
+
+#[test_case]
+fn test_scrollback_pages_and_returns_on_output() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for i in 0..BUFFER_HEIGHT + 10 {
+            write!(writer, "\nscroll {:02}", i).expect("write failed");
+        }
+        let live_top = writer.read_row(0);
+
+        // New lines start after the prompt columns
+        writer.scroll_up(5);
+        let row = writer.read_row(0);
+        let text: [u8; 9] = core::array::from_fn(|i| row[writer.input_start() + i].ascii_character);
+        assert_eq!(&text, b"scroll 05");
+
+        // Scrolling further than the scrollback goes stops at the oldest line
+        writer.scroll_up(SCROLLBACK_LINES + 10);
+        assert_eq!(writer.scroll_offset, SCROLLBACK.lock().len);
+
+        writer.scroll_down(SCROLLBACK_LINES + 10);
+        assert_eq!(writer.scroll_offset, 0);
+        assert_eq!(writer.read_row(0), live_top);
+
+        // New output goes back to the live screen first
+        writer.scroll_up(3);
+        writer.write_byte(b'!');
+        assert_eq!(writer.scroll_offset, 0);
+        assert_eq!(writer.read_row(0), live_top);
+    });
+}
+
+#[test_case]
+fn test_multi_character_prompt() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.set_prompt("seraphine$");
+        writer.set_prompt_color(Color::Green);
+        writer.write_byte(b'\n');
+        writer.toggle_prompt(true);
+
+        let row = BUFFER_HEIGHT - 1;
+        for (i, c) in "seraphine$".chars().enumerate() {
+            let screen_char = writer.buffer.chars[row][writer.prompt_position + i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+            assert_eq!(screen_char.color_code, ColorCode::new(Color::Green, Color::Black));
+        }
+
+        // Input starts after the prompt and a space, and backspace cannot reach into it
+        assert_eq!(writer.cursor_position, writer.prompt_position + "seraphine$".len() + 1);
+        writer.write_byte(0x08);
+        assert_eq!(char::from(writer.buffer.chars[row][writer.cursor_position - 2].read().ascii_character), '$');
+
+        writer.take_input();
+        writer.set_prompt(">");
+        writer.set_prompt_color(Color::Red);
+    });
+}
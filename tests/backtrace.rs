@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+use seraphine::arch::backtrace::{Registers, StackFrames};
+use seraphine::mem::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    seraphine::init();
+    memory::set_physical_memory_offset(VirtAddr::new(boot_info.physical_memory_offset));
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+#[inline(never)]
+fn outer() -> usize {
+    core::hint::black_box(middle())
+}
+
+#[inline(never)]
+fn middle() -> usize {
+    core::hint::black_box(inner())
+}
+
+#[inline(never)]
+fn inner() -> usize {
+    let registers = Registers::capture();
+    StackFrames::new(registers.rbp).count()
+}
+
+#[test_case]
+fn walks_nested_frames() {
+    // inner's caller, middle's caller and outer's caller at the very least
+    assert!(outer() >= 3);
+}
+
+#[test_case]
+fn stack_pointer_is_below_frame_pointer() {
+    let registers = Registers::capture();
+    assert!(registers.rsp <= registers.rbp);
+    assert!(StackFrames::new(registers.rbp).all(|address| address != 0));
+}
+
+#[test_case]
+fn bad_frame_pointer_ends_walk() {
+    assert_eq!(StackFrames::new(0).count(), 0);
+    assert_eq!(StackFrames::new(0x1234_5677).count(), 0);
+    // Canonical, aligned, but nothing is mapped there
+    assert_eq!(StackFrames::new(0x7fff_ffff_f000).count(), 0);
+}
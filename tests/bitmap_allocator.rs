@@ -0,0 +1,127 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::VirtAddr;
+
+use seraphine::hardware::pit;
+use seraphine::mem::allocator;
+use seraphine::mem::bitmap::BitmapFrameAllocator;
+use seraphine::mem::memory::{self, ContiguousFrames, UsableMemory};
+use seraphine::serial_println;
+
+const FRAME_COUNT: usize = 10_000;
+
+// The allocator that set up the heap, kept for the tests once `main` is done with it
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    }.expect("no usable region can hold the frame bitmap");
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+#[test_case]
+fn allocates_and_frees_many_frames() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().expect("frame allocator not set up");
+    let free_before = frame_allocator.free_frames();
+    assert!(free_before >= FRAME_COUNT, "only {} free frames", free_before);
+
+    let start = pit::uptime_ms();
+    let mut frames = Vec::with_capacity(FRAME_COUNT);
+    for _ in 0..FRAME_COUNT {
+        frames.push(frame_allocator.allocate_frame().expect("ran out of frames"));
+    }
+    serial_println!("allocated {} frames in {} ms", FRAME_COUNT, pit::uptime_ms() - start);
+
+    assert_eq!(frame_allocator.free_frames(), free_before - FRAME_COUNT);
+    let mut sorted = frames.clone();
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(sorted.len(), FRAME_COUNT, "a frame was handed out twice");
+
+    for &frame in &frames {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(frame_allocator.free_frames(), free_before);
+
+    // Freeing makes the lowest freed frame the next one handed out again
+    assert_eq!(frame_allocator.allocate_frame(), Some(sorted[0]));
+    unsafe { frame_allocator.deallocate_frame(sorted[0]) };
+}
+
+#[test_case]
+fn double_free_is_ignored() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().expect("frame allocator not set up");
+
+    let frame = frame_allocator.allocate_frame().expect("no frame");
+    let free = frame_allocator.free_frames();
+    unsafe {
+        frame_allocator.deallocate_frame(frame);
+        frame_allocator.deallocate_frame(frame);
+    }
+    assert_eq!(frame_allocator.free_frames(), free + 1);
+}
+
+#[test_case]
+fn contiguous_run_skips_used_frames_without_taking_them() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().expect("frame allocator not set up");
+
+    // Leaves a single free frame between two used ones, too short for the run below
+    let first = frame_allocator.allocate_frame().expect("no frame");
+    let gap = frame_allocator.allocate_frame().expect("no frame");
+    let third = frame_allocator.allocate_frame().expect("no frame");
+    unsafe { frame_allocator.deallocate_frame(gap) };
+    let free = frame_allocator.free_frames();
+
+    let start = frame_allocator.allocate_contiguous(16).expect("no 16-frame run");
+    assert!(start > third, "run overlaps the frames in use");
+    assert_eq!(frame_allocator.free_frames(), free - 16);
+
+    // The gap was not taken along the way
+    assert_eq!(frame_allocator.allocate_frame(), Some(gap));
+
+    for frame in PhysFrame::range(start, start + 16).chain([first, gap, third]) {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    // Back to before the test, when `first` and `third` were still free too
+    assert_eq!(frame_allocator.free_frames(), free + 2);
+}
+
+#[test_case]
+fn heap_is_sized_from_usable_memory() {
+    let guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_ref().expect("frame allocator not set up");
+
+    let expected = allocator::heap_size_for(frame_allocator.usable_memory());
+    assert_eq!(allocator::heap_stats().size, expected);
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+use alloc::string::String;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use seraphine::commands::{dispatch, CommandAction, UnlockedCommand};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use seraphine::mem::allocator;
+    use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+/// The action `dispatch` returns for `command`, checking it wrote nothing itself.
+fn silent_action(command: &str) -> CommandAction<'_> {
+    let mut out = String::new();
+    let action = dispatch(command, &mut out);
+    assert_eq!(out, "");
+    action
+}
+
+/// What `command` writes, checking it leaves nothing for the caller to do.
+fn output_of(command: &str) -> String {
+    let mut out = String::new();
+    assert_eq!(dispatch(command, &mut out), CommandAction::None);
+    out
+}
+
+#[test_case]
+fn disk_commands_run_unlocked() {
+    assert_eq!(silent_action("read"), CommandAction::RunUnlocked(UnlockedCommand::ReadBlock(0)));
+    assert_eq!(silent_action("read 42"), CommandAction::RunUnlocked(UnlockedCommand::ReadBlock(42)));
+    assert_eq!(silent_action("write 7"), CommandAction::RunUnlocked(UnlockedCommand::WriteBlock(7)));
+    assert_eq!(silent_action("ls"), CommandAction::RunUnlocked(UnlockedCommand::ListFiles));
+    assert_eq!(silent_action("  cat  README.TXT "), CommandAction::RunUnlocked(UnlockedCommand::CatFile("README.TXT")));
+    assert_eq!(silent_action("breakpoint"), CommandAction::RunUnlocked(UnlockedCommand::Breakpoint));
+}
+
+#[test_case]
+fn bad_disk_arguments_print_usage() {
+    assert_eq!(output_of("read x"), "\nUsage: read [lba]\n");
+    assert_eq!(output_of("read 1 2"), "\nUsage: read [lba]\n");
+    assert_eq!(output_of("write"), "\nUsage: write <lba>\n");
+    assert_eq!(output_of("cat"), "\nUsage: cat <file>\n");
+}
+
+#[test_case]
+fn bind_is_left_to_the_shell() {
+    assert_eq!(silent_action("bind"), CommandAction::Bind(""));
+    assert_eq!(silent_action("bind F2 echo  hi"), CommandAction::Bind("F2 echo  hi"));
+}
@@ -0,0 +1,66 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+use alloc::string::String;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use seraphine::commands::{dispatch, CommandAction};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use seraphine::mem::allocator;
+    use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+/// What `command` writes, after the line break that leaves the input line.
+fn output_of(command: &str) -> String {
+    let mut out = String::new();
+    assert_eq!(dispatch(command, &mut out), CommandAction::None);
+    out.strip_prefix('\n').expect("output does not leave the input line").into()
+}
+
+#[test_case]
+fn echo_joins_words_with_single_spaces() {
+    assert_eq!(output_of("echo hello   world"), "hello world\n");
+}
+
+#[test_case]
+fn echo_n_drops_trailing_newline() {
+    assert_eq!(output_of("echo -n hello world"), "hello world");
+}
+
+#[test_case]
+fn echo_without_words() {
+    assert_eq!(output_of("echo"), "\n");
+    assert_eq!(output_of("echo -n"), "");
+}
+
+#[test_case]
+fn echo_n_only_as_first_argument() {
+    assert_eq!(output_of("echo hello -n"), "hello -n\n");
+}
@@ -0,0 +1,183 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use seraphine::filesystem::fat::{FatError, FatType, FatVolume};
+use seraphine::filesystem::ramdisk::RamDisk;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use seraphine::mem::allocator;
+    use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+// Smallest sensible FAT16 layout: one sector per cluster, just over the FAT12 limit
+const SECTOR: usize = 512;
+const PARTITION_START: usize = 8;
+const RESERVED: usize = 1;
+const FAT_SECTORS: usize = 16;
+const ROOT_ENTRIES: usize = 512;
+const FIRST_DATA: usize = RESERVED + 2 * FAT_SECTORS + ROOT_ENTRIES * 32 / SECTOR;
+const CLUSTERS: usize = 4090;
+const VOLUME_SECTORS: usize = FIRST_DATA + CLUSTERS;
+
+fn hello_contents() -> Vec<u8> {
+    (0..700).map(|i| b'a' + (i % 26) as u8).collect()
+}
+
+fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+    image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_entry(image: &mut [u8], index: usize, name: &[u8; 11], attributes: u8, cluster: u16, size: u32) {
+    let offset = (PARTITION_START + RESERVED + 2 * FAT_SECTORS) * SECTOR + index * 32;
+    image[offset..offset + 11].copy_from_slice(name);
+    image[offset + 11] = attributes;
+    put_u16(image, offset + 26, cluster);
+    put_u32(image, offset + 28, size);
+}
+
+fn set_fat(image: &mut [u8], cluster: usize, value: u16) {
+    for fat in 0..2 {
+        let offset = (PARTITION_START + RESERVED + fat * FAT_SECTORS) * SECTOR + cluster * 2;
+        put_u16(image, offset, value);
+    }
+}
+
+/// An MBR disk with one FAT16 partition holding a few files in its root directory.
+fn build_disk() -> RamDisk {
+    RamDisk::from_image(SECTOR, &build_image())
+}
+
+fn build_image() -> Vec<u8> {
+    let mut image = vec![0u8; (PARTITION_START + VOLUME_SECTORS) * SECTOR];
+
+    // MBR: FAT16 (LBA) partition 0
+    image[446 + 4] = 0x0E;
+    put_u32(&mut image, 446 + 8, PARTITION_START as u32);
+    put_u32(&mut image, 446 + 12, VOLUME_SECTORS as u32);
+    put_u16(&mut image, 510, 0xAA55);
+
+    let boot = PARTITION_START * SECTOR;
+    image[boot] = 0xEB;
+    put_u16(&mut image, boot + 11, SECTOR as u16);
+    image[boot + 13] = 1;
+    put_u16(&mut image, boot + 14, RESERVED as u16);
+    image[boot + 16] = 2;
+    put_u16(&mut image, boot + 17, ROOT_ENTRIES as u16);
+    put_u16(&mut image, boot + 19, VOLUME_SECTORS as u16);
+    put_u16(&mut image, boot + 22, FAT_SECTORS as u16);
+    put_u16(&mut image, boot + 510, 0xAA55);
+
+    set_fat(&mut image, 0, 0xFFF8);
+    set_fat(&mut image, 1, 0xFFFF);
+    set_fat(&mut image, 2, 3); // HELLO.TXT: 2 -> 3
+    set_fat(&mut image, 3, 0xFFFF);
+    set_fat(&mut image, 4, 0xFFFF); // SUBDIR
+    set_fat(&mut image, 5, 0xFFF7); // BAD.BIN continues into a bad cluster
+    set_fat(&mut image, 6, 0xFFFF); // SHORT.BIN claims more than one cluster
+
+    put_entry(&mut image, 0, b"SERAPHINE  ", 0x08, 0, 0);
+    put_entry(&mut image, 1, b"Bhello.txt ", 0x0F, 0, 0);
+    put_entry(&mut image, 2, b"HELLO   TXT", 0x20, 2, 700);
+    put_entry(&mut image, 3, b"\xE5LD     TXT", 0x20, 0, 0);
+    put_entry(&mut image, 4, b"EMPTY   TXT", 0x20, 0, 0);
+    put_entry(&mut image, 5, b"SUBDIR     ", 0x10, 4, 0);
+    put_entry(&mut image, 6, b"BAD     BIN", 0x20, 5, 2000);
+    put_entry(&mut image, 7, b"SHORT   BIN", 0x20, 6, 2000);
+
+    let data = (PARTITION_START + FIRST_DATA) * SECTOR;
+    image[data..data + 700].copy_from_slice(&hello_contents());
+    image
+}
+
+#[test_case]
+fn partition_is_found_and_parsed() {
+    let volume = FatVolume::open(build_disk()).expect("volume did not open");
+    let geometry = volume.geometry();
+
+    assert_eq!(geometry.fat_type, FatType::Fat16);
+    assert_eq!(geometry.cluster_count, CLUSTERS as u32);
+    assert_eq!(geometry.first_data_sector, FIRST_DATA as u32);
+}
+
+#[test_case]
+fn root_directory_skips_labels_long_names_and_deleted_entries() {
+    let mut volume = FatVolume::open(build_disk()).unwrap();
+    let entries = volume.root_dir().unwrap();
+
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, ["HELLO.TXT", "EMPTY.TXT", "SUBDIR", "BAD.BIN", "SHORT.BIN"]);
+    assert_eq!(entries[0].size, 700);
+    assert!(!entries[0].is_dir);
+    assert!(entries[2].is_dir);
+}
+
+#[test_case]
+fn file_spanning_two_clusters_is_read_whole() {
+    let mut volume = FatVolume::open(build_disk()).unwrap();
+
+    assert_eq!(volume.read_file("hello.txt").unwrap(), hello_contents());
+    assert_eq!(volume.read_file("EMPTY.TXT").unwrap(), Vec::<u8>::new());
+}
+
+#[test_case]
+fn bad_lookups_and_chains_are_reported() {
+    let mut volume = FatVolume::open(build_disk()).unwrap();
+
+    assert_eq!(volume.read_file("MISSING.TXT"), Err(FatError::NotFound));
+    assert_eq!(volume.read_file("SUBDIR"), Err(FatError::IsDirectory));
+    assert_eq!(volume.read_file("BAD.BIN"), Err(FatError::Corrupt));
+    assert_eq!(volume.read_file("SHORT.BIN"), Err(FatError::Corrupt));
+}
+
+#[test_case]
+fn blank_disk_has_no_volume() {
+    assert_eq!(FatVolume::open(RamDisk::new(SECTOR, 16)).err(), Some(FatError::NoFatVolume));
+}
+
+#[test_case]
+fn mbr_with_jump_boot_code_still_finds_the_partition() {
+    let mut image = build_image();
+    // Boot code like GRUB's boot.img opens with a short jump, but bytes 11..13 are code
+    image[0] = 0xEB;
+    image[1] = 0x63;
+    image[2] = 0x90;
+    put_u16(&mut image, 11, 0x7C00);
+
+    let disk = RamDisk::from_image(SECTOR, &image);
+    let volume = FatVolume::open(disk).expect("partition behind a jumping MBR did not open");
+    assert_eq!(volume.geometry().fat_type, FatType::Fat16);
+}
@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use seraphine::mem::allocator;
+use seraphine::mem::memory::{self, BootInfoFrameAllocator, EmptyFrameAllocator};
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+const HIGH_REGION_START: u64 = 0x1_0000_0000;
+const HIGH_REGION_END: u64 = 0x1_0001_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+/// Builds a memory map with a small usable region below 1MB and one above 4GB.
+fn synthetic_memory_map() -> &'static MemoryMap {
+    let mut memory_map = MemoryMap::new();
+    memory_map.add_region(MemoryRegion {
+        range: FrameRange::new(0x1000, 0x3000),
+        region_type: MemoryRegionType::Usable,
+    });
+    memory_map.add_region(MemoryRegion {
+        range: FrameRange::new(HIGH_REGION_START, HIGH_REGION_END),
+        region_type: MemoryRegionType::Usable,
+    });
+    Box::leak(Box::new(memory_map))
+}
+
+#[test_case]
+fn frames_above_4g_are_allocated() {
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(synthetic_memory_map()) };
+
+    // The two low frames come first, the rest must come from the high region
+    frame_allocator.allocate_frame().expect("no low frame");
+    frame_allocator.allocate_frame().expect("no low frame");
+
+    let frame = frame_allocator.allocate_frame().expect("no frame above 4GB");
+    assert_eq!(frame.start_address().as_u64(), HIGH_REGION_START);
+}
+
+#[test_case]
+fn freed_frame_is_reallocated() {
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(synthetic_memory_map()) };
+
+    let first = frame_allocator.allocate_frame().expect("no frame");
+    let second = frame_allocator.allocate_frame().expect("no frame");
+    assert_ne!(first, second);
+
+    unsafe { frame_allocator.deallocate_frame(first) };
+    assert_eq!(frame_allocator.allocate_frame(), Some(first));
+
+    // The free list is empty again, so allocation continues where it left off
+    let third = frame_allocator.allocate_frame().expect("no frame");
+    assert_eq!(third.start_address().as_u64(), HIGH_REGION_START);
+}
+
+#[test_case]
+fn every_usable_frame_is_handed_out_once() {
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(synthetic_memory_map()) };
+    let high_frames = (HIGH_REGION_END - HIGH_REGION_START) / 4096;
+
+    let mut expected = (0x1000..0x3000).step_by(4096)
+        .chain((HIGH_REGION_START..HIGH_REGION_END).step_by(4096));
+    for _ in 0..2 + high_frames {
+        let frame = frame_allocator.allocate_frame().expect("ran out of frames early");
+        assert_eq!(Some(frame.start_address().as_u64()), expected.next());
+    }
+    assert_eq!(frame_allocator.allocate_frame(), None);
+}
+
+#[test_case]
+fn usable_memory_above_4g_is_detected() {
+    let memory_map = synthetic_memory_map();
+
+    assert_eq!(memory::usable_memory_above_4g(memory_map), HIGH_REGION_END - HIGH_REGION_START);
+    assert_eq!(memory::max_physical_address(memory_map), HIGH_REGION_END);
+}
+
+#[test_case]
+fn frame_above_4g_is_mappable() {
+    let phys_mem_offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(synthetic_memory_map()) };
+    let frame = core::iter::from_fn(|| frame_allocator.allocate_frame())
+        .find(|frame| frame.start_address().as_u64() >= HIGH_REGION_START)
+        .expect("no frame above 4GB");
+
+    // Right after the heap, so the page tables already exist and no frames are needed
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(allocator::heap_stats().end as u64));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper.map_to(page, frame, flags, &mut EmptyFrameAllocator)
+            .expect("mapping a frame above 4GB failed")
+            .flush();
+    }
+
+    assert_eq!(mapper.translate_page(page).ok(), Some(frame));
+
+    assert_eq!(memory::unmap_page(page, &mut mapper).ok(), Some(frame));
+    assert!(mapper.translate_page(page).is_err());
+}
@@ -9,16 +9,17 @@ use alloc::boxed::Box;
 use alloc::format;
 use alloc::vec::Vec;
 use alloc::string::String;
+use alloc::alloc::{alloc, dealloc, Layout};
 
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use seraphine::memory::allocator::HEAP_SIZE;
+use seraphine::mem::allocator::HEAP_SIZE;
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use seraphine::memory::allocator;
-    use seraphine::memory::{self, BootInfoFrameAllocator};
+    use seraphine::mem::allocator;
+    use seraphine::mem::memory::{self, BootInfoFrameAllocator};
     use x86_64::VirtAddr;
 
     seraphine::init();
@@ -96,4 +97,61 @@ fn many_strings() {
         let s = format!("String number {}", i);
         assert_eq!(s, format!("String number {}", i));
     }
-}
\ No newline at end of file
+}
+
+/// Small deterministic LCG, so a failing run can be reproduced exactly.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+#[test_case]
+fn random_alloc_free() {
+    const OPERATIONS: usize = 5000;
+    const MAX_LIVE: usize = 64;
+    const MAX_SIZE: u64 = 512; // MAX_LIVE * MAX_SIZE stays well inside HEAP_SIZE
+
+    let free_before = seraphine::mem::allocator::heap_free();
+    let mut rng = Lcg(0x5EED);
+    let mut live: Vec<(*mut u8, Layout, u8)> = Vec::with_capacity(MAX_LIVE);
+
+    for _ in 0..OPERATIONS {
+        if live.len() < MAX_LIVE && (live.is_empty() || rng.below(2) == 0) {
+            let size = 1 + rng.below(MAX_SIZE) as usize;
+            let align = 1 << rng.below(7); // 1..=64
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let pattern = rng.next() as u8;
+
+            let ptr = unsafe { alloc(layout) };
+            assert!(!ptr.is_null(), "allocation of {:?} failed", layout);
+            assert_eq!(ptr as usize % align, 0);
+            unsafe { core::ptr::write_bytes(ptr, pattern, size) };
+            live.push((ptr, layout, pattern));
+        } else {
+            let (ptr, layout, pattern) = live.swap_remove(rng.below(live.len() as u64) as usize);
+            for i in 0..layout.size() {
+                assert_eq!(unsafe { *ptr.add(i) }, pattern, "allocation was overwritten");
+            }
+            unsafe { dealloc(ptr, layout) };
+        }
+    }
+
+    for (ptr, layout, pattern) in live.drain(..) {
+        for i in 0..layout.size() {
+            assert_eq!(unsafe { *ptr.add(i) }, pattern, "allocation was overwritten");
+        }
+        unsafe { dealloc(ptr, layout) };
+    }
+    drop(live);
+
+    // Every block went back, so the heap is as free as before the test started
+    assert_eq!(seraphine::mem::allocator::heap_free(), free_before);
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+
+use seraphine::mem::allocator::{self, HEAP_MAX_SIZE, HEAP_SIZE, HEAP_START};
+use seraphine::mem::memory::{self, BootInfoFrameAllocator, EmptyFrameAllocator};
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+// Heap size before growing and bytes it grew by in `main`, where the boot frame allocator is
+// still available
+static INITIAL_SIZE: AtomicUsize = AtomicUsize::new(0);
+static GROWN: AtomicUsize = AtomicUsize::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    INITIAL_SIZE.store(allocator::heap_stats().size, Ordering::Relaxed);
+
+    // Deliberately not a multiple of the page size
+    let grown = allocator::extend_heap(HEAP_SIZE + 1, &mut mapper, &mut frame_allocator)
+        .expect("extending the heap failed");
+    GROWN.store(grown, Ordering::Relaxed);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+#[test_case]
+fn heap_grows_by_whole_pages() {
+    let initial_size = INITIAL_SIZE.load(Ordering::Relaxed);
+    let grown = GROWN.load(Ordering::Relaxed);
+    assert_eq!(grown, HEAP_SIZE + 4096);
+
+    let stats = allocator::heap_stats();
+    assert_eq!(stats.start, HEAP_START);
+    assert_eq!(stats.end, HEAP_START + initial_size + grown);
+    assert_eq!(stats.size, initial_size + grown);
+}
+
+#[test_case]
+fn allocation_larger_than_initial_heap() {
+    let initial_size = INITIAL_SIZE.load(Ordering::Relaxed);
+    let mut values: Vec<u8> = Vec::with_capacity(initial_size + HEAP_SIZE / 2);
+    values.resize(values.capacity(), 0x5A);
+    assert!(values.iter().all(|&value| value == 0x5A));
+}
+
+#[test_case]
+fn extend_without_frames_fails_cleanly() {
+    let phys_mem_offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let before = allocator::heap_stats();
+
+    assert_eq!(allocator::extend_heap(4096, &mut mapper, &mut EmptyFrameAllocator),
+               Err("out of physical frames"));
+    assert_eq!(allocator::heap_stats().end, before.end);
+}
+
+#[test_case]
+fn extend_past_reserved_range_is_rejected() {
+    let phys_mem_offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    assert_eq!(allocator::extend_heap(HEAP_MAX_SIZE, &mut mapper, &mut EmptyFrameAllocator),
+               Err("heap would grow past its reserved address range"));
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size2MiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use seraphine::mem::allocator;
+use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+
+// Far from the heap and the MMIO window, and 2MiB aligned
+const HUGE_PAGE_VIRT: u64 = 0x5555_5540_0000;
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // The first 2MiB of physical memory, which always exists; only ever read through this page
+    let page = Page::<Size2MiB>::containing_address(VirtAddr::new(HUGE_PAGE_VIRT));
+    let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0));
+    memory::map_huge_page(page, frame, PageTableFlags::PRESENT, &mut mapper, &mut frame_allocator)
+        .expect("mapping a 2MiB page failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+#[test_case]
+fn huge_page_translates_with_offset() {
+    let offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+
+    let phys = unsafe { memory::translate_addr(VirtAddr::new(HUGE_PAGE_VIRT + 0x1_2345), offset) };
+    assert_eq!(phys, Some(PhysAddr::new(0x1_2345)));
+}
+
+#[test_case]
+fn huge_page_reads_same_memory() {
+    let offset = PHYS_MEM_OFFSET.load(Ordering::Relaxed);
+
+    // The BIOS area, read through the huge page and through the bootloader's mapping
+    let through_huge: *const u64 = (HUGE_PAGE_VIRT + 0xE0000) as *const u64;
+    let through_offset: *const u64 = (offset + 0xE0000) as *const u64;
+    unsafe {
+        assert_eq!(core::ptr::read_volatile(through_huge), core::ptr::read_volatile(through_offset));
+    }
+}
+
+#[test_case]
+fn physical_memory_mapping_translates() {
+    // The bootloader may map physical memory with 2MiB pages, which used to panic here
+    let offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+
+    let phys = unsafe { memory::translate_addr(offset + 0x20_1234u64, offset) };
+    assert_eq!(phys, Some(PhysAddr::new(0x20_1234)));
+}
@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use seraphine::task::line_editor::{History, HistoryBrowser, ReverseSearch};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use seraphine::mem::allocator;
+    use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+fn history_of(entries: &[&str]) -> History {
+    let mut history = History::new();
+    for entry in entries {
+        history.push(entry);
+    }
+    history
+}
+
+#[test_case]
+fn history_skips_blank_and_repeated_lines() {
+    let history = history_of(&["help", "  ", "help", "ticks"]);
+
+    assert_eq!(history.len(), 2);
+    assert_eq!(history.get(0), Some("help"));
+    assert_eq!(history.get(1), Some("ticks"));
+}
+
+#[test_case]
+fn reverse_search_finds_newest_match_first() {
+    let history = history_of(&["echo one", "pci -v", "echo two"]);
+    let mut search = ReverseSearch::new(alloc::string::String::new());
+
+    search.push_char('e', &history);
+    search.push_char('c', &history);
+    assert_eq!(search.matched(&history), Some("echo two"));
+    assert_eq!(search.display(&history), "(reverse-i-search) `ec': echo two");
+}
+
+#[test_case]
+fn reverse_search_cycles_to_older_matches() {
+    let history = history_of(&["echo one", "pci -v", "echo two"]);
+    let mut search = ReverseSearch::new(alloc::string::String::new());
+
+    search.push_char('e', &history);
+    search.search_older(&history);
+    assert_eq!(search.matched(&history), Some("echo one"));
+
+    // No older match left, so the current one stays selected
+    search.search_older(&history);
+    assert_eq!(search.matched(&history), Some("echo one"));
+    assert_eq!(search.accept(&history), "echo one");
+}
+
+#[test_case]
+fn reverse_search_cancel_restores_input() {
+    let history = history_of(&["help"]);
+    let mut search = ReverseSearch::new(alloc::string::String::from("ech"));
+
+    search.push_char('x', &history);
+    assert_eq!(search.matched(&history), None);
+    assert_eq!(search.display(&history), "(failed reverse-i-search) `x': ");
+    assert_eq!(search.cancel(), "ech");
+}
+
+#[test_case]
+fn browser_stops_at_oldest_entry() {
+    let history = history_of(&["help", "ticks"]);
+    let mut browser = HistoryBrowser::new();
+
+    assert_eq!(browser.up(&history), Some("ticks"));
+    assert_eq!(browser.up(&history), Some("help"));
+    assert_eq!(browser.up(&history), Some("help"));
+}
+
+#[test_case]
+fn browser_returns_to_empty_line_past_newest() {
+    let history = history_of(&["help", "ticks"]);
+    let mut browser = HistoryBrowser::new();
+
+    browser.up(&history);
+    browser.up(&history);
+    assert_eq!(browser.down(&history), "ticks");
+    assert_eq!(browser.down(&history), "");
+    assert_eq!(browser.down(&history), "");
+    assert_eq!(browser.up(&history), Some("ticks"));
+}
+
+#[test_case]
+fn browser_on_empty_history() {
+    let history = History::new();
+    let mut browser = HistoryBrowser::new();
+
+    assert_eq!(browser.up(&history), None);
+    assert_eq!(browser.down(&history), "");
+}
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use seraphine::hardware::madt::{self, InterruptSourceOverride, IoApic};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use seraphine::mem::allocator;
+    use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+/// MADT body as QEMU's q35 machine lays it out, trimmed to one of each entry type.
+const MADT_BODY: [u8; 40] = [
+    0x00, 0x00, 0xE0, 0xFE, // Local APIC address 0xFEE00000
+    0x01, 0x00, 0x00, 0x00, // PCAT_COMPAT
+    0x00, 0x08, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // CPU 0, APIC ID 0, enabled
+    0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, // CPU 1, APIC ID 1, disabled
+    0x01, 0x0C, 0x00, 0x00, 0x00, 0x00, 0xC0, 0xFE, 0x00, 0x00, 0x00, 0x00, // I/O APIC 0 at 0xFEC00000
+    0x02, 0x0A, 0x00, 0x00, // ISA IRQ 0 -> GSI 2 (continued below)
+];
+
+#[test_case]
+fn madt_entries_are_collected() {
+    let mut body = alloc::vec::Vec::from(&MADT_BODY[..]);
+    body.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+    let info = madt::parse_body(&body).expect("MADT did not parse");
+    assert_eq!(info.local_apic_address, 0xFEE0_0000);
+    assert!(info.has_legacy_pics);
+    assert_eq!(info.local_apic_ids, [0]);
+    assert_eq!(info.io_apics, [IoApic { id: 0, address: 0xFEC0_0000, gsi_base: 0 }]);
+    assert_eq!(info.overrides, [InterruptSourceOverride { bus: 0, source: 0, gsi: 2, flags: 0 }]);
+}
+
+#[test_case]
+fn madt_stops_at_truncated_entry() {
+    // The I/O APIC entry claims 12 bytes but only 4 are left
+    let info = madt::parse_body(&MADT_BODY[..28]).expect("MADT did not parse");
+    assert_eq!(info.local_apic_ids, [0]);
+    assert!(info.io_apics.is_empty());
+}
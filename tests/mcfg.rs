@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use seraphine::hardware::mcfg::{self, EcamRegion};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use seraphine::mem::allocator;
+    use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+/// MCFG body as QEMU's q35 machine lays it out: one window for all 256 buses of segment 0.
+const MCFG_BODY: [u8; 24] = [
+    0, 0, 0, 0, 0, 0, 0, 0, // Reserved
+    0x00, 0x00, 0x00, 0xb0, 0x00, 0x00, 0x00, 0x00, // Base 0xb000_0000
+    0x00, 0x00, // Segment 0
+    0x00, 0xff, // Buses 0-255
+    0, 0, 0, 0, // Reserved
+];
+
+#[test_case]
+fn parses_single_allocation() {
+    let regions = mcfg::parse_body(&MCFG_BODY);
+    assert_eq!(regions.len(), 1);
+    assert_eq!(regions[0], EcamRegion { base: 0xb000_0000, segment: 0, start_bus: 0, end_bus: 0xff });
+    assert_eq!(regions[0].size(), 256 << 20);
+}
+
+#[test_case]
+fn computes_function_address() {
+    let region = mcfg::parse_body(&MCFG_BODY)[0];
+    assert_eq!(region.function_address(1, 2, 3), 0xb000_0000 + (1 << 20) + (2 << 15) + (3 << 12));
+    assert!(region.contains(0, 0x80));
+    assert!(!region.contains(1, 0x80));
+}
+
+#[test_case]
+fn ignores_truncated_allocation() {
+    assert!(mcfg::parse_body(&MCFG_BODY[..20]).is_empty());
+    assert!(mcfg::parse_body(&[]).is_empty());
+}
@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use seraphine::filesystem::block::{BlockDevice, BlockError};
+use seraphine::filesystem::ramdisk::RamDisk;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use seraphine::mem::allocator;
+    use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+#[test_case]
+fn blocks_read_back_what_was_written() {
+    let mut disk = RamDisk::new(512, 8);
+    assert_eq!(disk.block_size(), 512);
+    assert_eq!(disk.num_blocks(), 8);
+
+    for lba in [0, 3, 7] {
+        let pattern = vec![lba as u8 ^ 0xA5; 512];
+        disk.write_block(lba, &pattern).unwrap();
+    }
+
+    let mut buf = vec![0xFF; 512];
+    for lba in 0..8 {
+        disk.read_block(lba, &mut buf).unwrap();
+        let expected = if [0, 3, 7].contains(&lba) { lba as u8 ^ 0xA5 } else { 0 };
+        assert!(buf.iter().all(|&byte| byte == expected), "block {} has the wrong contents", lba);
+    }
+}
+
+#[test_case]
+fn larger_buffers_only_use_one_block() {
+    let mut disk = RamDisk::new(512, 2);
+    disk.write_block(0, &vec![1; 1024]).unwrap();
+
+    let mut buf = vec![0; 1024];
+    disk.read_block(1, &mut buf).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 0));
+}
+
+#[test_case]
+fn out_of_range_lba_is_rejected() {
+    let mut disk = RamDisk::new(4096, 4);
+    let mut buf = vec![0; 4096];
+
+    assert_eq!(disk.read_block(4, &mut buf), Err(BlockError::OutOfRange));
+    assert_eq!(disk.write_block(4, &buf), Err(BlockError::OutOfRange));
+    assert_eq!(disk.read_block(u64::MAX, &mut buf), Err(BlockError::OutOfRange));
+}
+
+#[test_case]
+fn short_buffer_is_rejected() {
+    let mut disk = RamDisk::new(512, 1);
+    let mut buf = vec![0; 511];
+
+    assert_eq!(disk.read_block(0, &mut buf), Err(BlockError::BufferTooSmall));
+    assert_eq!(disk.write_block(0, &buf), Err(BlockError::BufferTooSmall));
+}
+
+#[test_case]
+fn image_is_padded_to_whole_blocks() {
+    let mut disk = RamDisk::from_image(512, &[7; 600]);
+    assert_eq!(disk.num_blocks(), 2);
+
+    let mut buf = vec![0; 512];
+    disk.read_block(1, &mut buf).unwrap();
+    assert!(buf[..88].iter().all(|&byte| byte == 7));
+    assert!(buf[88..].iter().all(|&byte| byte == 0));
+}
@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+use futures_util::task::{waker, ArcWake};
+use seraphine::hardware::pit::timer_wait_ms;
+use seraphine::task::timer::sleep_ms;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use seraphine::mem::allocator;
+    use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+/// Waker that only records that it was woken.
+struct FlagWaker {
+    woken: AtomicBool,
+}
+
+impl ArcWake for FlagWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.woken.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn zero_sleep_is_ready_immediately() {
+    let flag = Arc::new(FlagWaker { woken: AtomicBool::new(false) });
+    let waker = waker(flag);
+    let mut context = Context::from_waker(&waker);
+
+    let mut sleep = sleep_ms(0);
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Ready(()));
+}
+
+#[test_case]
+fn sleep_is_woken_by_the_timer_interrupt() {
+    let flag = Arc::new(FlagWaker { woken: AtomicBool::new(false) });
+    let waker = waker(flag.clone());
+    let mut context = Context::from_waker(&waker);
+
+    let mut sleep = sleep_ms(50);
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Pending);
+    assert!(!flag.woken.load(Ordering::SeqCst));
+
+    timer_wait_ms(100);
+
+    assert!(flag.woken.load(Ordering::SeqCst));
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Ready(()));
+}
+
+#[test_case]
+fn earlier_of_two_sleeps_still_wakes_the_task() {
+    let flag = Arc::new(FlagWaker { woken: AtomicBool::new(false) });
+    let waker = waker(flag.clone());
+    let mut context = Context::from_waker(&waker);
+
+    // A timeout race: the short sleep is polled first, then the long one from the same task
+    let mut short = sleep_ms(50);
+    let mut long = sleep_ms(10_000);
+    assert_eq!(Pin::new(&mut short).poll(&mut context), Poll::Pending);
+    assert_eq!(Pin::new(&mut long).poll(&mut context), Poll::Pending);
+
+    timer_wait_ms(100);
+
+    assert!(flag.woken.load(Ordering::SeqCst));
+    assert_eq!(Pin::new(&mut short).poll(&mut context), Poll::Ready(()));
+    assert_eq!(Pin::new(&mut long).poll(&mut context), Poll::Pending);
+}
@@ -0,0 +1,210 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(seraphine::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::instructions::interrupts;
+
+use seraphine::input;
+use seraphine::vga_buffer::WRITER;
+
+const VGA_BUFFER: usize = 0xb8000;
+const BUFFER_WIDTH: usize = 80;
+const INPUT_ROW: usize = 24;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use seraphine::mem::allocator;
+    use seraphine::mem::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    seraphine::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    seraphine::test_panic_handler(info)
+}
+
+/// The character shown at `col` on the input row, read straight from text-mode memory.
+fn screen_char(col: usize) -> char {
+    let cell = (VGA_BUFFER + (INPUT_ROW * BUFFER_WIDTH + col) * 2) as *const u8;
+    char::from(unsafe { core::ptr::read_volatile(cell) })
+}
+
+#[test_case]
+fn caret_blink_restores_cell() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        writer.toggle_prompt(true);
+        writer.set_input("ab");
+
+        let col = (0..BUFFER_WIDTH).find(|&col| screen_char(col) == 'b').expect("input not shown") + 1;
+        writer.blink_caret();
+        assert_eq!(screen_char(col), '_');
+        writer.blink_caret();
+        assert_eq!(screen_char(col), ' ');
+
+        // Typing while the caret shows writes over it, and the caret does not move along
+        writer.blink_caret();
+        writer.write_byte(b'c');
+        assert_eq!(screen_char(col), 'c');
+        assert_eq!(screen_char(col + 1), ' ');
+        assert_eq!(writer.input(), "abc");
+
+        // Command output is written with input mode off, so there is no caret to draw
+        writer.take_input();
+        writer.blink_caret();
+        assert_eq!(screen_char(col + 1), ' ');
+    });
+}
+
+/// Starts a fresh input line showing `text`, with the cursor after it.
+fn start_input(writer: &mut seraphine::vga_buffer::Writer, text: &str) {
+    writer.write_byte(b'\n');
+    writer.toggle_prompt(true);
+    writer.set_input(text);
+}
+
+/// The input row from column `start`, as far as `len` characters.
+fn screen_text(start: usize, len: usize) -> alloc::string::String {
+    (start..start + len).map(screen_char).collect()
+}
+
+#[test_case]
+fn insert_and_delete_mid_line() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        start_input(&mut writer, "helo");
+        let start = (0..BUFFER_WIDTH).find(|&col| screen_char(col) == 'h').expect("input not shown");
+
+        writer.set_input_cursor(3);
+        writer.write_byte(b'l');
+        assert_eq!(writer.input(), "hello");
+        assert_eq!(writer.input_cursor(), 4);
+        assert_eq!(screen_text(start, 6), "hello ");
+
+        // Backspace removes the character before the cursor, Delete the one under it
+        writer.set_input_cursor(1);
+        writer.write_byte(0x08);
+        assert_eq!(writer.input(), "ello");
+        writer.delete_forward();
+        assert_eq!(writer.input(), "llo");
+        assert_eq!(writer.input_cursor(), 0);
+        assert_eq!(screen_text(start, 5), "llo  ");
+
+        // Nothing to delete at the end of the input
+        writer.set_input_cursor(usize::MAX);
+        assert_eq!(writer.input_cursor(), 3);
+        writer.delete_forward();
+        assert_eq!(writer.input(), "llo");
+
+        writer.take_input();
+    });
+}
+
+#[test_case]
+fn cursor_stays_within_input() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        start_input(&mut writer, "ab");
+        let prompt = (0..BUFFER_WIDTH).find(|&col| screen_char(col) == '>').expect("no prompt");
+
+        writer.set_input_cursor(0);
+        for _ in 0..3 {
+            writer.write_byte(0x08);
+        }
+        assert_eq!(writer.input(), "ab");
+        assert_eq!(screen_char(prompt), '>');
+
+        writer.set_input_cursor(5);
+        assert_eq!(writer.input_cursor(), 2);
+
+        writer.take_input();
+    });
+}
+
+#[test_case]
+fn full_input_line_ignores_typing() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        start_input(&mut writer, "");
+        for _ in 0..BUFFER_WIDTH {
+            writer.write_byte(b'x');
+        }
+        let len = writer.input().len();
+        assert!(len < BUFFER_WIDTH);
+
+        // Inserting in the middle of a full line is refused too, instead of pushing text off
+        writer.set_input_cursor(0);
+        writer.write_byte(b'y');
+        assert_eq!(writer.input().len(), len);
+        assert!(!writer.input().contains('y'));
+        assert_eq!(screen_char(BUFFER_WIDTH - 1), 'x');
+
+        writer.take_input();
+    });
+}
+
+#[test_case]
+fn backspace_cannot_delete_the_prompt() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        start_input(&mut writer, "");
+        let prompt = (0..BUFFER_WIDTH).find(|&col| screen_char(col) == '>').expect("no prompt");
+
+        for &byte in b"abc" {
+            writer.write_byte(byte);
+        }
+        for _ in 0..6 {
+            writer.write_byte(0x08);
+        }
+
+        assert_eq!(writer.input(), "");
+        assert_eq!(writer.input_cursor(), 0);
+        assert_eq!(screen_char(prompt), '>');
+        assert_eq!(screen_text(prompt + 1, 4), "    ");
+
+        // Typing resumes right after the prompt
+        writer.write_byte(b'x');
+        assert_eq!(screen_text(prompt, 3), "> x");
+        assert_eq!(writer.input(), "x");
+
+        writer.take_input();
+    });
+}
+
+#[test_case]
+fn typed_escape_is_not_an_escape_sequence() {
+    interrupts::without_interrupts(|| start_input(&mut WRITER.lock(), ""));
+
+    // Typed input takes the writer lock itself
+    input::handle_char('\u{1b}');
+    input::handle_char('x');
+    for character in "\u{1b}[31m".chars() {
+        input::handle_char(character);
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        assert_eq!(writer.input(), "x[31m");
+        writer.take_input();
+    });
+}